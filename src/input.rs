@@ -8,7 +8,6 @@ use std::fmt;
 use std::fs::OpenOptions;
 use std::hash::Hash;
 use std::io::Error;
-use std::ops::Range;
 use std::path::PathBuf;
 
 #[derive(Debug)]
@@ -119,6 +118,49 @@ impl Input {
     }
 }
 
+#[cfg(test)]
+impl Input {
+    /// An input holding `text` in anonymous memory instead of a file.
+    pub fn from_text(text: &str) -> Input {
+        let mut data = memmap::MmapMut::map_anon(text.len().max(1)).unwrap();
+        data[..text.len()].copy_from_slice(text.as_bytes());
+        Input {
+            path: PathBuf::new(),
+            data: data.make_read_only().unwrap(),
+        }
+    }
+}
+
+/// The values of the results: the terms of the input and the terms computed while answering a
+/// query, e.g. aggregates. Computed terms are addressed by fields past the end of the input so
+/// that they can be stored in the join table like any other value.
+pub struct Terms<'a> {
+    input: &'a Input,
+    computed: Vec<String>,
+}
+
+impl<'a> Terms<'a> {
+    pub fn new(input: &'a Input) -> Self {
+        Terms {
+            input,
+            computed: Vec::new(),
+        }
+    }
+
+    /// Stores a computed term and returns the field referring to it.
+    pub fn add(&mut self, term: String) -> Field {
+        self.computed.push(term);
+        Field(self.input.data.len() + self.computed.len() - 1)
+    }
+
+    pub fn extract_str(&self, field: Field) -> Str<'_> {
+        match field.0.checked_sub(self.input.data.len()) {
+            Some(i) => Str::new(&self.computed[i]),
+            None => self.input.extract_str(field),
+        }
+    }
+}
+
 fn best_chunks(count: usize, base: usize, length: usize) -> usize {
     // The minimum amount of work per worker.
     let min_per_w = length / count;
@@ -200,6 +242,11 @@ pub struct InputLine<'a> {
 }
 
 impl<'a> InputLine<'a> {
+    /// The whole line without the line break, identifying the triple.
+    pub fn text(self) -> Str<'a> {
+        Str(self.data.strip_suffix(b"\n").unwrap_or(self.data))
+    }
+
     pub fn parse(self) -> (Str<'a>, Str<'a>, Str<'a>) {
         let subj = field_len(self.data);
         let prop = field_len(&self.data[subj + 1..]); // Add +1 to skip over the separating TAB
@@ -225,18 +272,13 @@ impl Field {
     pub fn offset(self) -> usize {
         self.0
     }
+}
 
-    pub fn advance(self, by: usize) -> Self {
-        Field(self.0.checked_add(by).unwrap_or(usize::MAX))
-    }
-
+#[cfg(test)]
+impl Field {
     pub fn from_offset(off: usize) -> Self {
         Field(off)
     }
-
-    pub fn make_range(self, length: usize) -> Range<Self> {
-        self..self.advance(length)
-    }
 }
 
 #[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
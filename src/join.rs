@@ -1,28 +1,165 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::fs::{self, File};
 use std::iter::zip;
 
-use std::mem::ManuallyDrop;
+use std::mem;
+use std::sync::atomic::{self, AtomicUsize};
 
 use std::usize;
-use std::{io, io::Write};
+use std::{io, io::BufWriter, io::Write};
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use itertools::Itertools;
-use rayon::iter::{FromParallelIterator, IntoParallelRefIterator, ParallelIterator};
-
-use crate::input::{self, Field, Input};
+use rayon::iter::{
+    FromParallelIterator, IndexedParallelIterator, IntoParallelIterator, IntoParallelRefIterator,
+    IntoParallelRefMutIterator, ParallelExtend, ParallelIterator,
+};
+use rayon::slice::ParallelSliceMut;
+
+use crate::input::{self, Field, Input, Terms};
+use crate::query::{
+    Bindings, Chain, Grouping, JoinMode, OrderKey, Path, Query, Selector, Table, Term,
+    TriplePattern,
+};
 use crate::relation::Relation;
-use crate::{colored, Args};
+use crate::{colored, Args, BuildSide, OutputFormat};
 
+mod aggregate;
+mod construct;
+mod eval;
+mod layout;
+mod path;
 mod pipeline;
 use pipeline::Pipeline;
 
 type RelSet<'a> = HashSet<input::Str<'a>>;
 
+/// How unbound fields are displayed.
+const UNBOUND: &str = "UNDEF";
+
 pub fn join(args: &Args, input: &Input) -> Result<bool> {
-    let joining_rels = args
-        .relations
+    let mut distinct = args.distinct;
+    let mut selection = args
+        .select
+        .as_deref()
+        .map(Selector::parse_list)
+        .transpose()?;
+    let mut order = args
+        .order_by
+        .as_deref()
+        .map(OrderKey::parse_list)
+        .transpose()?
+        .unwrap_or_default();
+    let mut limit = args.limit;
+    let mut offset = args.offset;
+    let mut grouping = None;
+    let mut construct = None;
+    let mut ask = args.ask;
+    let seeds = match &args.bindings {
+        Some(path) => {
+            let text = fs::read_to_string(path)
+                .with_context(|| format!("Cannot read bindings from {}", path.display()))?;
+            let seeds = Bindings::parse_tsv(&text)
+                .with_context(|| format!("Cannot parse bindings from {}", path.display()))?;
+            Some(seeds)
+        }
+        None => None,
+    };
+    let chains = match &args.query {
+        Some(text) => {
+            if !args.relations.is_empty() {
+                bail!("Relations cannot be given together with --query.")
+            }
+            let mut query = Query::parse(text)?;
+            distinct |= query.distinct;
+            if let Some(projection) = query.projection.take() {
+                if selection.is_some() {
+                    bail!("--select cannot be combined with a SELECT list.")
+                }
+                selection = Some(projection);
+            }
+            if !query.order.is_empty() {
+                if !order.is_empty() {
+                    bail!("--order-by cannot be combined with ORDER BY.")
+                }
+                order = std::mem::take(&mut query.order);
+            }
+            if query.limit.is_some() && args.limit.is_some() {
+                bail!("--limit cannot be combined with LIMIT.")
+            }
+            if query.offset.is_some() && args.offset.is_some() {
+                bail!("--offset cannot be combined with OFFSET.")
+            }
+            limit = limit.or(query.limit);
+            offset = offset.or(query.offset);
+            grouping = query.grouping.take();
+            construct = query.construct.take();
+            ask |= query.ask;
+            query.into_chains(seeds.as_ref())?
+        }
+        None => vec![Chain::from_relations(
+            &args.relations,
+            args.subject.as_ref(),
+            args.object.as_ref(),
+            seeds,
+        )?],
+    };
+
+    // ASK only needs to find a single row, none of its columns are kept.
+    if ask {
+        if selection.is_some()
+            || !order.is_empty()
+            || distinct
+            || limit.is_some()
+            || offset.is_some()
+        {
+            bail!("ASK cannot be combined with selecting, sorting, deduplicating or limiting.")
+        }
+        selection = Some(Vec::new());
+        limit = Some(1);
+    }
+
+    // The rows of CONSTRUCT consist of the variables of the template bound by the query.
+    if let Some(template) = &construct {
+        if selection.is_some() {
+            bail!("--select cannot be combined with CONSTRUCT.")
+        }
+        let variables = template
+            .iter()
+            .flat_map(|t| [&t.subject, &t.object])
+            .filter_map(Term::as_var)
+            .chain(template.iter().filter_map(|t| match &t.predicate {
+                Path::Variable(v) => Some(v.as_str()),
+                _ => None,
+            }))
+            .unique()
+            .filter(|&v| {
+                chains
+                    .iter()
+                    .any(|c| c.variables.iter().flatten().any(|cv| cv == v))
+            })
+            .map(|v| Selector::Var(v.to_owned()))
+            .collect_vec();
+        selection = Some(variables);
+    }
+
+    // The columns of a CLI chain are labelled with the relation binding them.
+    let relation_labels: HashMap<_, _> = match &args.query {
+        Some(_) => HashMap::new(),
+        None => chains[0]
+            .steps
+            .iter()
+            .filter_map(|step| {
+                let var = chains[0].variables[step.column?].clone()?;
+                Some((var, step.path.to_string()))
+            })
+            .collect(),
+    };
+
+    let joining_rels = chains
         .iter()
+        .flat_map(Chain::relations)
         .map(|name| input::Str::new(name))
         .collect_vec();
     let rels_set: RelSet = joining_rels
@@ -65,35 +202,634 @@ pub fn join(args: &Args, input: &Input) -> Result<bool> {
     if args.hash_join && args.sort_merge_join {
         bail!("Modes --hash and --sort are mutually exclusive.")
     }
+    if let Some(n) = args.partitions {
+        if !(1..=hash::MAX_PARTITIONS).contains(&n) {
+            bail!(
+                "--partitions has to be between 1 and {}, not {}.",
+                hash::MAX_PARTITIONS,
+                n
+            )
+        }
+    }
 
-    let settings = Settings {
-        join_count: args.relations.len(),
+    let new_algo = || -> Box<dyn JoinAlgo> {
+        if args.hash_join {
+            Box::new(hash::Impl::new(args.improved, args.partitions, args.build))
+        } else {
+            Box::new(sort_merge::Impl::new(args.improved))
+        }
+    };
+    let paths = path::Evaluator {
+        input,
+        universe: &universe,
+        new_algo: &new_algo,
+        max_depth: args.max_depth,
+        dedup_alternatives: args.dedup_alternatives,
     };
 
-    let pipeline = Pipeline::build(input, &universe, &args.relations)?;
-    let mut join_impl: ManuallyDrop<Box<dyn JoinAlgo>> = ManuallyDrop::new(if args.hash_join {
-        Box::new(hash::Impl::new(args.improved))
+    let plan = Plan {
+        chains,
+        selection,
+        distinct,
+        grouping,
+        order,
+        limit,
+        offset: offset.unwrap_or(0),
+        ask,
+        leak: true,
+    };
+    let mut terms = Terms::new(input);
+    solve(&paths, plan, &mut terms, |solution, terms| {
+        write_results(
+            args,
+            solution,
+            terms,
+            construct.as_deref(),
+            &relation_labels,
+            ask,
+            distinct,
+        )
+    })
+}
+
+/// The chains of a query and how their results are combined into the final rows.
+struct Plan {
+    chains: Vec<Chain>,
+    selection: Option<Vec<Selector>>,
+    distinct: bool,
+    grouping: Option<Grouping>,
+    order: Vec<OrderKey>,
+    limit: Option<usize>,
+    offset: usize,
+    /// Only whether there is any row matters.
+    ask: bool,
+    /// Leak the tables instead of freeing them, the program exits right after the outermost
+    /// plan.
+    leak: bool,
+}
+
+impl Plan {
+    /// The plan of a nested SELECT, whose modifiers apply to its own results only.
+    fn subquery(mut query: Query) -> Result<Self> {
+        let selection = query.projection.take();
+        let grouping = query.grouping.take();
+        let order = std::mem::take(&mut query.order);
+        let (distinct, limit, offset) = (query.distinct, query.limit, query.offset);
+        Ok(Plan {
+            chains: query.into_chains(None)?,
+            selection,
+            distinct,
+            grouping,
+            order,
+            limit,
+            offset: offset.unwrap_or(0),
+            ask: false,
+            leak: false,
+        })
+    }
+}
+
+/// The variable bound by each column of a table, if any.
+type Variables<'v> = Vec<Option<&'v String>>;
+
+/// The rows resulting from a plan.
+struct Solution<'s> {
+    rows: Box<dyn ExactSizeIterator<Item = &'s Vec<Field>> + 's>,
+    /// The variable bound by each column, if any.
+    variables: Vec<Option<&'s String>>,
+    /// The number of rows before duplicates were removed.
+    raw_count: usize,
+}
+
+/// Joins the chains of `plan` and combines their results. The subqueries of each chain are
+/// evaluated first. `finish` receives the resulting rows, which only live as long as the call.
+fn solve<R>(
+    paths: &path::Evaluator,
+    plan: Plan,
+    terms: &mut Terms,
+    finish: impl FnOnce(Solution, &Terms) -> Result<R>,
+) -> Result<R> {
+    let Plan {
+        mut chains,
+        selection,
+        distinct,
+        grouping,
+        order,
+        limit,
+        offset,
+        ask,
+        leak,
+    } = plan;
+
+    let join_selection = join_selection(&chains, selection.as_deref(), grouping.as_ref())?;
+
+    // Without sorting or deduplication the first rows produced are the result, so the last step
+    // of every chain can stop early.
+    let row_limit = limit
+        .filter(|_| !distinct && order.is_empty() && grouping.is_none())
+        .map(|n| n.saturating_add(offset));
+
+    evaluate_subqueries(paths, &mut chains, terms)?;
+
+    let mut join_impls = Vec::with_capacity(chains.len());
+    let mut outputs = Vec::with_capacity(chains.len());
+    for (i, chain) in chains.iter().enumerate() {
+        if chains.len() > 1 {
+            eprintln!();
+            eprintln!("-- Evaluating alternative {} of the union", i + 1);
+        }
+        let (join_impl, output) = join_chain(
+            paths,
+            chain,
+            join_selection.as_deref(),
+            row_limit,
+            ask,
+            terms,
+        )?;
+        let found = join_impl.results().len() > 0;
+        join_impls.push(join_impl);
+        outputs.push(output);
+        if ask && found {
+            if i + 1 < chains.len() {
+                eprintln!();
+                eprintln!("-- Found a result, skipping the remaining alternatives");
+            }
+            break;
+        }
+    }
+
+    // The tables backing the rows at the different stages below.
+    let mut union_table = None;
+    let mut grouped_rows = None;
+    let distinct_rows;
+    let sorted_rows;
+    let mut variables;
+    let join_results = if let [join_impl] = &join_impls[..] {
+        variables = outputs.pop().expect("output of the only chain");
+        join_impl.results()
     } else {
-        Box::new(sort_merge::Impl::new(args.improved))
-    });
+        // Selected variables come first, in the order they were selected.
+        let selected = join_selection
+            .iter()
+            .flatten()
+            .filter_map(|selector| match selector {
+                Selector::Var(v) => Some(v),
+                Selector::Position(_) => None,
+            })
+            .collect_vec();
+        let (table, union_variables) = union(selected, &outputs, &join_impls);
+        variables = union_variables;
+        Box::new(union_table.insert(table).iter())
+    };
 
-    for (i, ((relation, name), range)) in pipeline
-        .relations
-        .into_iter()
-        .zip(&args.relations)
-        .zip(pipeline.ranges)
+    let join_results: Box<dyn ExactSizeIterator<Item = &Vec<Field>>> = match &grouping {
+        Some(grouping) => {
+            let rows = join_results.collect_vec();
+            let (table, grouped_variables) =
+                group(terms, &rows, &variables, grouping, selection.as_deref())?;
+            variables = grouped_variables;
+            Box::new(grouped_rows.insert(table).iter())
+        }
+        None => join_results,
+    };
+    let raw_count = join_results.len();
+
+    let join_results: Box<dyn ExactSizeIterator<Item = &Vec<Field>>> = if distinct {
+        eprintln!();
+        eprintln!("-- Removing duplicates");
+        distinct_rows = remove_duplicates(terms, join_results.collect_vec());
+        Box::new(distinct_rows.iter().copied())
+    } else {
+        join_results
+    };
+
+    let join_results: Box<dyn ExactSizeIterator<Item = &Vec<Field>>> = if !order.is_empty() {
+        let keys = order_keys(&order, &variables)?;
+        eprintln!();
+        eprintln!("-- Sorting by {}", order.iter().format(", "));
+        sorted_rows = sort_rows(terms, join_results.collect_vec(), &keys);
+        Box::new(sorted_rows.iter().copied())
+    } else {
+        join_results
+    };
+    let join_results = join_results.skip(offset).take(limit.unwrap_or(usize::MAX));
+    let result = finish(
+        Solution {
+            rows: Box::new(join_results),
+            variables,
+            raw_count,
+        },
+        terms,
+    );
+
+    if leak {
+        // Freeing the tables takes a while and the program is about to exit.
+        mem::forget((join_impls, union_table, grouped_rows));
+    }
+    result
+}
+
+/// The columns the joins have to provide: the selected ones, or with grouping the keys and the
+/// aggregated variables. Checks that they are bound by the chains.
+fn join_selection(
+    chains: &[Chain],
+    selection: Option<&[Selector]>,
+    grouping: Option<&Grouping>,
+) -> Result<Option<Vec<Selector>>> {
+    let join_selection = match grouping {
+        Some(grouping) => Some(
+            grouping
+                .inputs()
+                .into_iter()
+                .map(|v| Selector::Var(v.clone()))
+                .collect_vec(),
+        ),
+        None => selection.map(<[_]>::to_vec),
+    };
+    for selector in join_selection.iter().flatten() {
+        match selector {
+            Selector::Var(v) => {
+                if !chains
+                    .iter()
+                    .any(|c| c.variables.contains(&Some(v.clone())))
+                {
+                    bail!("Cannot select {}, it is not bound by the query.", selector)
+                }
+            }
+            Selector::Position(_) if chains.len() > 1 => {
+                bail!("The columns of a UNION have to be selected by variable.")
+            }
+            Selector::Position(_) => {}
+        }
+    }
+    Ok(join_selection)
+}
+
+/// Evaluates the subqueries of the chains, their results become tables or seeds of the chains.
+fn evaluate_subqueries(
+    paths: &path::Evaluator,
+    chains: &mut [Chain],
+    terms: &mut Terms,
+) -> Result<()> {
+    for chain in chains {
+        for subquery in mem::take(&mut chain.subqueries) {
+            let variables = match subquery.table {
+                Some(table) => chain.tables[table].bindings.variables.clone(),
+                None => chain
+                    .seeds
+                    .as_ref()
+                    .expect("seeds of subquery")
+                    .variables
+                    .clone(),
+            };
+            eprintln!();
+            eprintln!(
+                "-- Evaluating subquery for {}",
+                variables.iter().map(|v| format!("?{}", v)).format(", ")
+            );
+            let results = materialize(paths, subquery.query, &variables, terms)?;
+            eprintln!();
+            eprintln!("-- Subquery done, {} rows", results.rows.len());
+            match subquery.table {
+                Some(table) => chain.tables[table] = Table::new(table, results),
+                None => chain.seeds = Some(results),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Runs the steps of `chain` with its binds and filters. Returns the join algorithm holding the
+/// output columns and the variable of each of them.
+fn join_chain<'c>(
+    paths: &path::Evaluator,
+    chain: &'c Chain,
+    join_selection: Option<&[Selector]>,
+    row_limit: Option<usize>,
+    ask: bool,
+    terms: &mut Terms,
+) -> Result<(Box<dyn JoinAlgo>, Variables<'c>)> {
+    let output = chain.output_columns(join_selection)?;
+    let layout = layout::Layout::new(chain, &output);
+    let filters = chain
+        .steps
+        .iter()
         .enumerate()
-    {
+        .map(|(i, step)| {
+            let variables = layout.variables(chain, i);
+            step.filters
+                .iter()
+                .map(|expr| eval::Filter::compile(expr, &variables))
+                .collect::<Result<Vec<_>>>()
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let binds = chain
+        .steps
+        .iter()
+        .enumerate()
+        .map(|(i, step)| {
+            let variables = layout.variables(chain, i);
+            step.binds
+                .iter()
+                .map(|bind| eval::Filter::compile(&bind.expr, &variables))
+                .collect::<Result<Vec<_>>>()
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let pipeline = Pipeline::build(paths.input, paths, chain, terms)?;
+    let settings = Settings {
+        width: chain.width(),
+    };
+    let mut join_impl = (paths.new_algo)();
+    // The seeds take the place of the first step.
+    let first = match pipeline.seeds {
+        Some(rows) => {
+            eprintln!();
+            eprintln!("-- Starting from {} bound rows", rows.len());
+            join_impl.seed(rows);
+            1
+        }
+        None => 0,
+    };
+
+    for (i, (relation, step)) in pipeline.relations.into_iter().zip(&chain.steps).enumerate() {
         eprintln!();
-        eprintln!("-- Joining {}", name);
-        join_impl.join(&settings, i, relation, range);
+        eprintln!("-- Joining {}", step.path);
+        let position = &layout.positions[i];
+        let join_step = JoinStep {
+            index: first + i,
+            key: position[step.key].expect("key column dropped"),
+            column: step
+                .column
+                .map(|c| position[c].expect("output column dropped")),
+            object_key: step
+                .object_key
+                .map(|c| position[c].expect("object key column dropped")),
+            mode: step.mode,
+            limit: (i + 1 == chain.steps.len() && step.filters.is_empty())
+                .then_some(row_limit)
+                .flatten(),
+        };
+        if let Some(n) = join_step.limit {
+            eprintln!("-- Stopping after {} rows", n);
+        }
+        join_impl.join(&settings, &join_step, relation);
         eprintln!("-- {} entries", join_impl.results().len());
+
+        for (bind, expr) in step.binds.iter().zip(&binds[i]) {
+            let var = chain.variables[bind.column]
+                .as_ref()
+                .expect("computed variable");
+            eprintln!();
+            eprintln!("-- Computing ?{} as {}", var, bind.expr);
+            let rows = join_impl.results().collect_vec();
+            let values = rows
+                .par_iter()
+                .map(|row| expr.term(terms, row))
+                .collect::<Vec<_>>();
+            // Store each distinct value once.
+            let mut known = HashMap::new();
+            let fields = values
+                .into_iter()
+                .map(|value| match value {
+                    Some(term) => *known
+                        .entry(term)
+                        .or_insert_with_key(|term: &String| terms.add(term.clone())),
+                    None => Field::INVALID,
+                })
+                .collect();
+            join_impl.fill(
+                position[bind.column].expect("computed column dropped"),
+                fields,
+            );
+        }
+
+        if !step.filters.is_empty() {
+            eprintln!();
+            eprintln!("-- Filtering {}", step.filters.iter().format(" && "));
+            let filters = &filters[i];
+            join_impl.retain(&|row| filters.iter().all(|f| f.matches(terms, row)));
+            eprintln!("-- {} entries", join_impl.results().len());
+        }
+
+        if let Some(keep) = &layout.projections[i] {
+            eprintln!("-- Dropping columns, keeping {}", keep.len());
+            join_impl.project(keep);
+        }
+
+        // No later step can add rows to an empty join table.
+        if ask && join_impl.results().len() == 0 {
+            eprintln!("-- No rows left, skipping the remaining steps");
+            break;
+        }
+    }
+    if let Some(order) = &layout.output {
+        join_impl.project(order);
+    }
+
+    let variables = output
+        .iter()
+        .map(|&c| chain.variables[c].as_ref())
+        .collect_vec();
+    Ok((join_impl, variables))
+}
+
+/// Groups and aggregates the rows, whose columns bind `variables`, and keeps the groups passing
+/// HAVING. The resulting rows hold the selected keys and aggregates, also returns the variable of
+/// each of their columns.
+fn group<'v>(
+    terms: &mut Terms,
+    rows: &[&Vec<Field>],
+    variables: &[Option<&String>],
+    grouping: &'v Grouping,
+    selection: Option<&[Selector]>,
+) -> Result<(Vec<Vec<Field>>, Variables<'v>)> {
+    eprintln!();
+    if grouping.keys.is_empty() {
+        eprintln!("-- Aggregating");
+    } else {
+        eprintln!(
+            "-- Grouping by {}",
+            grouping.keys.iter().map(|k| format!("?{}", k)).format(", ")
+        );
+    }
+    let mut table = aggregate::aggregate(terms, rows, variables, grouping);
+    eprintln!("-- {} groups", table.len());
+
+    // The rows hold the keys followed by the aggregates.
+    let names = grouping
+        .keys
+        .iter()
+        .chain(grouping.aggregates.iter().map(|(name, _)| name))
+        .map(Some)
+        .collect_vec();
+    if !grouping.having.is_empty() {
+        eprintln!();
+        eprintln!("-- Filtering {}", grouping.having.iter().format(" && "));
+        let filters = grouping
+            .having
+            .iter()
+            .map(|expr| eval::Filter::compile(expr, &names))
+            .collect::<Result<Vec<_>>>()?;
+        let terms = &*terms;
+        table = table
+            .into_par_iter()
+            .filter(|row| filters.iter().all(|f| f.matches(terms, row)))
+            .collect();
+        eprintln!("-- {} groups", table.len());
+    }
+
+    // The parser makes sure that only keys and aggregates are selected.
+    let columns = selection
+        .iter()
+        .copied()
+        .flatten()
+        .map(|selector| match selector {
+            Selector::Var(v) => names
+                .iter()
+                .position(|&name| name == Some(v))
+                .expect("selected variable is grouped or aggregated"),
+            Selector::Position(p) => *p,
+        })
+        .collect_vec();
+    table
+        .par_iter_mut()
+        .for_each(|row| *row = columns.iter().map(|&c| row[c]).collect());
+    let variables = columns.iter().map(|&c| names[c]).collect();
+    Ok((table, variables))
+}
+
+/// Resolves the columns of the sort keys in the rows, whose columns bind `variables`. Each
+/// column is paired with whether it is sorted in descending order.
+fn order_keys(order: &[OrderKey], variables: &[Option<&String>]) -> Result<Vec<(usize, bool)>> {
+    order
+        .iter()
+        .map(|key| {
+            let column = match &key.column {
+                Selector::Position(p) if *p < variables.len() => *p,
+                Selector::Var(v) => match variables.iter().position(|&var| var == Some(v)) {
+                    Some(c) => c,
+                    None => bail!("Cannot order by ?{}, it is not part of the result.", v),
+                },
+                Selector::Position(p) => bail!(
+                    "Cannot order by column {}, the result has only {} columns.",
+                    p,
+                    variables.len()
+                ),
+            };
+            Ok((column, key.descending))
+        })
+        .collect()
+}
+
+/// Evaluates a subquery and decodes its results into a table with the given columns.
+fn materialize(
+    paths: &path::Evaluator,
+    query: Query,
+    variables: &[String],
+    terms: &mut Terms,
+) -> Result<Bindings> {
+    solve(paths, Plan::subquery(query)?, terms, |solution, terms| {
+        let columns = variables
+            .iter()
+            .map(|var| solution.variables.iter().position(|&v| v == Some(var)))
+            .collect_vec();
+        let rows = solution
+            .rows
+            .map(|row| {
+                columns
+                    .iter()
+                    .map(|&c| {
+                        let field = row[c?];
+                        field
+                            .is_valid()
+                            .then(|| terms.extract_str(field).decode().into_owned())
+                    })
+                    .collect()
+            })
+            .collect();
+        Ok(Bindings {
+            variables: variables.to_vec(),
+            rows,
+        })
+    })
+}
+
+/// Writes the results in the requested format. Returns the answer of ASK queries, `true`
+/// otherwise.
+fn write_results(
+    args: &Args,
+    solution: Solution,
+    terms: &Terms,
+    construct: Option<&[TriplePattern]>,
+    relation_labels: &HashMap<String, String>,
+    ask: bool,
+    distinct: bool,
+) -> Result<bool> {
+    let mut out: Box<dyn Write> = match &args.output {
+        Some(path) => {
+            Box::new(BufWriter::new(File::create(path).with_context(|| {
+                format!("Cannot create output file {}", path.display())
+            })?))
+        }
+        None => Box::new(io::stdout().lock()),
+    };
+    if ask {
+        let answer = solution.rows.len() > 0;
+        eprintln!();
+        writeln!(out, "{}", answer)?;
+        out.flush()?;
+        return Ok(answer);
+    }
+
+    let result_count = solution.rows.len();
+    let summary = if distinct {
+        format!("{} results, {} distinct", solution.raw_count, result_count)
+    } else {
+        format!("{} results", result_count)
+    };
+
+    if let Some(template) = construct {
+        let triples = construct::write_triples(
+            &mut out,
+            template,
+            &solution.variables,
+            solution.rows,
+            terms,
+            args.dedup_triples,
+        )?;
+        out.flush()?;
+        eprintln!();
+        eprintln!("{}, {} triples", summary, triples);
+        return Ok(true);
+    }
+    if args.format != OutputFormat::Table {
+        write_delimited(
+            &mut out,
+            args.format,
+            &solution.variables,
+            solution.rows,
+            terms,
+        )?;
+        out.flush()?;
+        eprintln!();
+        eprintln!("{}", summary);
+        return Ok(true);
     }
 
-    let join_results = join_impl.results();
-    let result_count = join_results.len();
+    let header = solution
+        .variables
+        .iter()
+        .map(
+            |var| match (var, var.and_then(|v| relation_labels.get(v))) {
+                (Some(v), Some(relation)) => format!("?{} {}", v, relation),
+                (Some(v), None) => format!("?{}", v),
+                (None, _) => String::new(),
+            },
+        )
+        .collect_vec();
 
-    println!();
+    writeln!(out)?;
     if args.print_result {
         let print_count = if args.print_count > 0 {
             result_count.min(args.print_count)
@@ -102,16 +838,19 @@ pub fn join(args: &Args, input: &Input) -> Result<bool> {
         };
 
         // Decode all columns.
-        let decoded = join_results
+        let decoded = solution
+            .rows
             .take(print_count)
-            .enumerate()
-            .map(|(i, fields)| {
-                if fields.contains(&Field::INVALID) {
-                    eprintln!("invalid field in row {}: {:?}", i, fields);
-                }
+            .map(|fields| {
                 fields
                     .iter()
-                    .map(|f| input.extract_str(*f).decode())
+                    .map(|f| {
+                        if f.is_valid() {
+                            terms.extract_str(*f).decode()
+                        } else {
+                            UNBOUND.into()
+                        }
+                    })
                     .collect_vec()
             })
             .collect_vec();
@@ -121,8 +860,11 @@ pub fn join(args: &Args, input: &Input) -> Result<bool> {
             .par_iter()
             .map(|cols| Columns(cols.iter().map(|c| c.len().max(1)).collect_vec()))
             .collect();
+        let widths = zip(widths.into_iter().chain(std::iter::repeat(1)), &header)
+            .map(|(w, h)| w.max(h.len()))
+            .collect_vec();
 
-        let write_div = |h: &mut io::StdoutLock, cbase: char, csplit: char| -> Result<()> {
+        let write_div = |h: &mut dyn Write, cbase: char, csplit: char| -> Result<()> {
             for (i, w) in widths.iter().copied().enumerate() {
                 if i == 0 {
                     // The first column gets an extra `cbase`.
@@ -142,25 +884,18 @@ pub fn join(args: &Args, input: &Input) -> Result<bool> {
         };
 
         // Print everything.
-        let mut io = io::stdout().lock();
-        write_div(&mut io, '═', '╤')?;
+        let io = &mut out;
+        write_div(io, '═', '╤')?;
+        write_row(io, &header, &widths)?;
+        write_div(io, '─', '┼')?;
         for (i, cols) in decoded.into_iter().enumerate() {
             if i % 5 == 0 && i > 0 {
-                write_div(&mut io, '─', '┼')?;
-            }
-            for (i, (field, width)) in zip(&cols[..cols.len() - 1], &widths).enumerate() {
-                if i == 0 {
-                    write!(io, " ")?;
-                }
-                write!(io, "{field:width$} │ ")?;
+                write_div(io, '─', '┼')?;
             }
-            if let Some(last_field) = cols.last() {
-                write!(io, "{last_field}")?;
-            }
-            writeln!(io)?;
+            write_row(io, &cols, &widths)?;
         }
         if print_count > 0 && print_count % 5 == 0 && print_count < result_count {
-            write_div(&mut io, '─', '┼')?;
+            write_div(io, '─', '┼')?;
         }
         if print_count < result_count {
             for (i, w) in widths.iter().copied().enumerate() {
@@ -173,13 +908,188 @@ pub fn join(args: &Args, input: &Input) -> Result<bool> {
             }
             writeln!(io)?;
         }
-        write_div(&mut io, '═', '╧')?;
+        write_div(io, '═', '╧')?;
     }
 
-    println!("{} results", result_count);
+    writeln!(out, "{}", summary)?;
+    out.flush()?;
     Ok(true)
 }
 
+/// Writes a row of the result table, every column but the last is padded to its width.
+fn write_row(io: &mut impl Write, cols: &[impl Display], widths: &[usize]) -> Result<()> {
+    if let Some((last, cols)) = cols.split_last() {
+        for (i, (field, width)) in zip(cols, widths).enumerate() {
+            if i == 0 {
+                write!(io, " ")?;
+            }
+            write!(io, "{field:width$} │ ")?;
+        }
+        write!(io, "{last}")?;
+    }
+    writeln!(io)?;
+    Ok(())
+}
+
+/// Writes all results as SPARQL TSV or CSV: a header row naming the variables, followed by the
+/// rows. Unbound fields are left empty. TSV keeps the terms as they are, CSV writes the plain
+/// values of IRIs and literals and quotes them where necessary.
+fn write_delimited<'a>(
+    io: &mut impl Write,
+    format: OutputFormat,
+    variables: &[Option<&String>],
+    rows: impl Iterator<Item = &'a Vec<Field>>,
+    terms: &Terms,
+) -> Result<()> {
+    let (separator, prefix) = match format {
+        OutputFormat::Tsv => ('\t', "?"),
+        OutputFormat::Csv => (',', ""),
+        OutputFormat::Table => unreachable!("the table is not delimited"),
+    };
+    let header = variables
+        .iter()
+        .map(|var| var.map_or(String::new(), |v| format!("{}{}", prefix, v)));
+    writeln!(io, "{}", header.format(&separator.to_string()))?;
+    for row in rows {
+        for (i, field) in row.iter().enumerate() {
+            if i > 0 {
+                write!(io, "{}", separator)?;
+            }
+            if !field.is_valid() {
+                continue;
+            }
+            let term = terms.extract_str(*field).decode();
+            if format == OutputFormat::Tsv {
+                write!(io, "{}", term)?;
+                continue;
+            }
+            let value = match eval::Value::from_term(term) {
+                eval::Value::Literal(s) => s.into_owned(),
+                value => value.as_str().into_owned(),
+            };
+            if value.contains([',', '"', '\n', '\r']) {
+                write!(io, "\"{}\"", value.replace('"', "\"\""))?;
+            } else {
+                write!(io, "{}", value)?;
+            }
+        }
+        writeln!(io)?;
+    }
+    Ok(())
+}
+
+/// Concatenates the results of the alternatives of a union. `outputs` names the variable of each
+/// output column of the alternatives. Columns binding the same variable are merged, all other
+/// columns are left unbound in the rows of the other alternatives. The columns of `selected`
+/// come first. Also returns the variable of each column of the combined rows.
+fn union<'v>(
+    selected: Vec<&'v String>,
+    outputs: &[Vec<Option<&'v String>>],
+    join_impls: &[Box<dyn JoinAlgo>],
+) -> (Vec<Vec<Field>>, Variables<'v>) {
+    let mut variables = selected.into_iter().map(Some).collect_vec();
+    let positions = outputs
+        .iter()
+        .map(|output| {
+            output
+                .iter()
+                .map(|&var| {
+                    let known =
+                        var.and_then(|v| variables.iter().position(|&other| other == Some(v)));
+                    known.unwrap_or_else(|| {
+                        variables.push(var);
+                        variables.len() - 1
+                    })
+                })
+                .collect_vec()
+        })
+        .collect_vec();
+
+    eprintln!();
+    eprintln!("-- Combining {} alternatives", outputs.len());
+    let width = variables.len();
+    let mut table = Vec::new();
+    for (join_impl, positions) in zip(join_impls, &positions) {
+        let rows = join_impl.results().collect_vec();
+        table.par_extend(rows.into_par_iter().map(|fields| {
+            let mut row = vec![Field::INVALID; width];
+            for (&field, &pos) in zip(fields, positions) {
+                row[pos] = field;
+            }
+            row
+        }));
+    }
+    (table, variables)
+}
+
+/// Removes duplicate rows, keeping the first occurrence of each. Rows are compared by their
+/// decoded values since equal values are not represented by the same field in every column.
+fn remove_duplicates<'r>(terms: &Terms, rows: Vec<&'r Vec<Field>>) -> Vec<&'r Vec<Field>> {
+    let first_seen = |mut seen: HashMap<_, _>, (key, i)| {
+        seen.entry(key)
+            .and_modify(|j: &mut usize| *j = (*j).min(i))
+            .or_insert(i);
+        seen
+    };
+    let unique = rows
+        .par_iter()
+        .enumerate()
+        .map(|(i, row)| {
+            let key = row
+                .iter()
+                .map(|&f| f.is_valid().then(|| terms.extract_str(f)))
+                .collect_vec();
+            (key, i)
+        })
+        .fold(HashMap::new, first_seen)
+        .reduce(HashMap::new, |a, b| {
+            let (large, small) = if a.len() < b.len() { (b, a) } else { (a, b) };
+            small.into_iter().fold(large, first_seen)
+        });
+
+    let mut indices = unique.into_values().collect_vec();
+    indices.par_sort_unstable();
+    indices.into_iter().map(|i| rows[i]).collect()
+}
+
+/// Sorts the rows by the given columns, each paired with whether it is sorted in descending
+/// order. The sort is stable, rows which compare equal keep their order.
+fn sort_rows<'r>(
+    terms: &Terms,
+    rows: Vec<&'r Vec<Field>>,
+    keys: &[(usize, bool)],
+) -> Vec<&'r Vec<Field>> {
+    // Decode the sort keys once instead of in every comparison.
+    let mut keyed = rows
+        .into_par_iter()
+        .map(|row| {
+            let values = keys
+                .iter()
+                .map(|&(c, _)| {
+                    row[c]
+                        .is_valid()
+                        .then(|| eval::Value::from_term(terms.extract_str(row[c]).decode()))
+                })
+                .collect_vec();
+            (values, row)
+        })
+        .collect::<Vec<_>>();
+    keyed.par_sort_by(|(a, _), (b, _)| {
+        zip(zip(a, b), keys)
+            .map(|((x, y), &(_, descending))| {
+                let ord = eval::Value::order(x.as_ref(), y.as_ref());
+                if descending {
+                    ord.reverse()
+                } else {
+                    ord
+                }
+            })
+            .find(|ord| ord.is_ne())
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    keyed.into_iter().map(|(_, row)| row).collect()
+}
+
 #[derive(Default)]
 struct Columns(Vec<usize>);
 
@@ -225,23 +1135,75 @@ impl FromParallelIterator<Columns> for Columns {
 }
 
 struct Settings {
-    pub join_count: usize,
+    /// Number of columns in the join table.
+    pub width: usize,
+}
+
+/// The columns of the join table a step works on.
+#[derive(Debug, Clone, Copy)]
+struct JoinStep {
+    /// Position of the step in the chain, the seeds count as a step. The first step initializes
+    /// the join table.
+    pub index: usize,
+    /// The column matched against the subjects of the relation.
+    pub key: usize,
+    /// The column the objects of the relation are written to, if any.
+    pub column: Option<usize>,
+    /// For a step which only filters the rows: the column also matched against the objects of
+    /// the relation. Rows leaving it unbound match any object.
+    pub object_key: Option<usize>,
+    pub mode: JoinMode,
+    /// The number of rows after which the step may stop producing more, `None` if all rows are
+    /// needed.
+    pub limit: Option<usize>,
+}
+
+/// Counts the rows produced by a join step with a limit. Shared between the threads of a step so
+/// that all of them stop once enough rows exist.
+struct Quota {
+    limit: usize,
+    claimed: AtomicUsize,
+}
+
+impl Quota {
+    fn new(limit: Option<usize>) -> Self {
+        Quota {
+            limit: limit.unwrap_or(usize::MAX),
+            claimed: AtomicUsize::new(0),
+        }
+    }
+
+    /// Whether the limit has been reached.
+    fn exhausted(&self) -> bool {
+        self.claimed.load(atomic::Ordering::Relaxed) >= self.limit
+    }
+
+    /// Claims a row. Returns `false` if the limit has been reached, no more rows may be added.
+    fn claim(&self) -> bool {
+        self.claimed.fetch_add(1, atomic::Ordering::Relaxed) < self.limit
+    }
 }
 
 trait JoinAlgo {
-    fn join(
-        &mut self,
-        settings: &Settings,
-        index: usize,
-        relation: Relation,
-        field_range: (Field, Field),
-    );
+    fn join(&mut self, settings: &Settings, step: &JoinStep, relation: Relation);
     fn results<'a>(&'a self) -> Box<dyn ExactSizeIterator<Item = &'a Vec<Field>> + 'a>;
+    /// Starts the join table from `rows` instead of the entries of the first relation.
+    fn seed(&mut self, rows: Vec<Vec<Field>>);
+    /// Writes `values` to `column` of the rows, in the order the rows are returned by `results`.
+    fn fill(&mut self, column: usize, values: Vec<Field>);
+    /// Keeps only the rows of the join table matching `predicate`.
+    fn retain(&mut self, predicate: &(dyn Fn(&[Field]) -> bool + Sync));
+    /// Replaces every row of the join table by the fields at `columns`.
+    fn project(&mut self, columns: &[usize]);
 }
 
 mod hash {
 
-    use std::{collections::HashMap, mem, ops::Range};
+    use std::{
+        collections::{HashMap, HashSet},
+        mem,
+        path::Path,
+    };
 
     use rayon::iter::*;
 
@@ -249,105 +1211,151 @@ mod hash {
 
     use super::*;
 
+    /// Upper bound for the number of partitions, more of them only add overhead.
+    pub const MAX_PARTITIONS: usize = 1 << 16;
+
+    /// Partitions per thread by default, so that threads finishing early can take over some of
+    /// the work of the others.
+    const PARTITIONS_PER_THREAD: usize = 4;
+
+    /// Rows per partition below which splitting the work further does not pay off.
+    const MIN_PARTITION_ROWS: usize = 1024;
+
+    /// Assumed size of the L2 cache if the system does not tell.
+    const DEFAULT_CACHE_SIZE: usize = 256 * 1024;
+
     pub struct Impl {
         improved: bool,
+        /// Number of partitions of the improved join, `None` to choose it for each step.
+        partitions: Option<usize>,
+        /// Size of the L2 cache in bytes, partitions are made to fit into it.
+        cache_size: usize,
+        build_side: BuildSide,
         join_table: Vec<Vec<Field>>,
-        field_ranges: [Range<Field>; 8],
-        hash_tables: [HashMap<Field, Vec<Vec<Field>>>; 8],
+        hash_table: HashMap<Field, Vec<Vec<Field>>>,
     }
 
     impl Impl {
-        pub fn new(improved: bool) -> Self {
+        pub fn new(improved: bool, partitions: Option<usize>, build_side: BuildSide) -> Self {
             Impl {
                 improved,
+                partitions,
+                cache_size: cache_size().unwrap_or(DEFAULT_CACHE_SIZE),
+                build_side,
                 join_table: Vec::new(),
-                field_ranges: [
-                    Field::INVALID..Field::INVALID,
-                    Field::INVALID..Field::INVALID,
-                    Field::INVALID..Field::INVALID,
-                    Field::INVALID..Field::INVALID,
-                    Field::INVALID..Field::INVALID,
-                    Field::INVALID..Field::INVALID,
-                    Field::INVALID..Field::INVALID,
-                    Field::INVALID..Field::INVALID,
-                ],
-                hash_tables: [
-                    HashMap::new(),
-                    HashMap::new(),
-                    HashMap::new(),
-                    HashMap::new(),
-                    HashMap::new(),
-                    HashMap::new(),
-                    HashMap::new(),
-                    HashMap::new(),
-                ],
-            }
-        }
-
-        /// Hashes `self.join_table` into `self.hash_tables[0]`. `self.field_ranges[0]` is
-        /// adjusted to include the whole set of ranges.
-        fn simple_hash(&mut self, index: usize) {
+                hash_table: HashMap::new(),
+            }
+        }
+
+        /// The number of partitions for a build side of `rows` rows taking `row_size` bytes each
+        /// in the hash table: a few per thread unless they would get too small, and enough for
+        /// each of them to fit into the cache.
+        fn partition_count(&self, rows: usize, row_size: usize) -> usize {
+            let bytes = rows * row_size;
+            let for_cache = bytes.div_ceil(self.cache_size);
+            let for_threads = (rayon::current_num_threads() * PARTITIONS_PER_THREAD)
+                .min(rows / MIN_PARTITION_ROWS);
+            for_cache.max(for_threads).clamp(1, MAX_PARTITIONS)
+        }
+
+        /// Hashes `self.join_table` into `self.hash_table`.
+        fn simple_hash(&mut self, key: usize) {
             eprintln!(
                 "++ Hashing left hand side ({} entries)",
                 self.join_table.len()
             );
 
             while let Some(fields) = self.join_table.pop() {
-                self.hash_tables[0]
-                    .entry(fields[index])
-                    .or_default()
-                    .push(fields)
+                self.hash_table.entry(fields[key]).or_default().push(fields)
             }
-
-            self.field_ranges.fill(Field::INVALID..Field::INVALID);
-            self.field_ranges[0] = Field::from_offset(0).make_range(usize::MAX);
         }
 
-        /// Hashes `self.join_table` into the full width of `self.hash_tables`.
-        /// `self.field_ranges` is adjusted to reflect the partitioning.
-        fn partitioned_hash(&mut self, index: usize, field_range: (Field, Field)) {
-            debug_assert!(
-                field_range.0 <= field_range.1,
-                "invalid range: {:?}",
-                field_range
-            );
-            debug_assert!(field_range.0 != Field::INVALID);
+        /// Hashes the entries of `relation` by their subjects and looks up the key of every row
+        /// of `self.join_table`.
+        fn probe_join_table(&mut self, step: &JoinStep, relation: Relation) {
+            eprintln!("++ Hashing right hand side ({} entries)", relation.len());
+            let table = hash_entries(relation);
 
             eprintln!(
-                "++ Hashing left hand side ({} entries)",
+                "++ Probing with left hand side ({} entries)",
                 self.join_table.len()
             );
-            let full_range = field_range.0.offset()..field_range.1.offset() + 1;
-            let per_chunk = usize::max(full_range.len() / 8, 128);
+            let quota = &Quota::new(step.limit);
+            self.join_table = mem::take(&mut self.join_table)
+                .into_par_iter()
+                .flat_map_iter(|row| {
+                    let objects = table.get(&row[step.key]).map_or(&[][..], Vec::as_slice);
+                    extend_row(row, objects, step, quota)
+                })
+                .collect();
+        }
 
-            // Distribute the ranges.
-            self.field_ranges.fill(Field::INVALID..Field::INVALID);
-            self.field_ranges[0] = Field::from_offset(0).make_range(full_range.start + per_chunk);
+        /// Joins `relation` with `self.join_table` one partition at a time: both sides are split
+        /// by the hash of their keys, then each partition is hashed and probed on its own.
+        /// Hashing balances the partitions even if the keys cluster in parts of the input.
+        fn partitioned_join(
+            &mut self,
+            settings: &Settings,
+            step: &JoinStep,
+            relation: Relation,
+            build_right: bool,
+        ) {
+            let key = step.key;
+            let partitions = self.partitions.unwrap_or_else(|| {
+                if build_right {
+                    self.partition_count(relation.len(), ENTRY_SIZE)
+                } else {
+                    let row_size = settings.width * mem::size_of::<Field>() + ROW_OVERHEAD;
+                    self.partition_count(self.join_table.len(), row_size)
+                }
+            });
 
-            for i in 1..self.field_ranges.len() {
-                self.field_ranges[i] = self.field_ranges[i - 1].end.make_range(per_chunk);
-            }
+            eprintln!(
+                "++ Partitioning both sides ({} and {} entries) into {} partitions",
+                self.join_table.len(),
+                relation.len(),
+                partitions
+            );
+            let mut join_table = mem::take(&mut self.join_table);
+            let rows = partition(&mut join_table, partitions, |row| row[key], mem::take);
+            let mut relation = relation;
+            let entries = partition(&mut relation, partitions, |&(subj, _)| subj, |entry| *entry);
+            drop((join_table, relation));
 
-            // For each range hash the correct set of elements from join_table.
-            self.field_ranges
-                .par_iter()
-                .zip(self.hash_tables.par_iter_mut())
-                .for_each(|(range, table)| {
-                    for fields in &self.join_table {
-                        if range.contains(&fields[index]) {
-                            table.entry(fields[index]).or_default().push(fields.clone());
-                        }
-                    }
-                });
+            let quota = &Quota::new(step.limit);
+            eprintln!(
+                "++ Hashing the {} hand side of each partition and probing it",
+                if build_right { "right" } else { "left" }
+            );
+            self.join_table = rows
+                .into_par_iter()
+                .zip(entries)
+                .flat_map_iter(|(rows, entries)| {
+                    let rows = rows.into_iter().flatten();
+                    let entries = entries.into_iter().flatten();
+                    join_partition(rows, entries, step, quota, build_right)
+                })
+                .collect();
         }
 
-        fn scan_hashed(&mut self, index: usize, relation: Relation) {
+        fn scan_hashed(&mut self, step: &JoinStep, relation: Relation) {
+            let column = step.column.expect("joining step without column");
+
             // Clear out the old join table (which now exists in hashed form) in parallel.
             eprintln!("++ Clearing out join table",);
             mem::take(&mut self.join_table)
                 .into_par_iter()
                 .for_each(mem::drop);
 
+            let quota = &Quota::new(step.limit);
+
+            // Remember which keys are matched to find the rows to keep for an outer join.
+            let probed: HashSet<Field> = if step.mode == JoinMode::Optional {
+                relation.par_iter().map(|&(subj, _)| subj).collect()
+            } else {
+                HashSet::new()
+            };
+
             eprintln!(
                 "++ Scanning through right hand side ({} entries)",
                 relation.len()
@@ -355,58 +1363,311 @@ mod hash {
             self.join_table = relation
                 .into_par_iter()
                 .flat_map_iter(|(subj, obj)| {
-                    // Find the correct index. Although partition_point can return an index equal
-                    // to the slice length we know that all values inside the relation are
-                    // included in the range based on how they are built in Pipeline::build.
-                    let idx = self.field_ranges.partition_point(|r| r.start <= subj);
-                    let hm = &self.hash_tables[idx - 1];
-                    hm.get(&subj).into_iter().flat_map(move |field_list| {
-                        field_list.iter().cloned().map(move |mut fields| {
-                            fields[index + 1] = obj;
-                            fields
-                        })
+                    let matches = self.hash_table.get(&subj).filter(|_| !quota.exhausted());
+                    matches.into_iter().flat_map(move |field_list| {
+                        field_list
+                            .iter()
+                            .take_while(|_| quota.claim())
+                            .cloned()
+                            .map(move |mut fields| {
+                                fields[column] = obj;
+                                fields
+                            })
                     })
                 })
                 .collect();
+
+            if step.mode == JoinMode::Optional {
+                // The rows without a match keep their unbound column.
+                eprintln!("++ Keeping unmatched rows");
+                let unmatched = self
+                    .hash_table
+                    .par_iter()
+                    .filter(|(k, _)| !probed.contains(k))
+                    .flat_map_iter(|(_, field_list)| {
+                        field_list.iter().take_while(|_| quota.claim()).cloned()
+                    });
+                self.join_table.par_extend(unmatched);
+            }
+        }
+
+        /// Keeps the rows whose key appears among the subjects of `relation` for a semi join or
+        /// the rows whose key does not appear for an anti join. Only the keys are needed, so they
+        /// are hashed instead of the join table.
+        fn filter(&mut self, step: &JoinStep, relation: Relation) {
+            eprintln!("++ Hashing right hand side ({} entries)", relation.len());
+            let keys: HashSet<Field> = relation.par_iter().map(|&(subj, _)| subj).collect();
+            // Rows with a bound object key need an entry matching both of their ends.
+            let entries: HashSet<(Field, Field)> = match step.object_key {
+                Some(_) => relation.into_par_iter().collect(),
+                None => HashSet::new(),
+            };
+            let keep_matched = step.mode == JoinMode::Semi;
+            let quota = Quota::new(step.limit);
+
+            eprintln!(
+                "++ Filtering left hand side ({} entries)",
+                self.join_table.len()
+            );
+            let matches = |fields: &Vec<Field>| {
+                let key = fields[step.key];
+                match step.object_key.map(|c| fields[c]).filter(|f| f.is_valid()) {
+                    Some(obj) => entries.contains(&(key, obj)),
+                    None => keys.contains(&key),
+                }
+            };
+            self.join_table = mem::take(&mut self.join_table)
+                .into_par_iter()
+                .filter(|fields| matches(fields) == keep_matched && quota.claim())
+                .collect();
         }
     }
 
     impl JoinAlgo for Impl {
-        fn join(
-            &mut self,
-            settings: &Settings,
-            index: usize,
-            relation: Relation,
-            field_range: (Field, Field),
-        ) {
-            if index == 0 {
+        fn join(&mut self, settings: &Settings, step: &JoinStep, relation: Relation) {
+            if step.index == 0 {
+                let column = step.column.expect("first step without column");
+                let limit = step.limit.unwrap_or(usize::MAX);
                 self.join_table
-                    .extend(relation.into_iter().map(|(subj, obj)| {
-                        let mut v = vec![Field::INVALID; settings.join_count + 1];
+                    .extend(relation.into_iter().take(limit).map(|(subj, obj)| {
+                        let mut v = vec![Field::INVALID; settings.width];
                         v[0] = subj;
-                        v[1] = obj;
+                        v[column] = obj;
                         v
                     }));
                 return;
             }
 
-            eprintln!("++ Clearing out hash tables.");
-            self.hash_tables
-                .par_iter_mut()
-                .for_each(|table| table.clear());
+            if relation.is_empty() {
+                // Nothing can match.
+                eprintln!("++ Right hand side is empty");
+                if matches!(step.mode, JoinMode::Inner | JoinMode::Semi) {
+                    self.join_table.clear();
+                } else if let Some(limit) = step.limit {
+                    self.join_table.truncate(limit);
+                }
+                return;
+            }
+
+            if step.column.is_none() {
+                self.filter(step, relation);
+                return;
+            }
 
+            let build_right = match self.build_side {
+                BuildSide::Auto => relation.len() < self.join_table.len(),
+                BuildSide::Left => false,
+                BuildSide::Right => true,
+            };
             if self.improved {
-                self.partitioned_hash(index, field_range);
-            } else {
-                self.simple_hash(index);
+                self.partitioned_join(settings, step, relation, build_right);
+                return;
             }
 
-            self.scan_hashed(index, relation)
+            eprintln!("++ Clearing out hash table.");
+            self.hash_table.clear();
+            if build_right {
+                self.probe_join_table(step, relation);
+                return;
+            }
+            self.simple_hash(step.key);
+            self.scan_hashed(step, relation)
         }
 
         fn results<'a>(&'a self) -> Box<dyn ExactSizeIterator<Item = &'a Vec<Field>> + 'a> {
             Box::new(self.join_table.iter())
         }
+
+        fn seed(&mut self, rows: Vec<Vec<Field>>) {
+            self.join_table = rows;
+        }
+
+        fn fill(&mut self, column: usize, values: Vec<Field>) {
+            self.join_table
+                .par_iter_mut()
+                .zip(values)
+                .for_each(|(fields, value)| fields[column] = value);
+        }
+
+        fn retain(&mut self, predicate: &(dyn Fn(&[Field]) -> bool + Sync)) {
+            self.join_table = mem::take(&mut self.join_table)
+                .into_par_iter()
+                .filter(|fields| predicate(fields))
+                .collect();
+        }
+
+        fn project(&mut self, columns: &[usize]) {
+            self.join_table
+                .par_iter_mut()
+                .for_each(|fields| *fields = columns.iter().map(|&c| fields[c]).collect());
+        }
+    }
+
+    /// Bytes taken by a row besides its fields: the vector itself and its share of the hash
+    /// table.
+    const ROW_OVERHEAD: usize = mem::size_of::<Vec<Field>>() + 2 * mem::size_of::<Field>();
+
+    /// Bytes taken by an entry of the relation in the hash table: its object and its share of
+    /// the table.
+    const ENTRY_SIZE: usize = 3 * mem::size_of::<Field>();
+
+    /// Groups the objects of `entries` by their subjects.
+    fn hash_entries(
+        entries: impl IntoIterator<Item = (Field, Field)>,
+    ) -> HashMap<Field, Vec<Field>> {
+        let mut table: HashMap<Field, Vec<Field>> = HashMap::new();
+        for (subj, obj) in entries {
+            table.entry(subj).or_default().push(obj);
+        }
+        table
+    }
+
+    /// Joins the rows and entries of a partition by hashing one side and probing it with the
+    /// other. Hashing the rows groups the objects of the entries by the row keys, so both ways
+    /// end up extending each row with the objects matching its key.
+    fn join_partition(
+        rows: impl Iterator<Item = Vec<Field>>,
+        entries: impl Iterator<Item = (Field, Field)>,
+        step: &JoinStep,
+        quota: &Quota,
+        build_right: bool,
+    ) -> Vec<Vec<Field>> {
+        let key = step.key;
+        if build_right {
+            let table = hash_entries(entries);
+            return rows
+                .flat_map(|row| {
+                    let objects = table.get(&row[key]).map_or(&[][..], Vec::as_slice);
+                    extend_row(row, objects, step, quota)
+                })
+                .collect();
+        }
+
+        // The objects matching each key and the rows with the key.
+        let mut table: HashMap<Field, (Vec<Field>, Vec<Vec<Field>>)> = HashMap::new();
+        for row in rows {
+            table.entry(row[key]).or_default().1.push(row);
+        }
+        for (subj, obj) in entries {
+            if let Some((objects, _)) = table.get_mut(&subj) {
+                objects.push(obj);
+            }
+        }
+        table
+            .into_values()
+            .flat_map(|(objects, rows)| {
+                rows.into_iter()
+                    .flat_map(move |row| extend_row(row, &objects, step, quota))
+            })
+            .collect()
+    }
+
+    /// The rows resulting from `row` given the `objects` of the entries matching its key: one
+    /// for each object, or the row itself without any for an outer join.
+    fn extend_row(
+        row: Vec<Field>,
+        objects: &[Field],
+        step: &JoinStep,
+        quota: &Quota,
+    ) -> Vec<Vec<Field>> {
+        let column = step.column.expect("joining step without column");
+        if objects.is_empty() {
+            let keep = step.mode == JoinMode::Optional && quota.claim();
+            return if keep { vec![row] } else { Vec::new() };
+        }
+        objects
+            .iter()
+            .take_while(|_| quota.claim())
+            .map(|&obj| {
+                let mut row = row.clone();
+                row[column] = obj;
+                row
+            })
+            .collect()
+    }
+
+    /// Splits `items` into `partitions` partitions by the hash of their `key`. Each thread takes
+    /// a chunk of the items, counts how many of them fall into every partition and then moves
+    /// them into buffers of exactly that size using `take`. Returns the parts of each partition,
+    /// one for each chunk.
+    fn partition<T: Send>(
+        items: &mut [T],
+        partitions: usize,
+        key: impl Fn(&T) -> Field + Sync,
+        take: impl Fn(&mut T) -> T + Sync,
+    ) -> Vec<Vec<Vec<T>>> {
+        let chunk_size = items.len().div_ceil(rayon::current_num_threads()).max(1);
+        let chunks = items
+            .par_chunks_mut(chunk_size)
+            .map(|chunk| {
+                let mut histogram = vec![0; partitions];
+                for item in chunk.iter() {
+                    histogram[partition_of(key(item), partitions)] += 1;
+                }
+                let mut parts = histogram
+                    .into_iter()
+                    .map(Vec::with_capacity)
+                    .collect::<Vec<_>>();
+                for item in chunk.iter_mut() {
+                    parts[partition_of(key(item), partitions)].push(take(item));
+                }
+                parts
+            })
+            .collect::<Vec<_>>();
+
+        let mut by_partition = (0..partitions)
+            .map(|_| Vec::with_capacity(chunks.len()))
+            .collect::<Vec<_>>();
+        for parts in chunks {
+            for (partition, part) in zip(&mut by_partition, parts) {
+                partition.push(part);
+            }
+        }
+        by_partition
+    }
+
+    /// The partition of `key` among `partitions`, found by scaling its Fibonacci hash to the
+    /// number of partitions. The fields are offsets into the input, so they are far from
+    /// uniformly distributed themselves.
+    fn partition_of(key: Field, partitions: usize) -> usize {
+        let hash = (key.offset() as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        ((u128::from(hash) * partitions as u128) >> 64) as usize
+    }
+
+    /// Reads the size of the L2 cache of the first CPU.
+    fn cache_size() -> Option<usize> {
+        l2_cache_size(Path::new("/sys/devices/system/cpu/cpu0/cache"))
+    }
+
+    /// Finds the level 2 data or unified cache among the `index*` entries of a CPU's sysfs
+    /// `cache` directory and parses its size, e.g. `2048K`.
+    pub(super) fn l2_cache_size(dir: &Path) -> Option<usize> {
+        let read = |entry: &Path, file| fs::read_to_string(entry.join(file)).ok();
+        let entry = fs::read_dir(dir)
+            .ok()?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with("index"))
+            .map(|entry| entry.path())
+            .find(|entry| {
+                let level = read(entry, "level");
+                let kind = read(entry, "type");
+                level.as_deref().map(str::trim) == Some("2")
+                    && matches!(kind.as_deref().map(str::trim), Some("Data" | "Unified"))
+            })?;
+        let size = read(&entry, "size")?;
+        let size = size.trim();
+        let (number, unit) = match size.strip_suffix(['K', 'M']) {
+            Some(number) => (
+                number,
+                if size.ends_with('K') {
+                    1 << 10
+                } else {
+                    1 << 20
+                },
+            ),
+            None => (size, 1),
+        };
+        number.parse::<usize>().ok().map(|n| n * unit)
     }
 }
 
@@ -415,7 +1676,7 @@ mod sort_merge {
     use std::{mem, sync::mpsc::channel};
 
     use rayon::{
-        iter::{IndexedParallelIterator, ParallelIterator},
+        iter::{IndexedParallelIterator, IntoParallelRefMutIterator, ParallelIterator},
         slice::ParallelSliceMut,
     };
 
@@ -438,25 +1699,22 @@ mod sort_merge {
     }
 
     impl JoinAlgo for Impl {
-        fn join(
-            &mut self,
-            settings: &Settings,
-            index: usize,
-            mut relation: Relation,
-            _field_range: (Field, Field),
-        ) {
-            if index == 0 {
+        fn join(&mut self, settings: &Settings, step: &JoinStep, mut relation: Relation) {
+            if step.index == 0 {
+                let column = step.column.expect("first step without column");
+                let limit = step.limit.unwrap_or(usize::MAX);
                 self.join_table
-                    .extend(relation.into_iter().map(|(subj, obj)| {
-                        let mut fields = vec![Field::INVALID; settings.join_count + 1];
+                    .extend(relation.into_iter().take(limit).map(|(subj, obj)| {
+                        let mut fields = vec![Field::INVALID; settings.width];
                         fields[0] = subj;
-                        fields[1] = obj;
+                        fields[column] = obj;
                         fields
                     }));
                 return;
             }
 
-            let jt_key = |fields: &Vec<Field>| fields[index];
+            let key = step.key;
+            let jt_key = |fields: &Vec<Field>| fields[key];
             if self.improved {
                 eprintln!(
                     "++ [sorting-par]  left-hand side: {} entries",
@@ -484,58 +1742,99 @@ mod sort_merge {
             // hand side for each chunk and directly modify `self.join_table` with the results.
             //
             // If rows have to be duplicated we send them via a channel to be appended later. If
-            // rows have to be removed we send the index to be removed.
+            // rows have to be removed we send the index to be removed. For an outer join rows
+            // without a match are kept, their new column stays unbound. An anti join keeps
+            // exactly those rows, a semi join keeps the matched rows without modifying them.
             eprintln!("++ merging tables");
+            let keep_unmatched = matches!(step.mode, JoinMode::Optional | JoinMode::Anti);
             let (dup_send, dup_recv) = channel::<Vec<Field>>();
+            let quota = Quota::new(step.limit);
             let chunk_size = 1024;
             self.join_table
                 .par_chunks_mut(chunk_size)
                 .enumerate()
                 .map_with(dup_send, |dup, (chunk_index, chunk)| {
-                    let fst_key = chunk.first().unwrap()[index];
+                    let fst_key = chunk.first().unwrap()[key];
                     let mut i = relation.partition_point(|x| x.0 < fst_key);
 
                     let chunk_base = chunk_index * chunk_size;
                     let chunk_len = chunk.len();
-                    let abort = |idx| {
-                        (
-                            chunk_base,
-                            Vec::from_iter(chunk_base + idx..chunk_base + chunk_len),
-                        )
+                    let abort = |idx, mut del_indices: Vec<usize>| {
+                        if !keep_unmatched {
+                            del_indices.extend(chunk_base + idx..chunk_base + chunk_len);
+                        }
+                        (chunk_index, del_indices)
                     };
 
                     if i >= relation.len() {
-                        return abort(0);
+                        return abort(0, Vec::new());
                     }
 
                     let mut del_indices = Vec::new();
                     for (r_idx, row) in chunk.iter_mut().enumerate() {
-                        let lhs_k = row[index];
+                        if quota.exhausted() {
+                            // Enough rows exist, the rest of the chunk is not needed.
+                            del_indices.extend(chunk_base + r_idx..chunk_base + chunk_len);
+                            break;
+                        }
+                        let lhs_k = row[key];
 
                         // If the right hand side is smaller, advance.
                         while relation[i].0 < lhs_k {
                             i += 1;
 
                             if i >= relation.len() {
-                                return abort(r_idx);
+                                return abort(r_idx, del_indices);
                             }
                         }
 
                         if relation[i].0 != lhs_k {
                             // Remove this row if there is no matching entry.
-                            del_indices.push(chunk_base + r_idx);
+                            if keep_unmatched {
+                                quota.claim();
+                            } else {
+                                del_indices.push(chunk_base + r_idx);
+                            }
                             continue;
                         }
 
+                        let column = match step.column {
+                            Some(column) => column,
+                            None => {
+                                // The entries of the key are sorted by their objects.
+                                let object = step.object_key.map(|c| row[c]);
+                                let matched = match object.filter(|f| f.is_valid()) {
+                                    Some(obj) => {
+                                        let entries = &relation[i..];
+                                        let end = entries.partition_point(|x| x.0 == lhs_k);
+                                        entries[..end].binary_search_by_key(&obj, |x| x.1).is_ok()
+                                    }
+                                    None => true,
+                                };
+                                if matched == (step.mode == JoinMode::Anti) {
+                                    // Remove this row since a matching entry exists for an anti
+                                    // join or none does for a semi join.
+                                    del_indices.push(chunk_base + r_idx);
+                                } else {
+                                    quota.claim();
+                                }
+                                continue;
+                            }
+                        };
+
                         // Update this row in-place.
                         debug_assert!(relation[i].1.is_valid());
-                        row[index + 1] = relation[i].1;
+                        row[column] = relation[i].1;
+                        quota.claim();
 
                         // Maybe we have to insert additional rows.
-                        for entry in relation[i + 1..].iter().take_while(|x| x.0 == lhs_k) {
+                        for entry in relation[i + 1..]
+                            .iter()
+                            .take_while(|x| x.0 == lhs_k && quota.claim())
+                        {
                             debug_assert!(entry.1.is_valid());
                             let mut new_row = row.clone();
-                            new_row[index + 1] = entry.1;
+                            new_row[column] = entry.1;
                             dup.send(new_row).unwrap();
                         }
                     }
@@ -558,10 +1857,843 @@ mod sort_merge {
             // Insert all the new rows.
             eprintln!("++ inserting additional rows");
             self.join_table.extend(dup_recv);
+
+            // Threads may have kept some rows after the limit was reached.
+            if let Some(limit) = step.limit {
+                self.join_table.truncate(limit);
+            }
         }
 
         fn results<'a>(&'a self) -> Box<dyn ExactSizeIterator<Item = &'a Vec<Field>> + 'a> {
             Box::new(self.join_table.iter())
         }
+
+        fn seed(&mut self, rows: Vec<Vec<Field>>) {
+            self.join_table = rows;
+        }
+
+        fn fill(&mut self, column: usize, values: Vec<Field>) {
+            self.join_table
+                .par_iter_mut()
+                .zip(values)
+                .for_each(|(fields, value)| fields[column] = value);
+        }
+
+        fn retain(&mut self, predicate: &(dyn Fn(&[Field]) -> bool + Sync)) {
+            self.join_table = mem::take(&mut self.join_table)
+                .into_par_iter()
+                .filter(|fields| predicate(fields))
+                .collect();
+        }
+
+        fn project(&mut self, columns: &[usize]) {
+            self.join_table
+                .par_iter_mut()
+                .for_each(|fields| *fields = columns.iter().map(|&c| fields[c]).collect());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every join algorithm, with both build sides and with and without partitions.
+    fn algorithms() -> Vec<(&'static str, Box<dyn JoinAlgo>)> {
+        vec![
+            (
+                "hash",
+                Box::new(hash::Impl::new(false, None, BuildSide::Left)),
+            ),
+            (
+                "hash building right",
+                Box::new(hash::Impl::new(false, None, BuildSide::Right)),
+            ),
+            (
+                "partitioned hash",
+                Box::new(hash::Impl::new(true, Some(3), BuildSide::Left)),
+            ),
+            (
+                "partitioned hash building right",
+                Box::new(hash::Impl::new(true, Some(3), BuildSide::Right)),
+            ),
+            ("sort-merge", Box::new(sort_merge::Impl::new(false))),
+            ("parallel sort-merge", Box::new(sort_merge::Impl::new(true))),
+        ]
+    }
+
+    /// A row of fields, `None` for unbound ones.
+    fn row(fields: &[Option<usize>]) -> Vec<Field> {
+        fields
+            .iter()
+            .map(|f| f.map_or(Field::INVALID, Field::from_offset))
+            .collect()
+    }
+
+    fn relation(entries: &[(usize, usize)]) -> Relation {
+        entries
+            .iter()
+            .map(|&(s, o)| (Field::from_offset(s), Field::from_offset(o)))
+            .collect()
+    }
+
+    fn step(mode: JoinMode, key: usize, column: Option<usize>) -> JoinStep {
+        JoinStep {
+            index: 1,
+            key,
+            column,
+            object_key: None,
+            mode,
+            limit: None,
+        }
+    }
+
+    /// Puts the two-column `rows` in place by joining them as the first step.
+    fn seed(algo: &mut dyn JoinAlgo, settings: &Settings, rows: &[[Option<usize>; 2]]) {
+        let first = rows
+            .iter()
+            .map(|r| {
+                let r = row(r);
+                (r[0], r[1])
+            })
+            .collect_vec();
+        let first_step = JoinStep {
+            index: 0,
+            key: 0,
+            column: Some(1),
+            object_key: None,
+            mode: JoinMode::Inner,
+            limit: None,
+        };
+        algo.join(settings, &first_step, first);
+    }
+
+    /// Seeds the two-column `rows`, joins them with `entries` using every algorithm and checks
+    /// that the resulting rows, in any order, are `expected`.
+    fn check(
+        rows: &[[Option<usize>; 2]],
+        step: JoinStep,
+        entries: &[(usize, usize)],
+        expected: &[&[Option<usize>]],
+    ) {
+        let mut expected = expected.iter().map(|r| row(r)).collect_vec();
+        expected.sort();
+        for (name, mut algo) in algorithms() {
+            let settings = Settings {
+                width: step.column.map_or(2, |c| c + 1),
+            };
+            seed(algo.as_mut(), &settings, rows);
+            algo.join(&settings, &step, relation(entries));
+            let mut results = algo.results().cloned().collect_vec();
+            results.sort();
+            assert_eq!(results, expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn optional_keeps_unmatched_rows() {
+        check(
+            &[[Some(1), Some(5)], [Some(2), Some(6)], [Some(3), Some(7)]],
+            step(JoinMode::Optional, 0, Some(2)),
+            &[(1, 10), (1, 11), (3, 30), (4, 40)],
+            &[
+                &[Some(1), Some(5), Some(10)],
+                &[Some(1), Some(5), Some(11)],
+                &[Some(2), Some(6), None],
+                &[Some(3), Some(7), Some(30)],
+            ],
+        );
+    }
+
+    #[test]
+    fn optional_without_any_match() {
+        check(
+            &[[Some(1), Some(5)], [Some(2), Some(6)]],
+            step(JoinMode::Optional, 1, Some(2)),
+            &[(1, 50)],
+            &[&[Some(1), Some(5), None], &[Some(2), Some(6), None]],
+        );
+    }
+
+    #[test]
+    fn optional_with_empty_relation() {
+        check(
+            &[[Some(1), Some(5)]],
+            step(JoinMode::Optional, 0, Some(2)),
+            &[],
+            &[&[Some(1), Some(5), None]],
+        );
+    }
+
+    #[test]
+    fn inner_join_with_empty_relation() {
+        check(
+            &[[Some(1), Some(5)], [Some(2), Some(6)]],
+            step(JoinMode::Inner, 0, Some(2)),
+            &[],
+            &[],
+        );
+    }
+
+    #[test]
+    fn inner_join_drops_unmatched_rows() {
+        check(
+            &[[Some(1), Some(5)], [Some(2), Some(6)]],
+            step(JoinMode::Inner, 1, Some(2)),
+            &[(6, 20), (6, 21)],
+            &[&[Some(2), Some(6), Some(20)], &[Some(2), Some(6), Some(21)]],
+        );
+    }
+
+    /// Rows with keys clustered at the start of the input and entries matching some of them
+    /// several times, together with the rows joining them with `mode` results in.
+    #[allow(clippy::type_complexity)]
+    fn many_rows(
+        mode: JoinMode,
+    ) -> (
+        Vec<[Option<usize>; 2]>,
+        Vec<(usize, usize)>,
+        Vec<Vec<Option<usize>>>,
+    ) {
+        let rows = (0..5000).map(|i| [Some(i * 7 % 1500), None]).collect_vec();
+        let entries = (0..3000)
+            .step_by(2)
+            .flat_map(|s| (0..s % 3).map(move |o| (s, 100_000 + s * 3 + o)))
+            .collect_vec();
+        let expected = rows
+            .iter()
+            .flat_map(|row| {
+                let objects = entries
+                    .iter()
+                    .filter(|&&(s, _)| Some(s) == row[0])
+                    .map(|&(_, o)| vec![row[0], Some(o)])
+                    .collect_vec();
+                if objects.is_empty() && mode == JoinMode::Optional {
+                    vec![row.to_vec()]
+                } else {
+                    objects
+                }
+            })
+            .collect_vec();
+        (rows, entries, expected)
+    }
+
+    #[test]
+    fn joins_many_rows() {
+        for mode in [JoinMode::Inner, JoinMode::Optional] {
+            let (rows, entries, expected) = many_rows(mode);
+            check(
+                &rows,
+                step(mode, 0, Some(1)),
+                &entries,
+                &expected.iter().map(Vec::as_slice).collect_vec(),
+            );
+        }
+    }
+
+    #[test]
+    fn partition_count_does_not_change_results() {
+        let (rows, entries, expected) = many_rows(JoinMode::Optional);
+        let mut expected = expected.iter().map(|r| row(r)).collect_vec();
+        expected.sort();
+        let settings = Settings { width: 2 };
+        for partitions in [None, Some(1), Some(2), Some(7), Some(64), Some(10_000)] {
+            let mut algo = hash::Impl::new(true, partitions, BuildSide::Left);
+            algo.seed(rows.iter().map(|r| row(r)).collect());
+            algo.join(
+                &settings,
+                &step(JoinMode::Optional, 0, Some(1)),
+                relation(&entries),
+            );
+            let mut results = algo.results().cloned().collect_vec();
+            results.sort();
+            assert_eq!(results, expected, "{:?}", partitions);
+        }
+    }
+
+    #[test]
+    fn cache_size_is_read_from_the_l2_data_cache() {
+        let dir = std::env::temp_dir().join(format!("sparql-joins-cache-{}", std::process::id()));
+        let caches = [
+            ("index0", "1", "Data", "48K"),
+            ("index1", "2", "Instruction", "64K"),
+            ("index2", "3", "Unified", "32M"),
+        ];
+        for (index, level, kind, size) in caches {
+            let entry = dir.join(index);
+            fs::create_dir_all(&entry).unwrap();
+            fs::write(entry.join("level"), format!("{}\n", level)).unwrap();
+            fs::write(entry.join("type"), format!("{}\n", kind)).unwrap();
+            fs::write(entry.join("size"), format!("{}\n", size)).unwrap();
+        }
+        assert_eq!(hash::l2_cache_size(&dir), None);
+        fs::create_dir_all(dir.join("index3")).unwrap();
+        fs::write(dir.join("index3/level"), "2\n").unwrap();
+        fs::write(dir.join("index3/type"), "Unified\n").unwrap();
+        fs::write(dir.join("index3/size"), "2048K\n").unwrap();
+        let size = hash::l2_cache_size(&dir);
+        fs::remove_dir_all(&dir).ok();
+        assert_eq!(size, Some(2048 * 1024));
+    }
+
+    #[test]
+    fn build_sides_give_the_same_rows() {
+        for mode in [JoinMode::Inner, JoinMode::Optional] {
+            let (rows, entries, expected) = many_rows(mode);
+            // The join table is the larger side for all keys and the smaller one for a few.
+            for keys in [1500, 20] {
+                let rows = rows.iter().filter(|r| r[0] < Some(keys)).collect_vec();
+                let mut expected = expected
+                    .iter()
+                    .filter(|r| r[0] < Some(keys))
+                    .map(|r| row(r))
+                    .collect_vec();
+                expected.sort();
+                let settings = Settings { width: 2 };
+                for improved in [false, true] {
+                    for side in [BuildSide::Auto, BuildSide::Left, BuildSide::Right] {
+                        let mut algo = hash::Impl::new(improved, None, side);
+                        algo.seed(rows.iter().map(|r| row(&r[..])).collect());
+                        algo.join(&settings, &step(mode, 0, Some(1)), relation(&entries));
+                        let mut results = algo.results().cloned().collect_vec();
+                        results.sort();
+                        assert_eq!(results, expected, "{:?} {} {:?}", mode, improved, side);
+                    }
+                }
+            }
+        }
+    }
+
+    fn filter_step(mode: JoinMode, key: usize, object_key: Option<usize>) -> JoinStep {
+        JoinStep {
+            object_key,
+            ..step(mode, key, None)
+        }
+    }
+
+    #[test]
+    fn anti_join_keeps_unmatched_rows() {
+        check(
+            &[[Some(1), Some(5)], [Some(2), Some(6)], [Some(3), Some(7)]],
+            filter_step(JoinMode::Anti, 0, None),
+            &[(1, 10), (1, 11), (3, 30)],
+            &[&[Some(2), Some(6)]],
+        );
+    }
+
+    #[test]
+    fn anti_join_with_empty_relation() {
+        check(
+            &[[Some(1), Some(5)], [Some(2), Some(6)]],
+            filter_step(JoinMode::Anti, 0, None),
+            &[],
+            &[&[Some(1), Some(5)], &[Some(2), Some(6)]],
+        );
+    }
+
+    #[test]
+    fn anti_join_matches_both_ends() {
+        // Rows leaving the object unbound match any entry of their key.
+        check(
+            &[
+                [Some(1), Some(10)],
+                [Some(1), Some(12)],
+                [Some(2), Some(20)],
+                [Some(3), None],
+                [Some(4), None],
+            ],
+            filter_step(JoinMode::Anti, 0, Some(1)),
+            &[(1, 11), (1, 10), (2, 21), (3, 30)],
+            &[&[Some(1), Some(12)], &[Some(2), Some(20)], &[Some(4), None]],
+        );
+    }
+
+    #[test]
+    fn semi_join_keeps_matched_rows_once() {
+        check(
+            &[[Some(1), Some(5)], [Some(2), Some(6)], [Some(3), Some(7)]],
+            filter_step(JoinMode::Semi, 0, None),
+            &[(1, 10), (1, 11), (1, 12), (3, 30)],
+            &[&[Some(1), Some(5)], &[Some(3), Some(7)]],
+        );
+    }
+
+    #[test]
+    fn semi_join_with_empty_relation() {
+        check(
+            &[[Some(1), Some(5)], [Some(2), Some(6)]],
+            filter_step(JoinMode::Semi, 0, None),
+            &[],
+            &[],
+        );
+    }
+
+    #[test]
+    fn semi_join_matches_both_ends() {
+        check(
+            &[
+                [Some(1), Some(10)],
+                [Some(1), Some(12)],
+                [Some(2), Some(20)],
+                [Some(3), None],
+                [Some(4), None],
+            ],
+            filter_step(JoinMode::Semi, 0, Some(1)),
+            &[(1, 11), (1, 10), (2, 21), (3, 30), (3, 31)],
+            &[&[Some(1), Some(10)], &[Some(3), None]],
+        );
+    }
+
+    /// Stores the terms of `rows` as computed terms, each occurrence in a field of its own.
+    fn term_rows(terms: &mut Terms, rows: &[&[Option<&str>]]) -> Vec<Vec<Field>> {
+        rows.iter()
+            .map(|row| {
+                row.iter()
+                    .map(|t| t.map_or(Field::INVALID, |t| terms.add(t.to_owned())))
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn decode(terms: &Terms, rows: Vec<&Vec<Field>>) -> Vec<Vec<Option<String>>> {
+        rows.iter()
+            .map(|row| {
+                row.iter()
+                    .map(|&f| f.is_valid().then(|| terms.extract_str(f).to_string()))
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn strings(rows: &[&[Option<&str>]]) -> Vec<Vec<Option<String>>> {
+        rows.iter()
+            .map(|row| row.iter().map(|t| t.map(str::to_owned)).collect())
+            .collect()
+    }
+
+    #[test]
+    fn duplicates_are_compared_by_value() {
+        let input = Input::from_text("");
+        let mut terms = Terms::new(&input);
+        let rows = term_rows(
+            &mut terms,
+            &[
+                &[Some("<b>"), Some("\"1\"")],
+                &[Some("<a>"), None],
+                &[Some("<b>"), Some("\"1\"")],
+                &[Some("<a>"), Some("\"1\"")],
+                &[Some("<a>"), None],
+            ],
+        );
+        let unique = remove_duplicates(&terms, rows.iter().collect());
+        assert_eq!(
+            decode(&terms, unique),
+            strings(&[
+                &[Some("<b>"), Some("\"1\"")],
+                &[Some("<a>"), None],
+                &[Some("<a>"), Some("\"1\"")],
+            ])
+        );
+    }
+
+    #[test]
+    fn sorts_by_decoded_values() {
+        let input = Input::from_text("");
+        let mut terms = Terms::new(&input);
+        let rows = term_rows(
+            &mut terms,
+            &[
+                &[Some("\"10\""), Some("<a>")],
+                &[Some("\"b\""), Some("<b>")],
+                &[Some("\"9\""), Some("<c>")],
+                &[None, Some("<d>")],
+                &[Some("<x>"), Some("<e>")],
+                &[Some("_:b"), Some("<f>")],
+                &[Some("\"9.0\""), Some("<g>")],
+            ],
+        );
+        // Unbound values come first, then blank nodes, IRIs, numbers and other literals.
+        // Equal numbers keep their order.
+        let sorted = sort_rows(&terms, rows.iter().collect(), &[(0, false)]);
+        let order = decode(&terms, sorted)
+            .into_iter()
+            .map(|row| row[1].clone().unwrap())
+            .collect_vec();
+        assert_eq!(order, ["<d>", "<f>", "<e>", "<c>", "<g>", "<a>", "<b>"]);
+
+        let sorted = sort_rows(&terms, rows.iter().collect(), &[(0, true)]);
+        let order = decode(&terms, sorted)
+            .into_iter()
+            .map(|row| row[1].clone().unwrap())
+            .collect_vec();
+        assert_eq!(order, ["<b>", "<a>", "<c>", "<g>", "<e>", "<f>", "<d>"]);
+    }
+
+    #[test]
+    fn sorts_by_several_keys() {
+        let input = Input::from_text("");
+        let mut terms = Terms::new(&input);
+        let rows = term_rows(
+            &mut terms,
+            &[
+                &[Some("<a>"), Some("\"1\"")],
+                &[Some("<b>"), Some("\"2\"")],
+                &[Some("<a>"), Some("\"3\"")],
+                &[Some("<b>"), Some("\"1\"")],
+            ],
+        );
+        let sorted = sort_rows(&terms, rows.iter().collect(), &[(0, false), (1, true)]);
+        assert_eq!(
+            decode(&terms, sorted),
+            strings(&[
+                &[Some("<a>"), Some("\"3\"")],
+                &[Some("<a>"), Some("\"1\"")],
+                &[Some("<b>"), Some("\"2\"")],
+                &[Some("<b>"), Some("\"1\"")],
+            ])
+        );
+    }
+
+    #[test]
+    fn resolves_order_keys() {
+        let (x, y) = ("x".to_owned(), "y".to_owned());
+        let variables = [Some(&x), None, Some(&y)];
+        let keys = OrderKey::parse_list("?y,desc(0),ASC(?x)").unwrap();
+        assert_eq!(
+            order_keys(&keys, &variables).unwrap(),
+            [(2, false), (0, true), (0, false)]
+        );
+        let keys = OrderKey::parse_list("?z").unwrap();
+        assert_eq!(
+            order_keys(&keys, &variables).unwrap_err().to_string(),
+            "Cannot order by ?z, it is not part of the result."
+        );
+        let keys = OrderKey::parse_list("3").unwrap();
+        assert!(order_keys(&keys, &variables).is_err());
+    }
+
+    /// Like `check` for a step stopping after `limit` rows: the rows have to be some of the
+    /// `expected` ones.
+    fn check_limit(
+        rows: &[[Option<usize>; 2]],
+        step: JoinStep,
+        limit: usize,
+        entries: &[(usize, usize)],
+        expected: &[&[Option<usize>]],
+    ) {
+        let expected = expected.iter().map(|r| row(r)).collect_vec();
+        let step = JoinStep {
+            limit: Some(limit),
+            ..step
+        };
+        for (name, mut algo) in algorithms() {
+            let settings = Settings {
+                width: step.column.map_or(2, |c| c + 1),
+            };
+            seed(algo.as_mut(), &settings, rows);
+            algo.join(&settings, &step, relation(entries));
+            let results = algo.results().cloned().collect_vec();
+            assert_eq!(results.len(), limit.min(expected.len()), "{}", name);
+            assert!(results.iter().all(|r| expected.contains(r)), "{}", name);
+            assert!(results.iter().all_unique(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn limit_stops_joining() {
+        let rows = &[[Some(1), Some(5)], [Some(2), Some(6)], [Some(3), Some(7)]];
+        let entries = &[(1, 10), (1, 11), (2, 20), (3, 30), (3, 31)];
+        let joined: &[&[Option<usize>]] = &[
+            &[Some(1), Some(5), Some(10)],
+            &[Some(1), Some(5), Some(11)],
+            &[Some(2), Some(6), Some(20)],
+            &[Some(3), Some(7), Some(30)],
+            &[Some(3), Some(7), Some(31)],
+        ];
+        for limit in [0, 1, 2, 4, 5, 10] {
+            check_limit(
+                rows,
+                step(JoinMode::Inner, 0, Some(2)),
+                limit,
+                entries,
+                joined,
+            );
+        }
+    }
+
+    #[test]
+    fn limit_counts_kept_rows() {
+        let rows = &[
+            [Some(1), Some(5)],
+            [Some(2), Some(6)],
+            [Some(3), Some(7)],
+            [Some(4), Some(8)],
+        ];
+        let entries = &[(1, 10), (3, 30)];
+        let optional: &[&[Option<usize>]] = &[
+            &[Some(1), Some(5), Some(10)],
+            &[Some(2), Some(6), None],
+            &[Some(3), Some(7), Some(30)],
+            &[Some(4), Some(8), None],
+        ];
+        let unmatched: &[&[Option<usize>]] = &[&[Some(2), Some(6)], &[Some(4), Some(8)]];
+        let matched: &[&[Option<usize>]] = &[&[Some(1), Some(5)], &[Some(3), Some(7)]];
+        for limit in [0, 1, 3] {
+            check_limit(
+                rows,
+                step(JoinMode::Optional, 0, Some(2)),
+                limit,
+                entries,
+                optional,
+            );
+            check_limit(
+                rows,
+                filter_step(JoinMode::Anti, 0, None),
+                limit,
+                entries,
+                unmatched,
+            );
+            check_limit(
+                rows,
+                filter_step(JoinMode::Semi, 0, None),
+                limit,
+                entries,
+                matched,
+            );
+        }
+    }
+
+    #[test]
+    fn quota() {
+        let quota = Quota::new(Some(2));
+        assert!(!quota.exhausted());
+        assert!(quota.claim() && quota.claim());
+        assert!(quota.exhausted());
+        assert!(!quota.claim());
+        assert!(!Quota::new(None).exhausted());
+    }
+
+    fn delimited(format: OutputFormat, rows: &[&[Option<&str>]]) -> String {
+        let input = Input::from_text("");
+        let mut terms = Terms::new(&input);
+        let rows = term_rows(&mut terms, rows);
+        let (x, y) = ("x".to_owned(), "y".to_owned());
+        let mut out = Vec::new();
+        write_delimited(
+            &mut out,
+            format,
+            &[Some(&x), None, Some(&y)],
+            rows.iter(),
+            &terms,
+        )
+        .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn writes_tsv() {
+        let rows: &[&[Option<&str>]] = &[
+            &[Some("<a>"), Some("<i>"), Some("\"b, \\\"c\\\"\"")],
+            &[Some("_:n"), None, None],
+        ];
+        assert_eq!(
+            delimited(OutputFormat::Tsv, rows),
+            "?x\t\t?y\n<a>\t<i>\t\"b, \\\"c\\\"\"\n_:n\t\t\n"
+        );
+    }
+
+    #[test]
+    fn writes_csv() {
+        let rows: &[&[Option<&str>]] = &[
+            &[Some("<a>"), Some("<i>"), Some("\"b, \\\"c\\\"\"")],
+            &[Some("\"plain\""), None, None],
+        ];
+        assert_eq!(
+            delimited(OutputFormat::Csv, rows),
+            "x,,y\na,i,\"b, \"\"c\"\"\"\nplain,,\n"
+        );
+    }
+
+    #[test]
+    fn pads_table_rows() {
+        let mut out = Vec::new();
+        write_row(&mut out, &["?x", "?yy", "z"], &[3, 4, 1]).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), " ?x  │ ?yy  │ z\n");
+    }
+
+    const GRAPH: &str = "<alice> <knows> <bob> .
+<bob> <knows> <carol> .
+<carol> <knows> <alice> .
+<dave> <knows> <bob> .
+<alice> <age> \"30\" .
+<bob> <age> \"25\" .
+<dave> <age> \"41\" .
+";
+
+    /// Runs `query` on `GRAPH` with the options `flags` using every join algorithm. Returns
+    /// whether there are results and the lines written, which have to be the same for all
+    /// algorithms up to the order of the rows unless they are sorted.
+    fn run(query: &str, flags: &[&str]) -> Result<(bool, Vec<String>)> {
+        use clap::Parser;
+
+        static RUNS: AtomicUsize = AtomicUsize::new(0);
+        let algorithms: [&[&str]; 4] = [
+            &["--hash"],
+            &["--hash", "--improved"],
+            &["--sort"],
+            &["--sort", "--improved"],
+        ];
+        let mut outputs = Vec::new();
+        for algorithm in algorithms {
+            let run = RUNS.fetch_add(1, atomic::Ordering::Relaxed);
+            let path = std::env::temp_dir().join(format!(
+                "sparql-joins-test-{}-{}",
+                std::process::id(),
+                run
+            ));
+            let output = path.to_str().unwrap();
+            let args = ["sparql-joins", "-", "--query", query, "--output", output];
+            let args = Args::parse_from(args.iter().chain(algorithm).chain(flags));
+            let input = Input::from_text(GRAPH);
+            let found = join(&args, &input);
+            let text = fs::read_to_string(&path).unwrap_or_default();
+            fs::remove_file(&path).ok();
+            let mut lines = text.lines().map(str::to_owned).collect_vec();
+            if !query.contains("ORDER BY") {
+                let header = usize::from(flags.contains(&"tsv")).min(lines.len());
+                lines[header..].sort();
+            }
+            outputs.push((found?, lines));
+        }
+        for (i, output) in outputs.iter().enumerate().skip(1) {
+            assert_eq!(output, &outputs[0], "{:?}", algorithms[i]);
+        }
+        Ok(outputs.swap_remove(0))
+    }
+
+    #[test]
+    fn ask_queries() {
+        let ask = "ASK { <alice> <knows> ?x . ?x <knows> <carol> }";
+        assert_eq!(run(ask, &[]).unwrap(), (true, vec!["true".to_owned()]));
+        let ask = "ASK WHERE { <carol> <knows> ?x . ?x <knows> <carol> }";
+        assert_eq!(run(ask, &[]).unwrap(), (false, vec!["false".to_owned()]));
+        let ask = "ASK { ?x <knows> ?y FILTER(?x = <erin>) }";
+        assert!(!run(ask, &[]).unwrap().0);
+    }
+
+    #[test]
+    fn ask_option_checks_any_query() {
+        let select = "SELECT * WHERE { ?x <age> ?a FILTER(?a > 40) }";
+        assert!(run(select, &["--ask"]).unwrap().0);
+        let select = "SELECT * WHERE { ?x <age> ?a FILTER(?a > 50) }";
+        assert!(!run(select, &["--ask"]).unwrap().0);
+    }
+
+    #[test]
+    fn ask_cannot_be_limited() {
+        let error = run("SELECT ?x WHERE { ?x <knows> ?y } LIMIT 1", &["--ask"]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "ASK cannot be combined with selecting, sorting, deduplicating or limiting."
+        );
+    }
+
+    #[test]
+    fn partitions_option() {
+        let query = "SELECT * WHERE { ?x <knows> ?y . ?y <age> ?a }";
+        let (_, lines) = run(query, &[]).unwrap();
+        for partitions in ["1", "3", "100"] {
+            assert_eq!(run(query, &["--partitions", partitions]).unwrap().1, lines);
+        }
+        for partitions in ["0", "65537"] {
+            let error = run(query, &["--partitions", partitions]).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                format!(
+                    "--partitions has to be between 1 and 65536, not {}.",
+                    partitions
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn build_option() {
+        let query = "SELECT * WHERE { ?x <knows> ?y OPTIONAL { ?y <age> ?a } }";
+        let (_, lines) = run(query, &[]).unwrap();
+        for side in ["auto", "left", "right"] {
+            assert_eq!(run(query, &["--build", side]).unwrap().1, lines);
+        }
+    }
+
+    #[test]
+    fn bind_queries() {
+        let query = "SELECT ?x ?next WHERE { ?x <age> ?a BIND(?a + 1 AS ?next) \
+                     FILTER(?next > 26) }";
+        let (_, lines) = run(query, &["--format", "tsv"]).unwrap();
+        let integer = |n| format!("\"{}\"^^<http://www.w3.org/2001/XMLSchema#integer>", n);
+        assert_eq!(
+            lines,
+            [
+                "?x\t?next".to_owned(),
+                format!("<alice>\t{}", integer(31)),
+                format!("<dave>\t{}", integer(42))
+            ]
+        );
+        let query = "SELECT ?z WHERE { ?x <knows> ?y BIND(?y AS ?z) } ORDER BY DESC(?z) LIMIT 2";
+        let (_, lines) = run(query, &["--format", "tsv"]).unwrap();
+        assert_eq!(lines, ["?z", "<carol>", "<bob>"]);
+    }
+
+    #[test]
+    fn bound_booleans_filter_rows() {
+        let query = "SELECT ?x WHERE { ?x <age> ?a BIND(?a > 28 AS ?o) FILTER(?o) }";
+        let (_, lines) = run(query, &["--format", "tsv"]).unwrap();
+        assert_eq!(lines, ["?x", "<alice>", "<dave>"]);
+        let query = "SELECT ?x WHERE { ?x <age> ?a BIND(?a - 25 AS ?d) FILTER(!?d) }";
+        let (_, lines) = run(query, &["--format", "tsv"]).unwrap();
+        assert_eq!(lines, ["?x", "<bob>"]);
+    }
+
+    #[test]
+    fn aggregates_and_bind_write_numbers_alike() {
+        let query = "SELECT (COUNT(?a) AS ?n) (SUM(?a) AS ?s) WHERE { ?x <age> ?a }";
+        let (_, counted) = run(query, &["--format", "tsv"]).unwrap();
+        let query =
+            "SELECT ?n ?s WHERE { <alice> <age> ?a BIND(?a - 27 AS ?n) BIND(?a * 3.2 AS ?s) }";
+        let (_, bound) = run(query, &["--format", "tsv"]).unwrap();
+        assert_eq!(counted, bound);
+    }
+
+    #[test]
+    fn subqueries() {
+        // Everyone known by someone with the number of people they know themselves.
+        let query = "SELECT ?y ?n WHERE { ?x <knows> ?y { SELECT ?y (COUNT(?z) AS ?n) \
+                     WHERE { ?y <knows> ?z } GROUP BY ?y } } ORDER BY ?y";
+        let (_, lines) = run(query, &["--format", "tsv", "--distinct"]).unwrap();
+        assert_eq!(
+            lines,
+            [
+                "?y\t?n",
+                "<alice>\t\"1\"^^<http://www.w3.org/2001/XMLSchema#integer>",
+                "<bob>\t\"1\"^^<http://www.w3.org/2001/XMLSchema#integer>",
+                "<carol>\t\"1\"^^<http://www.w3.org/2001/XMLSchema#integer>"
+            ]
+        );
+
+        // The oldest person and who knows them, starting from the subquery.
+        let query = "SELECT ?x ?y WHERE { { SELECT ?x ?a WHERE { ?x <age> ?a } ORDER BY DESC(?a) \
+                     LIMIT 1 } ?x <knows> ?y }";
+        let (_, lines) = run(query, &["--format", "tsv"]).unwrap();
+        assert_eq!(lines, ["?x\t?y", "<dave>\t<bob>"]);
+    }
+
+    #[test]
+    fn subqueries_without_results() {
+        let query = "SELECT * WHERE { ?x <knows> ?y { SELECT ?y WHERE { ?y <age> ?a \
+                     FILTER(?a > 100) } } }";
+        let (found, lines) = run(query, &["--format", "tsv"]).unwrap();
+        assert!(found);
+        assert_eq!(lines, ["?x\t?y"]);
     }
 }
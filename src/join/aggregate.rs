@@ -0,0 +1,467 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::mem;
+
+use itertools::Itertools;
+use rayon::iter::{
+    IndexedParallelIterator, IntoParallelIterator, IntoParallelRefIterator, ParallelIterator,
+};
+
+use crate::input::{Field, Str, Terms};
+use crate::query::{Aggregate, Aggregation, Grouping};
+
+use super::eval::{self, Value};
+
+/// The rows of a group seen so far and the state of its aggregates.
+struct Group<'t> {
+    /// Index of the first row of the group.
+    first: usize,
+    /// The key columns of the first row.
+    keys: Vec<Field>,
+    states: Vec<State<'t>>,
+}
+
+enum State<'t> {
+    Count(usize),
+    /// `None` once a value is not a number.
+    Sum(Option<f64>),
+    Avg(Option<f64>, usize),
+    Min(Option<Field>),
+    Max(Option<Field>),
+    Concat(Option<String>),
+    /// The distinct values with the index of the row they first appear in. They are aggregated
+    /// once the group is complete.
+    Distinct(HashMap<Str<'t>, (usize, Field)>),
+}
+
+/// The value of an aggregate for a group.
+enum Output {
+    Field(Field),
+    Term(String),
+}
+
+/// Groups `rows` by the decoded values of the key columns and computes the aggregates of each
+/// group. `variables` names the columns of the rows. Returns a row per group holding the keys
+/// followed by the aggregates, the groups are ordered by their first row. Computed values are
+/// added to `terms`.
+pub fn aggregate(
+    terms: &mut Terms,
+    rows: &[&Vec<Field>],
+    variables: &[Option<&String>],
+    grouping: &Grouping,
+) -> Vec<Vec<Field>> {
+    let column = |var: &String| variables.iter().position(|&v| v == Some(var));
+    let keys = grouping.keys.iter().map(column).collect_vec();
+    let aggregates = grouping
+        .aggregates
+        .iter()
+        .map(|(_, aggregate)| (aggregate, aggregate.var.as_ref().map(column)))
+        .collect_vec();
+
+    evaluate(terms, rows, &keys, &aggregates)
+        .into_iter()
+        .map(|(mut row, outputs)| {
+            row.extend(outputs.into_iter().map(|output| match output {
+                Output::Field(field) => field,
+                Output::Term(term) => terms.add(term),
+            }));
+            row
+        })
+        .collect()
+}
+
+type Groups<'t> = HashMap<Vec<Option<Str<'t>>>, Group<'t>>;
+
+/// Groups the rows and computes the value of every aggregate for each group.
+fn evaluate<'t>(
+    terms: &'t Terms,
+    rows: &[&Vec<Field>],
+    keys: &[Option<usize>],
+    aggregates: &[(&Aggregate, Option<Option<usize>>)],
+) -> Vec<(Vec<Field>, Vec<Output>)> {
+    let new_group = |first, row: &Vec<Field>| Group {
+        first,
+        keys: keys
+            .iter()
+            .map(|&c| c.map_or(Field::INVALID, |c| row[c]))
+            .collect(),
+        states: aggregates.iter().map(|(a, _)| State::new(a)).collect(),
+    };
+    let fold = |mut groups: Groups<'t>, (i, row): (usize, &&Vec<Field>)| {
+        let key = keys
+            .iter()
+            .map(|&c| c.map(|c| row[c]).filter(|f| f.is_valid()))
+            .map(|f| f.map(|f| terms.extract_str(f)))
+            .collect_vec();
+        let group = groups.entry(key).or_insert_with(|| new_group(i, row));
+        for (state, &(aggregate, column)) in group.states.iter_mut().zip(aggregates) {
+            // `COUNT(*)` counts every row, all other aggregates skip unbound values.
+            let field = match column {
+                None => Field::INVALID,
+                Some(c) => match c.map(|c| row[c]).filter(|f| f.is_valid()) {
+                    Some(field) => field,
+                    None => continue,
+                },
+            };
+            state.add(terms, aggregate, i, field);
+        }
+        groups
+    };
+    let mut groups = rows
+        .par_iter()
+        .enumerate()
+        .fold(HashMap::new, fold)
+        .reduce(HashMap::new, |a, b| {
+            let (mut large, small) = if a.len() < b.len() { (b, a) } else { (a, b) };
+            for (key, group) in small {
+                match large.get_mut(&key) {
+                    Some(known) => known.merge(terms, aggregates, group),
+                    None => {
+                        large.insert(key, group);
+                    }
+                }
+            }
+            large
+        })
+        .into_values()
+        .collect_vec();
+
+    // Without keys there is exactly one group, even if there are no rows.
+    if groups.is_empty() && keys.is_empty() {
+        groups.push(new_group(0, &Vec::new()));
+    }
+    groups.sort_unstable_by_key(|group| group.first);
+
+    groups
+        .into_par_iter()
+        .map(|group| {
+            let outputs = group
+                .states
+                .into_iter()
+                .zip(aggregates)
+                .map(|(state, &(aggregate, _))| state.finish(terms, aggregate))
+                .collect_vec();
+            (group.keys, outputs)
+        })
+        .collect::<Vec<_>>()
+}
+
+impl<'t> Group<'t> {
+    /// Adds the rows of `other`, which has to be a different part of the same group.
+    fn merge(
+        &mut self,
+        terms: &'t Terms,
+        aggregates: &[(&Aggregate, Option<Option<usize>>)],
+        mut other: Group<'t>,
+    ) {
+        // Merge in the order of the rows so that e.g. `GROUP_CONCAT` does not depend on how the
+        // rows were split up.
+        if other.first < self.first {
+            mem::swap(self, &mut other);
+        }
+        for ((state, later), &(aggregate, _)) in
+            self.states.iter_mut().zip(other.states).zip(aggregates)
+        {
+            state.merge(terms, aggregate, later);
+        }
+    }
+}
+
+impl<'t> State<'t> {
+    fn new(aggregate: &Aggregate) -> Self {
+        if aggregate.distinct {
+            return State::Distinct(HashMap::new());
+        }
+        match aggregate.function {
+            Aggregation::Count => State::Count(0),
+            Aggregation::Sum => State::Sum(Some(0.0)),
+            Aggregation::Avg => State::Avg(Some(0.0), 0),
+            Aggregation::Min => State::Min(None),
+            Aggregation::Max => State::Max(None),
+            Aggregation::GroupConcat => State::Concat(None),
+        }
+    }
+
+    /// Adds the value of the aggregated column of row `index`, an invalid field for `COUNT(*)`.
+    fn add(&mut self, terms: &'t Terms, aggregate: &Aggregate, index: usize, field: Field) {
+        let value = || Value::from_term(terms.extract_str(field).decode());
+        match self {
+            State::Count(n) => *n += 1,
+            State::Sum(sum) => *sum = sum.and_then(|s| Some(s + value().as_num()?)),
+            State::Avg(sum, n) => {
+                *sum = sum.and_then(|s| Some(s + value().as_num()?));
+                *n += 1;
+            }
+            State::Min(min) => {
+                if min.is_none_or(|m| order(terms, field, m).is_lt()) {
+                    *min = Some(field);
+                }
+            }
+            State::Max(max) => {
+                if max.is_none_or(|m| order(terms, field, m).is_gt()) {
+                    *max = Some(field);
+                }
+            }
+            State::Concat(text) => {
+                let value = value();
+                match text {
+                    Some(text) => {
+                        text.push_str(&eval::unescape(&aggregate.separator));
+                        text.push_str(&value.as_str());
+                    }
+                    None => *text = Some(value.as_str().into_owned()),
+                }
+            }
+            State::Distinct(values) => {
+                values
+                    .entry(terms.extract_str(field))
+                    .and_modify(|(first, _)| *first = (*first).min(index))
+                    .or_insert((index, field));
+            }
+        }
+    }
+
+    /// Adds the state of the rows following the rows of `self`.
+    fn merge(&mut self, terms: &'t Terms, aggregate: &Aggregate, later: State<'t>) {
+        match (self, later) {
+            (State::Count(n), State::Count(m)) => *n += m,
+            (State::Sum(sum), State::Sum(other)) => *sum = sum.and_then(|s| Some(s + other?)),
+            (State::Avg(sum, n), State::Avg(other, m)) => {
+                *sum = sum.and_then(|s| Some(s + other?));
+                *n += m;
+            }
+            (State::Min(min), State::Min(Some(other))) => {
+                if min.is_none_or(|m| order(terms, other, m).is_lt()) {
+                    *min = Some(other);
+                }
+            }
+            (State::Max(max), State::Max(Some(other))) => {
+                if max.is_none_or(|m| order(terms, other, m).is_gt()) {
+                    *max = Some(other);
+                }
+            }
+            (State::Concat(text), State::Concat(other)) => {
+                *text = match (text.take(), other) {
+                    (Some(mut text), Some(other)) => {
+                        text.push_str(&eval::unescape(&aggregate.separator));
+                        text.push_str(&other);
+                        Some(text)
+                    }
+                    (text, other) => text.or(other),
+                }
+            }
+            (State::Distinct(values), State::Distinct(other)) => {
+                for (value, (index, field)) in other {
+                    values
+                        .entry(value)
+                        .and_modify(|(first, _)| *first = (*first).min(index))
+                        .or_insert((index, field));
+                }
+            }
+            (State::Min(_), State::Min(None)) | (State::Max(_), State::Max(None)) => {}
+            _ => unreachable!("merging different aggregates"),
+        }
+    }
+
+    fn finish(self, terms: &'t Terms, aggregate: &Aggregate) -> Output {
+        let number = |n: f64| Output::Term(Value::Num(n).into_term());
+        match self {
+            State::Count(n) => number(n as f64),
+            State::Sum(sum) => sum.map_or(Output::Field(Field::INVALID), number),
+            State::Avg(_, 0) => number(0.0),
+            State::Avg(sum, n) => {
+                sum.map_or(Output::Field(Field::INVALID), |s| number(s / n as f64))
+            }
+            State::Min(field) | State::Max(field) => Output::Field(field.unwrap_or(Field::INVALID)),
+            State::Concat(text) => {
+                Output::Term(Value::Literal(Cow::Owned(text.unwrap_or_default())).into_term())
+            }
+            State::Distinct(values) => {
+                // Aggregate the distinct values in the order they appeared in.
+                let mut state = State::new(&Aggregate {
+                    distinct: false,
+                    ..aggregate.clone()
+                });
+                for (index, field) in values.into_values().sorted_unstable() {
+                    state.add(terms, aggregate, index, field);
+                }
+                state.finish(terms, aggregate)
+            }
+        }
+    }
+}
+
+/// Orders two fields like `ORDER BY`.
+fn order(terms: &Terms, a: Field, b: Field) -> std::cmp::Ordering {
+    let value = |f| Value::from_term(terms.extract_str(f).decode());
+    Value::order(Some(&value(a)), Some(&value(b)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::input::Input;
+    use crate::query::Query;
+
+    use super::*;
+
+    /// Groups rows binding `?g` and `?v` as given by the GROUP BY clause and aggregates of
+    /// `query`. Returns the decoded output rows.
+    fn group(query: &str, rows: &[[Option<&str>; 2]]) -> Vec<Vec<Option<String>>> {
+        let grouping = Query::parse(query).unwrap().grouping.unwrap();
+        let input = Input::from_text("");
+        let mut terms = Terms::new(&input);
+        let rows = rows
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|t| t.map_or(Field::INVALID, |t| terms.add(t.to_owned())))
+                    .collect_vec()
+            })
+            .collect_vec();
+        let (g, v) = ("g".to_owned(), "v".to_owned());
+        let rows = rows.iter().collect_vec();
+        let grouped = aggregate(&mut terms, &rows, &[Some(&g), Some(&v)], &grouping);
+        grouped
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|&f| f.is_valid().then(|| terms.extract_str(f).to_string()))
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn strings<const N: usize>(rows: &[[Option<&str>; N]]) -> Vec<Vec<Option<String>>> {
+        rows.iter()
+            .map(|row| row.iter().map(|t| t.map(str::to_owned)).collect())
+            .collect()
+    }
+
+    const ROWS: [[Option<&str>; 2]; 6] = [
+        [Some("<b>"), Some("\"2\"")],
+        [Some("<a>"), Some("\"1\"")],
+        [Some("<b>"), None],
+        [Some("<a>"), Some("\"4\"")],
+        [Some("<b>"), Some("\"2\"")],
+        [None, Some("\"8\"")],
+    ];
+
+    #[test]
+    fn groups_in_order_of_their_first_row() {
+        let groups = group(
+            "SELECT ?g (COUNT(*) AS ?n) (COUNT(?v) AS ?c) (SUM(?v) AS ?s) (AVG(?v) AS ?a) \
+             WHERE { ?g <p> ?v } GROUP BY ?g",
+            &ROWS,
+        );
+        assert_eq!(
+            groups,
+            strings(&[
+                [
+                    Some("<b>"),
+                    Some("\"3\"^^<http://www.w3.org/2001/XMLSchema#integer>"),
+                    Some("\"2\"^^<http://www.w3.org/2001/XMLSchema#integer>"),
+                    Some("\"4\"^^<http://www.w3.org/2001/XMLSchema#integer>"),
+                    Some("\"2\"^^<http://www.w3.org/2001/XMLSchema#integer>")
+                ],
+                [
+                    Some("<a>"),
+                    Some("\"2\"^^<http://www.w3.org/2001/XMLSchema#integer>"),
+                    Some("\"2\"^^<http://www.w3.org/2001/XMLSchema#integer>"),
+                    Some("\"5\"^^<http://www.w3.org/2001/XMLSchema#integer>"),
+                    Some("\"2.5\"^^<http://www.w3.org/2001/XMLSchema#double>")
+                ],
+                [
+                    None,
+                    Some("\"1\"^^<http://www.w3.org/2001/XMLSchema#integer>"),
+                    Some("\"1\"^^<http://www.w3.org/2001/XMLSchema#integer>"),
+                    Some("\"8\"^^<http://www.w3.org/2001/XMLSchema#integer>"),
+                    Some("\"8\"^^<http://www.w3.org/2001/XMLSchema#integer>")
+                ],
+            ])
+        );
+    }
+
+    #[test]
+    fn min_max_and_group_concat() {
+        let groups = group(
+            "SELECT ?g (MIN(?v) AS ?min) (MAX(?v) AS ?max) \
+             (GROUP_CONCAT(?v; SEPARATOR=\", \") AS ?all) \
+             (GROUP_CONCAT(DISTINCT ?v) AS ?distinct) \
+             WHERE { ?g <p> ?v } GROUP BY ?g",
+            &ROWS,
+        );
+        assert_eq!(
+            groups,
+            strings(&[
+                [
+                    Some("<b>"),
+                    Some("\"2\""),
+                    Some("\"2\""),
+                    Some("\"2, 2\""),
+                    Some("\"2\"")
+                ],
+                [
+                    Some("<a>"),
+                    Some("\"1\""),
+                    Some("\"4\""),
+                    Some("\"1, 4\""),
+                    Some("\"1 4\"")
+                ],
+                [
+                    None,
+                    Some("\"8\""),
+                    Some("\"8\""),
+                    Some("\"8\""),
+                    Some("\"8\"")
+                ],
+            ])
+        );
+    }
+
+    #[test]
+    fn group_concat_escapes_quotes() {
+        let rows = [
+            [Some("<a>"), Some("\"say \\\"hi\\\"\"")],
+            [Some("<a>"), Some("<b>")],
+        ];
+        let groups = group(
+            "SELECT (GROUP_CONCAT(?v; SEPARATOR=\"\\\"\") AS ?all) WHERE { ?g <p> ?v }",
+            &rows,
+        );
+        assert_eq!(groups, strings(&[[Some("\"say \\\"hi\\\"\\\"b\"")]]));
+    }
+
+    #[test]
+    fn count_distinct_and_sums_of_non_numbers() {
+        let rows = [
+            [Some("<a>"), Some("\"x\"")],
+            [Some("<a>"), Some("\"x\"")],
+            [Some("<a>"), Some("\"1\"")],
+        ];
+        let groups = group(
+            "SELECT (COUNT(DISTINCT ?v) AS ?n) (SUM(?v) AS ?s) WHERE { ?g <p> ?v }",
+            &rows,
+        );
+        assert_eq!(
+            groups,
+            strings(&[[
+                Some("\"2\"^^<http://www.w3.org/2001/XMLSchema#integer>"),
+                None
+            ]])
+        );
+    }
+
+    #[test]
+    fn aggregates_without_rows() {
+        let query = "SELECT (COUNT(*) AS ?n) (MAX(?v) AS ?m) WHERE { ?g <p> ?v }";
+        assert_eq!(
+            group(query, &[]),
+            strings(&[[
+                Some("\"0\"^^<http://www.w3.org/2001/XMLSchema#integer>"),
+                None
+            ]])
+        );
+        let query = "SELECT ?g (COUNT(*) AS ?n) WHERE { ?g <p> ?v } GROUP BY ?g";
+        assert!(group(query, &[]).is_empty());
+    }
+}
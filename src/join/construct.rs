@@ -0,0 +1,214 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashSet;
+use std::hash::{BuildHasher, Hasher};
+use std::io::Write;
+use std::process;
+use std::time::SystemTime;
+
+use anyhow::Result;
+
+use crate::input::{Field, Terms};
+use crate::query::{Path, Term, TriplePattern};
+
+/// Instantiates `template` for every row and writes the resulting triples in the format of the
+/// input. `variables` names the columns of the rows. The constants of the template have been
+/// checked by the parser, triples with an unbound variable or whose values are not well-formed,
+/// e.g. a literal bound as subject, are left out. Blank nodes of the template are renamed for
+/// every row and get a prefix unique to the run, so they cannot clash with the blank nodes of
+/// the input or of other runs. Returns the number of triples written.
+pub fn write_triples<'a>(
+    io: &mut impl Write,
+    template: &[TriplePattern],
+    variables: &[Option<&String>],
+    rows: impl Iterator<Item = &'a Vec<Field>>,
+    terms: &Terms,
+    dedup: bool,
+) -> Result<usize> {
+    let column = |var: &str| {
+        variables
+            .iter()
+            .position(|v| v.map(String::as_str) == Some(var))
+    };
+    let slots = template
+        .iter()
+        .map(|pattern| {
+            let predicate = match &pattern.predicate {
+                Path::Variable(v) => Term::Var(v.clone()),
+                Path::Relation(name) => Term::Const(name.clone()),
+                path => unreachable!("{} in a CONSTRUCT template", path),
+            };
+            [&pattern.subject, &predicate, &pattern.object].map(|term| match term {
+                Term::Var(v) => Slot::Column(column(v)),
+                Term::Const(c) if c.starts_with("_:") => Slot::Blank(c.clone()),
+                Term::Const(c) => Slot::Const(c.clone()),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let run = format!("{:016x}", run_id());
+    let mut seen = HashSet::new();
+    let mut count = 0;
+    for (i, row) in rows.enumerate() {
+        for triple in &slots {
+            let [subject, predicate, object] = match triple
+                .each_ref()
+                .map(|slot| slot.value(row, i, &run, terms))
+            {
+                [Some(s), Some(p), Some(o)] => [s, p, o],
+                _ => continue,
+            };
+            if subject.starts_with('"') || !predicate.starts_with('<') {
+                continue;
+            }
+            let line = format!("{}\t{}\t{} .", subject, predicate, object);
+            if dedup && !seen.insert(line.clone()) {
+                continue;
+            }
+            writeln!(io, "{}", line)?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Where a term of the template takes its value from.
+enum Slot {
+    /// The value of a column, `None` if the variable is not bound by the query.
+    Column(Option<usize>),
+    Const(String),
+    /// A blank node label, made unique for each row and run.
+    Blank(String),
+}
+
+impl Slot {
+    fn value(&self, row: &[Field], index: usize, run: &str, terms: &Terms) -> Option<String> {
+        match self {
+            Slot::Column(column) => {
+                let field = row[(*column)?];
+                field
+                    .is_valid()
+                    .then(|| terms.extract_str(field).decode().into_owned())
+            }
+            Slot::Const(c) => Some(c.clone()),
+            Slot::Blank(label) => Some(format!("_:r{}_{}_{}", run, &label[2..], index)),
+        }
+    }
+}
+
+/// A random number identifying the run. The hasher keys are random for each process, the time
+/// and process id make up for platforms without a source of randomness.
+fn run_id() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    if let Ok(time) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        hasher.write_u128(time.as_nanos());
+    }
+    hasher.write_u32(process::id());
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::input::Input;
+    use crate::query::Query;
+
+    use super::*;
+
+    /// Instantiates the template of `query` for rows binding `?x` and `?y`.
+    fn construct(query: &str, rows: &[[Option<&str>; 2]], dedup: bool) -> Vec<String> {
+        let template = Query::parse(query).unwrap().construct.unwrap();
+        let input = Input::from_text("");
+        let mut terms = Terms::new(&input);
+        let rows = rows
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|t| t.map_or(Field::INVALID, |t| terms.add(t.to_owned())))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let (x, y) = ("x".to_owned(), "y".to_owned());
+        let mut out = Vec::new();
+        let count = write_triples(
+            &mut out,
+            &template,
+            &[Some(&x), Some(&y)],
+            rows.iter(),
+            &terms,
+            dedup,
+        )
+        .unwrap();
+        let lines = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(str::to_owned)
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), count);
+        lines
+    }
+
+    #[test]
+    fn instantiates_the_template() {
+        let rows = [
+            [Some("<a>"), Some("<b>")],
+            [Some("<a>"), Some("\"lit\"")],
+            [Some("<c>"), None],
+        ];
+        let triples = construct(
+            "CONSTRUCT { ?x <knows> ?y . ?y <known> ?x . ?x <is> <person> } WHERE { ?x <p> ?y }",
+            &rows,
+            false,
+        );
+        // Triples with unbound variables or a literal as subject are left out.
+        assert_eq!(
+            triples,
+            [
+                "<a>\t<knows>\t<b> .",
+                "<b>\t<known>\t<a> .",
+                "<a>\t<is>\t<person> .",
+                "<a>\t<knows>\t\"lit\" .",
+                "<a>\t<is>\t<person> .",
+                "<c>\t<is>\t<person> .",
+            ]
+        );
+    }
+
+    #[test]
+    fn removes_duplicate_triples() {
+        let rows = [[Some("<a>"), Some("<b>")], [Some("<a>"), Some("<c>")]];
+        let triples = construct(
+            "CONSTRUCT { ?x <is> <person> } WHERE { ?x <p> ?y }",
+            &rows,
+            true,
+        );
+        assert_eq!(triples, ["<a>\t<is>\t<person> ."]);
+    }
+
+    #[test]
+    fn skips_predicates_which_are_no_iris() {
+        let rows = [[Some("<a>"), Some("\"p\"")], [Some("<a>"), Some("<p>")]];
+        let triples = construct("CONSTRUCT { ?x ?y ?x } WHERE { ?x <p> ?y }", &rows, false);
+        assert_eq!(triples, ["<a>\t<p>\t<a> ."]);
+    }
+
+    #[test]
+    fn renames_blank_nodes_for_every_row() {
+        let rows = [[Some("<a>"), None], [Some("<b>"), None]];
+        let query = "CONSTRUCT { ?x <has> _:n . _:n <of> ?x } WHERE { ?x <p> ?y }";
+        let blanks = |triples: Vec<String>| {
+            triples
+                .iter()
+                .flat_map(|t| t.split_whitespace().filter(|p| p.starts_with("_:")))
+                .map(str::to_owned)
+                .collect::<Vec<_>>()
+        };
+        let first = blanks(construct(query, &rows, false));
+        assert_eq!(first.len(), 4);
+        // The same label is the same node within a row and a different one in the next row.
+        assert_eq!(first[0], first[1]);
+        assert_eq!(first[2], first[3]);
+        assert_ne!(first[0], first[2]);
+        // Every run uses a different prefix.
+        let second = blanks(construct(query, &rows, false));
+        assert_ne!(first[0], second[0]);
+    }
+}
@@ -0,0 +1,795 @@
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::ops::Range;
+
+use anyhow::{bail, Context, Result};
+use regex::{Regex, RegexBuilder};
+
+use crate::input::{Field, Terms};
+use crate::query::{BinOp, Expr, Function};
+
+/// An expression prepared for the evaluation on rows of the join table. Variables refer to
+/// columns and regular expressions are compiled up front.
+pub struct Filter {
+    root: Node,
+}
+
+enum Node {
+    Column(usize),
+    /// A variable which is not bound by any column.
+    Unbound,
+    Const(Value<'static>),
+    Not(Box<Node>),
+    Neg(Box<Node>),
+    Binary(BinOp, Box<Node>, Box<Node>),
+    Regex(Box<Node>, Regex),
+    /// `REPLACE` with the compiled pattern and the replacement.
+    Replace(Box<Node>, Regex, String),
+    Call(Function, Vec<Node>),
+}
+
+/// The namespace of the XML Schema datatypes of typed literals.
+const XSD: &str = "http://www.w3.org/2001/XMLSchema#";
+
+/// The numeric XML Schema datatypes.
+const NUMERIC_TYPES: &[&str] = &[
+    "integer",
+    "decimal",
+    "double",
+    "float",
+    "int",
+    "long",
+    "short",
+    "byte",
+    "nonNegativeInteger",
+    "nonPositiveInteger",
+    "positiveInteger",
+    "negativeInteger",
+    "unsignedInt",
+    "unsignedLong",
+    "unsignedShort",
+    "unsignedByte",
+];
+
+/// The value of a term or the result of an expression.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value<'a> {
+    Bool(bool),
+    Num(f64),
+    /// The lexical form of a literal without the quotes.
+    Literal(Cow<'a, str>),
+    /// An IRI including the angle brackets, or any other non-literal term.
+    Iri(Cow<'a, str>),
+}
+
+impl Filter {
+    /// `variables` names the variable bound by each column.
+    pub fn compile(expr: &Expr, variables: &[Option<&String>]) -> Result<Self> {
+        Ok(Filter {
+            root: Node::compile(expr, variables)?,
+        })
+    }
+
+    /// Whether the effective boolean value of the expression is true. Errors, e.g. from
+    /// unbound variables, count as false.
+    pub fn matches(&self, terms: &Terms, row: &[Field]) -> bool {
+        self.root
+            .eval(terms, row)
+            .and_then(|v| v.ebv())
+            .unwrap_or(false)
+    }
+
+    /// The value of the expression written as a term, `None` if it cannot be computed.
+    pub fn term(&self, terms: &Terms, row: &[Field]) -> Option<String> {
+        self.root.eval(terms, row).map(Value::into_term)
+    }
+}
+
+impl Node {
+    fn compile(expr: &Expr, variables: &[Option<&String>]) -> Result<Self> {
+        let compile = |e| Self::compile(e, variables).map(Box::new);
+        Ok(match expr {
+            Expr::Var(v) => variables
+                .iter()
+                .position(|var| *var == Some(v))
+                .map_or(Node::Unbound, Node::Column),
+            Expr::Const(c) => Node::Const(Value::from_term(Cow::Owned(c.clone()))),
+            Expr::Number(n) => Node::Const(Value::Num(*n)),
+            Expr::Bool(b) => Node::Const(Value::Bool(*b)),
+            Expr::Not(inner) => Node::Not(compile(inner)?),
+            Expr::Neg(inner) => Node::Neg(compile(inner)?),
+            Expr::Binary(op, lhs, rhs) => Node::Binary(*op, compile(lhs)?, compile(rhs)?),
+            Expr::Call(Function::Regex, args) => {
+                let regex = Self::regex(&args[1], args.get(2), "REGEX")?;
+                Node::Regex(compile(&args[0])?, regex)
+            }
+            Expr::Call(Function::Replace, args) => {
+                let regex = Self::regex(&args[1], args.get(3), "REPLACE")?;
+                let replacement = match Self::compile(&args[2], variables)? {
+                    Node::Const(Value::Literal(s)) => s.into_owned(),
+                    _ => bail!("the replacement of REPLACE has to be a literal"),
+                };
+                Node::Replace(compile(&args[0])?, regex, replacement)
+            }
+            Expr::Call(func, args) => Node::Call(
+                *func,
+                args.iter()
+                    .map(|arg| Self::compile(arg, variables))
+                    .collect::<Result<_>>()?,
+            ),
+        })
+    }
+
+    /// Compiles the pattern and flags of `REGEX` or `REPLACE`, both have to be literals.
+    fn regex(pattern: &Expr, flags: Option<&Expr>, function: &str) -> Result<Regex> {
+        let literal = |arg: &Expr| match Self::compile(arg, &[])? {
+            Node::Const(Value::Literal(s)) => Ok(s.into_owned()),
+            _ => bail!("the pattern and flags of {} have to be literals", function),
+        };
+        let pattern = literal(pattern)?;
+        let flags = flags.map(literal).transpose()?.unwrap_or_default();
+        RegexBuilder::new(&pattern)
+            .case_insensitive(flags.contains('i'))
+            .multi_line(flags.contains('m'))
+            .dot_matches_new_line(flags.contains('s'))
+            .ignore_whitespace(flags.contains('x'))
+            .build()
+            .with_context(|| format!("invalid regular expression ‘{}’", pattern))
+    }
+
+    /// Evaluates the expression. `None` signals an error.
+    fn eval<'s>(&'s self, terms: &'s Terms, row: &[Field]) -> Option<Value<'s>> {
+        match self {
+            Node::Column(c) => {
+                let field = row[*c];
+                field
+                    .is_valid()
+                    .then(|| Value::from_term(terms.extract_str(field).decode()))
+            }
+            Node::Unbound => None,
+            Node::Const(value) => Some(value.borrowed()),
+            Node::Not(inner) => Some(Value::Bool(!inner.eval(terms, row)?.ebv()?)),
+            Node::Neg(inner) => Some(Value::Num(-inner.eval(terms, row)?.as_num()?)),
+            Node::Binary(op, lhs, rhs) => Self::binary(*op, lhs, rhs, terms, row),
+            Node::Regex(text, regex) => {
+                let text = text.eval(terms, row)?;
+                Some(Value::Bool(regex.is_match(text.as_literal()?)))
+            }
+            Node::Replace(text, regex, replacement) => {
+                let text = text.eval(terms, row)?;
+                let replaced = regex.replace_all(text.as_literal()?, replacement.as_str());
+                Some(Value::Literal(Cow::Owned(replaced.into_owned())))
+            }
+            Node::Call(Function::Bound, args) => Some(Value::Bool(
+                matches!(args[0], Node::Column(c) if row[c].is_valid()),
+            )),
+            // Only the chosen branch is evaluated, errors of the others do not matter.
+            Node::Call(Function::If, args) => {
+                let branch = if args[0].eval(terms, row)?.ebv()? {
+                    1
+                } else {
+                    2
+                };
+                args[branch].eval(terms, row)
+            }
+            Node::Call(Function::Coalesce, args) => {
+                args.iter().find_map(|arg| arg.eval(terms, row))
+            }
+            Node::Call(func, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| arg.eval(terms, row))
+                    .collect::<Option<Vec<_>>>()?;
+                Self::call(*func, args)
+            }
+        }
+    }
+
+    fn binary<'s>(
+        op: BinOp,
+        lhs: &'s Node,
+        rhs: &'s Node,
+        terms: &'s Terms,
+        row: &[Field],
+    ) -> Option<Value<'s>> {
+        let ebv = |node: &'s Node| node.eval(terms, row).and_then(|v| v.ebv());
+        let result = match op {
+            // An error on one side is hidden if the other side decides the result.
+            BinOp::Or => match (ebv(lhs), ebv(rhs)) {
+                (Some(true), _) | (_, Some(true)) => true,
+                (Some(false), Some(false)) => false,
+                _ => return None,
+            },
+            BinOp::And => match (ebv(lhs), ebv(rhs)) {
+                (Some(false), _) | (_, Some(false)) => false,
+                (Some(true), Some(true)) => true,
+                _ => return None,
+            },
+            _ => {
+                let (a, b) = (lhs.eval(terms, row)?, rhs.eval(terms, row)?);
+                let ord = Value::compare(&a, &b);
+                match op {
+                    BinOp::Eq => ord == Some(Ordering::Equal),
+                    BinOp::Ne => ord != Some(Ordering::Equal),
+                    BinOp::Lt => ord? == Ordering::Less,
+                    BinOp::Le => ord? != Ordering::Greater,
+                    BinOp::Gt => ord? == Ordering::Greater,
+                    BinOp::Ge => ord? != Ordering::Less,
+                    _ => {
+                        let (x, y) = (a.as_num()?, b.as_num()?);
+                        return Some(Value::Num(match op {
+                            BinOp::Add => x + y,
+                            BinOp::Sub => x - y,
+                            BinOp::Mul => x * y,
+                            _ => x / y,
+                        }));
+                    }
+                }
+            }
+        };
+        Some(Value::Bool(result))
+    }
+
+    fn call(func: Function, args: Vec<Value>) -> Option<Value> {
+        let literal = |i: usize| args[i].as_literal();
+        Some(match func {
+            Function::Str => Value::Literal(Cow::Owned(args[0].as_str().into_owned())),
+            Function::StrLen => Value::Num(literal(0)?.chars().count() as f64),
+            Function::Contains => Value::Bool(literal(0)?.contains(literal(1)?)),
+            Function::StrStarts => Value::Bool(literal(0)?.starts_with(literal(1)?)),
+            Function::StrEnds => Value::Bool(literal(0)?.ends_with(literal(1)?)),
+            Function::LCase => Value::Literal(Cow::Owned(literal(0)?.to_lowercase())),
+            Function::UCase => Value::Literal(Cow::Owned(literal(0)?.to_uppercase())),
+            Function::IsIri => Value::Bool(matches!(&args[0], Value::Iri(s) if s.starts_with('<'))),
+            Function::IsLiteral => Value::Bool(!matches!(args[0], Value::Iri(_))),
+            Function::IsBlank => {
+                Value::Bool(matches!(&args[0], Value::Iri(s) if s.starts_with("_:")))
+            }
+            Function::IsNumeric => {
+                Value::Bool(!matches!(args[0], Value::Iri(_)) && args[0].as_num().is_some())
+            }
+            Function::Concat => Value::Literal(Cow::Owned(
+                (0..args.len()).map(literal).collect::<Option<String>>()?,
+            )),
+            Function::SubStr => {
+                // Positions count characters starting at 1.
+                let start = args[1].as_num()?.round();
+                let end = match args.get(2) {
+                    Some(len) => start + len.as_num()?.round(),
+                    None => f64::INFINITY,
+                };
+                let substring = literal(0)?
+                    .chars()
+                    .zip(1..)
+                    .filter(|&(_, i)| start <= i as f64 && (i as f64) < end)
+                    .map(|(c, _)| c)
+                    .collect();
+                Value::Literal(Cow::Owned(substring))
+            }
+            Function::StrBefore => {
+                let (text, sep) = (literal(0)?, literal(1)?);
+                let before = text.find(sep).map_or("", |i| &text[..i]);
+                Value::Literal(Cow::Owned(before.to_owned()))
+            }
+            Function::StrAfter => {
+                let (text, sep) = (literal(0)?, literal(1)?);
+                let after = text.find(sep).map_or("", |i| &text[i + sep.len()..]);
+                Value::Literal(Cow::Owned(after.to_owned()))
+            }
+            Function::EncodeForUri => Value::Literal(Cow::Owned(encode_for_uri(literal(0)?))),
+            Function::Abs => Value::Num(args[0].as_num()?.abs()),
+            // SPARQL rounds halves up, e.g. -2.5 to -2.
+            Function::Round => Value::Num((args[0].as_num()? + 0.5).floor()),
+            Function::Ceil => Value::Num(args[0].as_num()?.ceil()),
+            Function::Floor => Value::Num(args[0].as_num()?.floor()),
+            Function::Iri | Function::Uri => match &args[0] {
+                Value::Iri(s) if s.starts_with('<') => Value::Iri(Cow::Owned(s.to_string())),
+                Value::Literal(s) => Value::Iri(Cow::Owned(format!("<{}>", s))),
+                _ => return None,
+            },
+            Function::Bound
+            | Function::Regex
+            | Function::Replace
+            | Function::If
+            | Function::Coalesce => unreachable!("handled by Node::eval"),
+        })
+    }
+}
+
+impl<'a> Value<'a> {
+    /// Interprets a term as written in the input or a query. Literals typed as XML Schema
+    /// booleans or numbers are read as such, other typed literals by their lexical form.
+    pub fn from_term(term: Cow<'a, str>) -> Self {
+        if term.len() >= 2 && term.starts_with('"') && term.ends_with('"') {
+            let end = term.len() - 1;
+            return Value::Literal(lexical_form(term, 1..end));
+        }
+        let end = match term.rfind("\"^^<") {
+            Some(end) if end > 0 && term.starts_with('"') && term.ends_with('>') => end,
+            _ => return Value::Iri(term),
+        };
+        let datatype = &term[end + 4..term.len() - 1];
+        let lexical = &term[1..end];
+        match datatype.strip_prefix(XSD) {
+            Some("boolean") if matches!(lexical, "true" | "1") => return Value::Bool(true),
+            Some("boolean") if matches!(lexical, "false" | "0") => return Value::Bool(false),
+            Some(name) if NUMERIC_TYPES.contains(&name) => {
+                if let Ok(n) = lexical.trim().parse() {
+                    return Value::Num(n);
+                }
+            }
+            _ => {}
+        }
+        Value::Literal(lexical_form(term, 1..end))
+    }
+
+    /// The value written as a term. Booleans and numbers are typed literals, so that they keep
+    /// their kind when read back.
+    pub fn into_term(self) -> String {
+        match self {
+            Value::Iri(s) => s.into_owned(),
+            Value::Bool(b) => format!("\"{}\"^^<{}boolean>", b, XSD),
+            Value::Num(n) if n.fract() == 0.0 => format!("\"{}\"^^<{}integer>", n, XSD),
+            Value::Num(n) => {
+                let lexical = match n {
+                    n if n.is_nan() => "NaN".to_owned(),
+                    n if n.is_infinite() => if n > 0.0 { "INF" } else { "-INF" }.to_owned(),
+                    n => n.to_string(),
+                };
+                format!("\"{}\"^^<{}double>", lexical, XSD)
+            }
+            Value::Literal(s) => format!("\"{}\"", escape(&s)),
+        }
+    }
+
+    fn borrowed(&self) -> Value<'_> {
+        match self {
+            Value::Bool(b) => Value::Bool(*b),
+            Value::Num(n) => Value::Num(*n),
+            Value::Literal(s) => Value::Literal(Cow::Borrowed(s)),
+            Value::Iri(s) => Value::Iri(Cow::Borrowed(s)),
+        }
+    }
+
+    /// The numeric value. Literals in the lexical form of a SPARQL integer, decimal or double
+    /// are parsed as numbers, others like `"inf"` or `"NaN"` are not numbers.
+    pub fn as_num(&self) -> Option<f64> {
+        match self {
+            Value::Num(n) => Some(*n),
+            Value::Literal(s) => {
+                let s = s.trim();
+                is_numeric(s).then(|| s.parse().ok()).flatten()
+            }
+            Value::Bool(_) | Value::Iri(_) => None,
+        }
+    }
+
+    fn as_literal(&self) -> Option<&str> {
+        match self {
+            Value::Literal(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// The string value as returned by `STR`: IRIs lose their angle brackets.
+    pub fn as_str(&self) -> Cow<'_, str> {
+        match self {
+            Value::Bool(b) => Cow::Owned(b.to_string()),
+            Value::Num(n) => Cow::Owned(n.to_string()),
+            Value::Literal(s) => Cow::Borrowed(s),
+            Value::Iri(s) => Cow::Borrowed(
+                s.strip_prefix('<')
+                    .and_then(|s| s.strip_suffix('>'))
+                    .unwrap_or(s),
+            ),
+        }
+    }
+
+    /// The effective boolean value.
+    fn ebv(&self) -> Option<bool> {
+        match self {
+            Value::Bool(b) => Some(*b),
+            Value::Num(n) => Some(*n != 0.0 && !n.is_nan()),
+            Value::Literal(s) => Some(!s.is_empty()),
+            Value::Iri(_) => None,
+        }
+    }
+
+    /// Compares numerically if both values are numbers, otherwise values of the same kind are
+    /// compared by their lexical form.
+    pub fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+        if let (Some(x), Some(y)) = (a.as_num(), b.as_num()) {
+            return x.partial_cmp(&y);
+        }
+        match (a, b) {
+            (Value::Literal(x), Value::Literal(y)) | (Value::Iri(x), Value::Iri(y)) => {
+                Some(x.cmp(y))
+            }
+            (Value::Bool(x), Value::Bool(y)) => Some(x.cmp(y)),
+            _ => None,
+        }
+    }
+
+    /// A total order for sorting results: unbound values come first, then blank nodes, IRIs,
+    /// numbers and all other literals. Numbers are compared by value, everything else by the
+    /// lexical form.
+    pub fn order(a: Option<&Value>, b: Option<&Value>) -> Ordering {
+        let rank = |v: Option<&Value>| match v {
+            None => 0,
+            Some(Value::Iri(s)) if !s.starts_with('<') => 1,
+            Some(Value::Iri(_)) => 2,
+            Some(v) if v.as_num().is_some() => 3,
+            Some(_) => 4,
+        };
+        rank(a).cmp(&rank(b)).then_with(|| match (a, b) {
+            (Some(x), Some(y)) => match (x.as_num(), y.as_num()) {
+                (Some(n), Some(m)) => n.total_cmp(&m),
+                _ => x.as_str().cmp(&y.as_str()),
+            },
+            _ => Ordering::Equal,
+        })
+    }
+}
+
+/// Whether `s` is a numeric literal as in SPARQL: digits with an optional sign, fraction and
+/// exponent such as `-1`, `2.5`, `.5` or `1e3`.
+fn is_numeric(s: &str) -> bool {
+    let digits = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+    let s = s.strip_prefix(['+', '-']).unwrap_or(s);
+    let (mantissa, exponent) = match s.split_once(['e', 'E']) {
+        Some((mantissa, exponent)) => (mantissa, Some(exponent)),
+        None => (s, None),
+    };
+    let (integer, fraction) = match mantissa.split_once('.') {
+        Some((integer, fraction)) => (integer, Some(fraction)),
+        None => (mantissa, None),
+    };
+    let exponent_ok = exponent.is_none_or(|e| {
+        let e = e.strip_prefix(['+', '-']).unwrap_or(e);
+        !e.is_empty() && digits(e)
+    });
+    // Only doubles may end with the decimal point, e.g. `1.e3`.
+    let fraction_ok = fraction.is_none_or(|f| digits(f) && (!f.is_empty() || exponent.is_some()));
+    let has_digits = !integer.is_empty() || fraction.is_some_and(|f| !f.is_empty());
+    digits(integer) && fraction_ok && exponent_ok && has_digits
+}
+
+/// Percent-encodes all characters but the unreserved ones of RFC 3986.
+fn encode_for_uri(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(byte as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    out
+}
+
+/// Resolves the escape sequences of a literal from a query.
+/// The part `range` of `term` with its escape sequences resolved. It is only copied if there is
+/// anything to resolve.
+fn lexical_form(term: Cow<'_, str>, range: Range<usize>) -> Cow<'_, str> {
+    match term {
+        Cow::Borrowed(s) if !s[range.clone()].contains('\\') => Cow::Borrowed(&s[range]),
+        s => Cow::Owned(unescape(&s[range])),
+    }
+}
+
+/// Escapes the characters of `s` which cannot appear in a literal as they are, the inverse of
+/// `unescape`.
+pub fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            '\\' | '"' => {
+                out.push('\\');
+                out.push(c);
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+pub fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some('r') => out.push('\r'),
+            Some(c @ ('\\' | '"' | '\'')) => out.push(c),
+            Some(c) => {
+                out.push('\\');
+                out.push(c);
+            }
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::input::Input;
+    use crate::query::{Element, Query};
+
+    use super::*;
+
+    /// Parses `expr` as the condition of a FILTER on the variables `?a` and `?b`.
+    fn compile(expr: &str) -> Filter {
+        let text = format!("SELECT * WHERE {{ ?a <p> ?b FILTER({}) }}", expr);
+        let expr = match Query::parse(&text).unwrap().pattern.elements.pop() {
+            Some(Element::Filter(expr)) => expr,
+            element => panic!("unexpected element {:?}", element),
+        };
+        let (a, b) = ("a".to_owned(), "b".to_owned());
+        Filter::compile(&expr, &[Some(&a), Some(&b)]).unwrap()
+    }
+
+    /// Evaluates `f` on a row binding `?a` and `?b` to the given terms.
+    fn with_row<T>(row: [Option<&str>; 2], f: impl FnOnce(&Terms, &[Field]) -> T) -> T {
+        let input = Input::from_text("");
+        let mut terms = Terms::new(&input);
+        let row = row
+            .iter()
+            .map(|term| term.map_or(Field::INVALID, |t| terms.add(t.to_owned())))
+            .collect::<Vec<_>>();
+        f(&terms, &row)
+    }
+
+    fn matches(expr: &str, row: [Option<&str>; 2]) -> bool {
+        let filter = compile(expr);
+        with_row(row, |terms, row| filter.matches(terms, row))
+    }
+
+    fn value(text: &str) -> Value<'_> {
+        Value::from_term(Cow::Borrowed(text))
+    }
+
+    #[test]
+    fn compares_numbers_by_value() {
+        assert!(matches("?a < ?b", [Some("\"9\""), Some("\"10\"")]));
+        assert!(matches("?a = 2.0", [Some("\"2\""), None]));
+        assert!(matches("?a >= -1.5", [Some("\"-1.5\""), None]));
+        assert!(!matches("?a > ?b", [Some("\"1e1\""), Some("\"10\"")]));
+    }
+
+    #[test]
+    fn compares_other_terms_lexically() {
+        assert!(matches("?a < ?b", [Some("\"abc\""), Some("\"abd\"")]));
+        assert!(matches("?a = <x>", [Some("<x>"), None]));
+        // Literals and IRIs are never equal and cannot be ordered.
+        assert!(matches("?a != ?b", [Some("\"x\""), Some("<x>")]));
+        assert!(!matches("?a < ?b", [Some("\"x\""), Some("<x>")]));
+        assert!(!matches("?a >= ?b", [Some("\"x\""), Some("<x>")]));
+        assert_eq!(
+            Value::compare(&value("\"b\""), &value("\"a\"")),
+            Some(Ordering::Greater)
+        );
+        assert_eq!(Value::compare(&Value::Bool(true), &value("\"1\"")), None);
+    }
+
+    #[test]
+    fn effective_boolean_value() {
+        assert_eq!(value("\"\"").ebv(), Some(false));
+        assert_eq!(value("\"x\"").ebv(), Some(true));
+        assert_eq!(value("<x>").ebv(), None);
+        assert_eq!(Value::Num(0.0).ebv(), Some(false));
+        assert_eq!(Value::Num(f64::NAN).ebv(), Some(false));
+        assert_eq!(Value::Num(-2.0).ebv(), Some(true));
+        assert!(matches("?a", [Some("\"x\""), None]));
+        assert!(!matches("?a", [Some("\"\""), None]));
+        // An IRI has no boolean value, neither it nor its negation hold.
+        assert!(!matches("?a", [Some("<x>"), None]));
+        assert!(!matches("!?a", [Some("<x>"), None]));
+    }
+
+    #[test]
+    fn errors_count_as_false() {
+        assert!(!matches("?b = 1", [Some("\"1\""), None]));
+        assert!(!matches("?b != 1", [Some("\"1\""), None]));
+        assert!(!matches("?c = 1", [Some("\"1\""), None]));
+        // Disjunctions and conjunctions hide the error if the other side decides.
+        assert!(matches("?b = 1 || ?a = 1", [Some("\"1\""), None]));
+        assert!(!matches("?b = 1 && ?a = 1", [Some("\"1\""), None]));
+        assert!(matches("!(?b = 1 && ?a = 2)", [Some("\"1\""), None]));
+        assert!(matches("BOUND(?a) && !BOUND(?b)", [Some("\"1\""), None]));
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert!(matches("?a + ?b * 2 = 7", [Some("\"1\""), Some("\"3\"")]));
+        assert!(matches(
+            "(?a - ?b) / 2 = -1",
+            [Some("\"1\""), Some("\"3\"")]
+        ));
+        assert!(matches("-?a = -1", [Some("\"1\""), None]));
+        assert!(!matches("?a + 1 = 1", [Some("\"x\""), None]));
+    }
+
+    #[test]
+    fn only_numeric_literals_are_numbers() {
+        for text in [
+            "1", "-1", "+1", "2.5", ".5", "1.e3", "1e3", "-1.5E-3", ".5e1", " 7 ",
+        ] {
+            let literal = Value::Literal(Cow::Borrowed(text));
+            assert!(literal.as_num().is_some(), "{}", text);
+        }
+        for text in [
+            "", "-", ".", "1.", "e3", "1e", "1.e", "1..2", "0x10", "inf", "NaN", "infinity",
+        ] {
+            let literal = Value::Literal(Cow::Borrowed(text));
+            assert_eq!(literal.as_num(), None, "{}", text);
+        }
+        assert_eq!(value("<1>").as_num(), None);
+        assert!(!matches("?a > 3", [Some("\"inf\""), None]));
+    }
+
+    #[test]
+    fn string_functions() {
+        let row = [Some("\"Hello World\""), Some("<http://a/b>")];
+        assert!(matches("STRLEN(?a) = 11", row));
+        assert!(matches("CONTAINS(?a, \"lo W\")", row));
+        assert!(matches(
+            "STRSTARTS(?a, \"Hell\") && STRENDS(?a, \"rld\")",
+            row
+        ));
+        assert!(matches("LCASE(?a) = \"hello world\"", row));
+        assert!(matches("UCASE(?a) = \"HELLO WORLD\"", row));
+        assert!(matches("STR(?b) = \"http://a/b\"", row));
+        assert!(matches("isIRI(?b) && !isIRI(?a)", row));
+        assert!(matches("isLiteral(?a) && !isLiteral(?b)", row));
+        // String functions need literals.
+        assert!(!matches("STRLEN(?b) > 0", row));
+    }
+
+    #[test]
+    fn regex() {
+        let row = [Some("\"Hello World\""), None];
+        assert!(matches("REGEX(?a, \"^hello\", \"i\")", row));
+        assert!(!matches("REGEX(?a, \"^hello\")", row));
+        assert!(matches("REGEX(?a, \"o W\")", row));
+        let text = "SELECT * WHERE { ?a <p> ?b FILTER(REGEX(?a, ?b)) }";
+        let expr = match Query::parse(text).unwrap().pattern.elements.pop() {
+            Some(Element::Filter(expr)) => expr,
+            element => panic!("unexpected element {:?}", element),
+        };
+        assert!(Filter::compile(&expr, &[]).is_err());
+    }
+
+    /// The term computed by `expr` as by `BIND`, on the row binding `?a` and `?b`.
+    fn term(expr: &str, row: [Option<&str>; 2]) -> Option<String> {
+        let filter = compile(expr);
+        with_row(row, |terms, row| filter.term(terms, row))
+    }
+
+    #[test]
+    fn computed_terms() {
+        let row = [Some("\"Hello World\""), Some("<http://a/b>")];
+        let term = |expr| term(expr, row);
+        assert_eq!(term("?b").as_deref(), Some("<http://a/b>"));
+        assert_eq!(term("STR(?b)").as_deref(), Some("\"http://a/b\""));
+        assert_eq!(
+            term("STRLEN(?a) * 2"),
+            Some(format!("\"22\"^^<{}integer>", XSD))
+        );
+        assert_eq!(
+            term("STRLEN(?a) / 4"),
+            Some(format!("\"2.75\"^^<{}double>", XSD))
+        );
+        assert_eq!(term("?a = ?a"), Some(format!("\"true\"^^<{}boolean>", XSD)));
+        assert_eq!(term("?c"), None);
+    }
+
+    #[test]
+    fn string_building_functions() {
+        let row = [Some("\"Hello World\""), Some("\"ö/x y\"")];
+        let term = |expr| term(expr, row);
+        assert_eq!(
+            term("CONCAT(?a, \" \", ?b)").as_deref(),
+            Some("\"Hello World ö/x y\"")
+        );
+        assert_eq!(term("SUBSTR(?a, 7)").as_deref(), Some("\"World\""));
+        assert_eq!(term("SUBSTR(?a, 2, 3)").as_deref(), Some("\"ell\""));
+        assert_eq!(term("SUBSTR(?b, 1, 1)").as_deref(), Some("\"ö\""));
+        assert_eq!(term("STRBEFORE(?a, \"o\")").as_deref(), Some("\"Hell\""));
+        assert_eq!(term("STRAFTER(?a, \"o\")").as_deref(), Some("\" World\""));
+        assert_eq!(term("STRAFTER(?a, \"x\")").as_deref(), Some("\"\""));
+        assert_eq!(
+            term("REPLACE(?a, \"o\", \"0\")").as_deref(),
+            Some("\"Hell0 W0rld\"")
+        );
+        assert_eq!(
+            term("REPLACE(?a, \"(l+)\", \"[$1]\")").as_deref(),
+            Some("\"He[ll]o Wor[l]d\"")
+        );
+        assert_eq!(
+            term("ENCODE_FOR_URI(?b)").as_deref(),
+            Some("\"%C3%B6%2Fx%20y\"")
+        );
+        assert_eq!(term("IRI(?a)").as_deref(), Some("<Hello World>"));
+        assert_eq!(term("URI(IRI(?b))").as_deref(), Some("<ö/x y>"));
+        assert_eq!(term("CONCAT(?a, 1)"), None);
+    }
+
+    #[test]
+    fn computed_literals_are_escaped() {
+        let row = [Some("\"bob\""), Some("\"a\\\\b\\nc\"")];
+        let term = |expr| term(expr, row);
+        assert_eq!(
+            term("REPLACE(?a, \"b\", \"\\\"\")").as_deref(),
+            Some("\"\\\"o\\\"\"")
+        );
+        // Escape sequences of the input are resolved and written again.
+        assert_eq!(
+            term("STRLEN(?b) = 5"),
+            Some(format!("\"true\"^^<{}boolean>", XSD))
+        );
+        assert_eq!(term("UCASE(?b)").as_deref(), Some("\"A\\\\B\\nC\""));
+        assert_eq!(
+            term("CONCAT(\"say \\\"\", ?a, \"\\\"\")").as_deref(),
+            Some("\"say \\\"bob\\\"\"")
+        );
+    }
+
+    #[test]
+    fn numeric_functions() {
+        let term = |expr| term(expr, [Some("\"-2.5\""), Some("\"2.4\"")]);
+        let integer = |n| Some(format!("\"{}\"^^<{}integer>", n, XSD));
+        assert_eq!(term("ABS(?a)"), Some(format!("\"2.5\"^^<{}double>", XSD)));
+        assert_eq!(term("ROUND(?a)"), integer(-2));
+        assert_eq!(term("ROUND(?b)"), integer(2));
+        assert_eq!(term("CEIL(?a)"), integer(-2));
+        assert_eq!(term("FLOOR(?b)"), integer(2));
+        assert_eq!(term("ABS(\"x\")"), None);
+    }
+
+    #[test]
+    fn typed_literals_keep_their_kind() {
+        let row = [
+            Some("\"false\"^^<http://www.w3.org/2001/XMLSchema#boolean>"),
+            Some("\"0\"^^<http://www.w3.org/2001/XMLSchema#integer>"),
+        ];
+        assert!(!matches("?a", row) && !matches("?b", row));
+        assert!(matches("!?a && ?b + 1 = 1 && isNumeric(?b)", row));
+        assert!(matches("STR(?a) = \"false\"", row));
+        let row = [
+            Some("\"x\"^^<http://example.org/t>"),
+            Some("\"1.5e0\"^^<foo>"),
+        ];
+        assert!(matches("?a = \"x\" && ?b = \"1.5e0\"", row));
+    }
+
+    #[test]
+    fn term_tests() {
+        let row = [Some("_:n"), Some("\"1e2\"")];
+        assert!(matches("isBlank(?a) && !isIRI(?a) && !isLiteral(?a)", row));
+        assert!(matches("isNumeric(?b) && !isNumeric(?a)", row));
+        assert!(!matches("isNumeric(\"x\")", row));
+    }
+
+    #[test]
+    fn conditionals_only_evaluate_the_needed_arguments() {
+        let row = [Some("\"1\""), None];
+        assert_eq!(
+            term("IF(?a = 1, \"yes\", ?b)", row).as_deref(),
+            Some("\"yes\"")
+        );
+        assert_eq!(term("IF(?a = 2, \"yes\", ?b)", row), None);
+        assert_eq!(term("IF(?b, 1, 2)", row), None);
+        assert_eq!(term("COALESCE(?b, ?c, ?a)", row).as_deref(), Some("\"1\""));
+        assert_eq!(term("COALESCE(?b)", row), None);
+    }
+}
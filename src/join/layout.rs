@@ -0,0 +1,165 @@
+use crate::query::Chain;
+
+/// Where the columns of a chain are stored in the rows of the join table. A column is dropped
+/// from the rows as soon as no later step, filter or the output refers to it. Columns which are
+/// not bound yet keep their (unbound) slot until they are written.
+pub struct Layout {
+    /// For each step the position of every column in the rows while the step runs, `None` if
+    /// the column has been dropped.
+    pub positions: Vec<Vec<Option<usize>>>,
+    /// For each step the positions to keep once the step and its filters are done, `None` if
+    /// nothing can be dropped.
+    pub projections: Vec<Option<Vec<usize>>>,
+    /// The positions making up the output rows in the order of the output columns, `None` if
+    /// the rows already have this form.
+    pub output: Option<Vec<usize>>,
+}
+
+impl Layout {
+    /// `output` lists the columns of the result in the order they are printed.
+    pub fn new(chain: &Chain, output: &[usize]) -> Self {
+        let width = chain.width();
+        let steps = &chain.steps;
+
+        let producers = chain.producers();
+
+        // Walk backwards through the steps to find the columns still needed after each one.
+        let mut needed = vec![false; width];
+        output.iter().for_each(|&c| needed[c] = true);
+        let mut needed_after = vec![Vec::new(); steps.len()];
+        for (i, step) in steps.iter().enumerate().rev() {
+            needed_after[i] = needed.clone();
+            needed[step.key] = true;
+            if let Some(c) = step.object_key {
+                needed[c] = true;
+            }
+            let exprs = step
+                .filters
+                .iter()
+                .chain(step.binds.iter().map(|b| &b.expr));
+            for var in exprs.flat_map(|expr| expr.variables()) {
+                if let Some(c) = chain
+                    .variables
+                    .iter()
+                    .position(|v| v.as_deref() == Some(var))
+                {
+                    needed[c] = true;
+                }
+            }
+        }
+
+        // `stored` lists the columns in the order they appear in the rows.
+        let mut stored = (0..width).collect::<Vec<_>>();
+        let mut positions = Vec::with_capacity(steps.len());
+        let mut projections = Vec::with_capacity(steps.len());
+        for (i, needed) in needed_after.iter().enumerate() {
+            let mut position = vec![None; width];
+            for (p, &c) in stored.iter().enumerate() {
+                position[c] = Some(p);
+            }
+            positions.push(position);
+
+            let keep = (0..stored.len())
+                .filter(|&p| producers[stored[p]] > i || needed[stored[p]])
+                .collect::<Vec<_>>();
+            if keep.len() < stored.len() {
+                stored = keep.iter().map(|&p| stored[p]).collect();
+                projections.push(Some(keep));
+            } else {
+                projections.push(None);
+            }
+        }
+
+        let order = output
+            .iter()
+            .map(|c| {
+                stored
+                    .iter()
+                    .position(|s| s == c)
+                    .expect("output column dropped")
+            })
+            .collect::<Vec<_>>();
+        let identity =
+            order.len() == stored.len() && order.iter().enumerate().all(|(i, &p)| i == p);
+
+        Layout {
+            positions,
+            projections,
+            output: (!identity).then_some(order),
+        }
+    }
+
+    /// The variable stored at each position of the rows while step `i` runs.
+    pub fn variables<'c>(&self, chain: &'c Chain, i: usize) -> Vec<Option<&'c String>> {
+        let position = &self.positions[i];
+        let mut variables = vec![None; position.iter().flatten().count()];
+        for (c, p) in position.iter().enumerate() {
+            if let Some(p) = *p {
+                variables[p] = chain.variables[c].as_ref();
+            }
+        }
+        variables
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::query::Query;
+
+    use super::*;
+
+    fn layout(text: &str) -> Layout {
+        let query = Query::parse(text).unwrap();
+        let projection = query.projection.clone();
+        let chain = query.into_chains(None).unwrap().remove(0);
+        let output = chain.output_columns(projection.as_deref()).unwrap();
+        Layout::new(&chain, &output)
+    }
+
+    #[test]
+    fn keeps_all_columns_of_select_all() {
+        let layout = layout("SELECT * WHERE { ?x <p> ?y . ?y <q> ?z }");
+        assert_eq!(layout.projections, [None, None]);
+        assert_eq!(layout.output, None);
+    }
+
+    #[test]
+    fn drops_columns_once_they_are_no_longer_needed() {
+        // ?x joins the last pattern, ?y only the second one.
+        let layout = layout("SELECT ?w WHERE { ?x <p> ?y . ?y <q> ?z . ?x <r> ?w }");
+        assert_eq!(layout.projections, [None, Some(vec![0, 3]), Some(vec![1])]);
+        assert_eq!(
+            layout.positions,
+            [
+                vec![Some(0), Some(1), Some(2), Some(3)],
+                vec![Some(0), Some(1), Some(2), Some(3)],
+                vec![Some(0), None, None, Some(1)],
+            ]
+        );
+        assert_eq!(layout.output, None);
+    }
+
+    #[test]
+    fn keeps_columns_for_filters() {
+        let layout =
+            layout("SELECT ?w WHERE { ?x <p> ?y . ?x <q> ?z . ?x <r> ?w FILTER(?y != ?w) }");
+        assert_eq!(
+            layout.projections,
+            [None, Some(vec![0, 1, 3]), Some(vec![2])]
+        );
+    }
+
+    #[test]
+    fn keeps_columns_for_anti_joins() {
+        // MINUS compares the rows on both ?x and ?y.
+        let layout = layout("SELECT ?x WHERE { ?x <p> ?y MINUS { ?x <q> ?y } }");
+        assert_eq!(layout.projections, [None, Some(vec![0])]);
+    }
+
+    #[test]
+    fn reorders_the_output() {
+        let layout = layout("SELECT ?z ?x WHERE { ?x <p> ?y . ?y <q> ?z }");
+        assert_eq!(layout.projections, [None, Some(vec![0, 2])]);
+        assert_eq!(layout.output, Some(vec![1, 0]));
+    }
+}
@@ -0,0 +1,459 @@
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    mem,
+};
+
+use rayon::{
+    iter::{IntoParallelRefIterator, ParallelIterator},
+    slice::ParallelSliceMut,
+};
+
+use crate::{
+    input::{self, Field, Input},
+    query::{JoinMode, Path, TriplePart},
+    relation::{Relation, StrRelation, Universe},
+};
+
+use super::{JoinAlgo, JoinStep, Settings};
+
+type Triple<'a> = (input::Str<'a>, input::Str<'a>, input::Str<'a>);
+
+/// Resolves paths to the plain relations they describe.
+pub struct Evaluator<'u, 'a> {
+    pub input: &'a Input,
+    pub universe: &'u Universe<'a>,
+    /// Creates a fresh instance of the join algorithm used to compute closures.
+    pub new_algo: &'u (dyn Fn() -> Box<dyn JoinAlgo> + Sync),
+    /// Maximum length of the paths matched by `p+` and `p*`.
+    pub max_depth: Option<usize>,
+    /// Remove duplicate entries from the union of alternatives.
+    pub dedup_alternatives: bool,
+}
+
+impl<'u, 'a> Evaluator<'u, 'a> {
+    /// All referenced relations have to exist in `self.universe`.
+    pub fn evaluate(&self, path: &'a Path) -> Cow<'u, StrRelation<'a>> {
+        match path {
+            Path::Relation(name) => Cow::Borrowed(&self.universe[&input::Str::new(name)]),
+            Path::Inverse(inner) => {
+                // Swap the pairs so that the dictionaries in `Pipeline::build` are built for the
+                // original subjects.
+                let rel = self.evaluate(inner);
+                Cow::Owned(rel.par_iter().map(|&(subj, obj)| (obj, subj)).collect())
+            }
+            Path::OneOrMore(inner) => Cow::Owned(self.closure(&self.evaluate(inner), false)),
+            Path::ZeroOrMore(inner) => Cow::Owned(self.closure(&self.evaluate(inner), true)),
+            Path::Repeat(inner, min, max) => {
+                Cow::Owned(self.repeat(&self.evaluate(inner), *min, *max))
+            }
+            Path::Alternative(paths) => {
+                let mut union = Vec::new();
+                for path in paths {
+                    union.extend_from_slice(&self.evaluate(path));
+                }
+                if self.dedup_alternatives {
+                    union.par_sort_unstable();
+                    union.dedup();
+                }
+                Cow::Owned(union)
+            }
+            Path::Any => Cow::Owned(self.scan(|(subj, _, obj), _| Some((subj, obj)))),
+            Path::Triples(object) => {
+                let object = object.as_deref().map(input::Str::new);
+                Cow::Owned(self.scan(|(subj, _, obj), line| {
+                    object.is_none_or(|o| o == obj).then_some((subj, line))
+                }))
+            }
+            Path::TriplePart(part) => Cow::Owned(self.scan(|(_, pred, obj), line| {
+                Some(match part {
+                    TriplePart::Predicate => (line, pred),
+                    TriplePart::Object => (line, obj),
+                })
+            })),
+            Path::Variable(_) => unreachable!("variable predicates are resolved by Chain"),
+            Path::TableRows(..) | Path::TableColumn(..) => {
+                unreachable!("subquery tables are resolved by Pipeline")
+            }
+        }
+    }
+
+    /// Builds a relation from all triples of the input. `entry` receives the subject, predicate
+    /// and object of each triple and the text of the whole triple which identifies it.
+    fn scan(
+        &self,
+        entry: impl Fn(Triple<'a>, input::Str<'a>) -> Option<(input::Str<'a>, input::Str<'a>)>,
+    ) -> StrRelation<'a> {
+        eprintln!("++ [scan] reading all triples");
+        self.input
+            .iter_lines()
+            .filter_map(|line| entry(line.parse(), line.text()))
+            .collect()
+    }
+
+    /// Computes the transitive closure of `rel` as a semi-naive fixpoint: in every round only the
+    /// pairs found in the previous round are joined with `rel` again. Pairs which are already
+    /// known are discarded which guarantees termination in the presence of cycles.
+    ///
+    /// If `reflexive` is set every node of `rel` is additionally related to itself.
+    fn closure(&self, rel: &StrRelation<'a>, reflexive: bool) -> StrRelation<'a> {
+        let (mut base, nodes) = self.to_fields(rel);
+        base.par_sort_unstable();
+        base.dedup();
+
+        let mut known: HashSet<(Field, Field)> = base.iter().copied().collect();
+        let mut delta = base.clone();
+        let mut depth = 1;
+        while !delta.is_empty() && self.max_depth.is_none_or(|max| depth < max) {
+            eprintln!(
+                "++ [closure] extending {} paths of length {}",
+                delta.len(),
+                depth
+            );
+            let mut found = self.extend_paths(mem::take(&mut delta), &base);
+            found.par_sort_unstable();
+            found.dedup();
+            delta = found
+                .into_iter()
+                .filter(|&pair| known.insert(pair))
+                .collect();
+            depth += 1;
+        }
+
+        if self.max_depth == Some(0) {
+            known.clear();
+        }
+        if reflexive {
+            known.extend(nodes.into_iter().map(|f| (f, f)));
+        }
+
+        eprintln!("++ [closure] {} pairs", known.len());
+        self.to_strs(known)
+    }
+
+    /// Computes the union of `rel` repeated `min` to `max` times. Each length is derived from the
+    /// paths of the previous length, with duplicates retained as if the chains were written out.
+    fn repeat(&self, rel: &StrRelation<'a>, min: usize, max: usize) -> StrRelation<'a> {
+        let (base, nodes) = self.to_fields(rel);
+
+        let mut result = Relation::new();
+        if min == 0 {
+            result.extend(nodes.into_iter().map(|f| (f, f)));
+        }
+
+        let mut paths = base.clone();
+        for length in 1..=max {
+            if length > 1 {
+                eprintln!(
+                    "++ [repeat] extending {} paths of length {}",
+                    paths.len(),
+                    length - 1
+                );
+                paths = self.extend_paths(paths, &base);
+            }
+            if paths.is_empty() {
+                break;
+            }
+            if length >= min {
+                result.extend_from_slice(&paths);
+            }
+        }
+
+        eprintln!("++ [repeat] {} pairs", result.len());
+        self.to_strs(result)
+    }
+
+    /// Translates `rel` into fields such that equal nodes share a single field, no matter if they
+    /// appear as subject or object. Also returns the set of all nodes.
+    fn to_fields(&self, rel: &StrRelation<'a>) -> (Relation, Vec<Field>) {
+        let mut dictionary = HashMap::new();
+        let mut field_of = |s: input::Str<'a>| {
+            *dictionary
+                .entry(s)
+                .or_insert_with(|| self.input.extract_field(s))
+        };
+        let fields = rel
+            .iter()
+            .map(|&(subj, obj)| (field_of(subj), field_of(obj)))
+            .collect();
+        (fields, dictionary.into_values().collect())
+    }
+
+    fn to_strs(&self, rel: impl IntoIterator<Item = (Field, Field)>) -> StrRelation<'a> {
+        rel.into_iter()
+            .map(|(subj, obj)| (self.input.extract_str(subj), self.input.extract_str(obj)))
+            .collect()
+    }
+
+    /// Appends `base` to every path in `paths` using the configured join algorithm. Only the end
+    /// points of the resulting paths are returned.
+    fn extend_paths(&self, paths: Relation, base: &Relation) -> Relation {
+        let settings = Settings { width: 3 };
+        let mut algo = (self.new_algo)();
+        let mut step = JoinStep {
+            index: 0,
+            key: 0,
+            column: Some(1),
+            object_key: None,
+            mode: JoinMode::Inner,
+            limit: None,
+        };
+        algo.join(&settings, &step, paths);
+        step.index = 1;
+        step.key = 1;
+        step.column = Some(2);
+        algo.join(&settings, &step, base.clone());
+        algo.results().map(|row| (row[0], row[2])).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+
+    use crate::join::sort_merge;
+
+    use super::*;
+
+    const TRIPLES: &str = "<a> <p> <b> .\n<a> <q> \"c d\" .\n<b> <p> \"c d\" .\n";
+
+    /// A cycle through `<a>`, `<b>` and `<c>` with an exit to `<d>`.
+    const CYCLE: &str = "<a> <p> <b> .\n<b> <p> <c> .\n<c> <p> <a> .\n<c> <p> <d> .\n";
+
+    /// Two paths of length two from `<a>` to `<d>`, and one of `<q>` from `<a>` to `<b>`.
+    const DIAMOND: &str = "<a> <p> <b> .\n<a> <p> <c> .\n<b> <p> <d> .\n<c> <p> <d> .\n\
+                           <a> <q> <b> .\n";
+
+    /// Evaluates `path` on `TRIPLES`, which do not make up any named relations.
+    fn evaluate(path: &Path) -> Vec<(String, String)> {
+        let input = Input::from_text(TRIPLES);
+        let universe = Universe::new();
+        evaluate_with(&input, &universe, path, None, false)
+    }
+
+    fn evaluate_with(
+        input: &Input,
+        universe: &Universe,
+        path: &Path,
+        max_depth: Option<usize>,
+        dedup_alternatives: bool,
+    ) -> Vec<(String, String)> {
+        let evaluator = Evaluator {
+            input,
+            universe,
+            new_algo: &|| Box::new(sort_merge::Impl::new(false)),
+            max_depth,
+            dedup_alternatives,
+        };
+        evaluator
+            .evaluate(path)
+            .iter()
+            .map(|(subj, obj)| (subj.to_string(), obj.to_string()))
+            .collect()
+    }
+
+    /// Evaluates `path` on `triples` with every predicate making up a relation. The pairs are
+    /// sorted.
+    fn evaluate_on(
+        triples: &str,
+        path: &Path,
+        max_depth: Option<usize>,
+        dedup_alternatives: bool,
+    ) -> Vec<(String, String)> {
+        let input = Input::from_text(triples);
+        let universe = input
+            .iter_lines()
+            .map(|line| line.parse())
+            .map(|(subj, pred, obj)| (pred, (subj, obj)))
+            .into_group_map();
+        let mut pairs = evaluate_with(&input, &universe, path, max_depth, dedup_alternatives);
+        pairs.sort();
+        pairs
+    }
+
+    fn relation(name: &str) -> Box<Path> {
+        Box::new(Path::Relation(name.to_owned()))
+    }
+
+    /// The pairs of `nodes` with themselves.
+    fn reflexive(nodes: &[&'static str]) -> Vec<(&'static str, &'static str)> {
+        nodes.iter().map(|&n| (n, n)).collect()
+    }
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|&(s, o)| (s.to_owned(), o.to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn any_predicate() {
+        assert_eq!(
+            evaluate(&Path::Any),
+            pairs(&[("<a>", "<b>"), ("<a>", "\"c d\""), ("<b>", "\"c d\"")])
+        );
+    }
+
+    #[test]
+    fn parts_of_triples() {
+        let lines = TRIPLES.lines().collect::<Vec<_>>();
+        assert_eq!(
+            evaluate(&Path::Triples(None)),
+            pairs(&[("<a>", lines[0]), ("<a>", lines[1]), ("<b>", lines[2])])
+        );
+        assert_eq!(
+            evaluate(&Path::Triples(Some("\"c d\"".to_owned()))),
+            pairs(&[("<a>", lines[1]), ("<b>", lines[2])])
+        );
+        assert_eq!(
+            evaluate(&Path::TriplePart(TriplePart::Predicate)),
+            pairs(&[(lines[0], "<p>"), (lines[1], "<q>"), (lines[2], "<p>")])
+        );
+        assert_eq!(
+            evaluate(&Path::TriplePart(TriplePart::Object)),
+            pairs(&[
+                (lines[0], "<b>"),
+                (lines[1], "\"c d\""),
+                (lines[2], "\"c d\"")
+            ])
+        );
+    }
+
+    fn sorted(entries: &[(&str, &str)]) -> Vec<(String, String)> {
+        let mut sorted = pairs(entries);
+        sorted.sort();
+        sorted
+    }
+
+    #[test]
+    fn closure_terminates_on_cycles() {
+        let nodes = ["<a>", "<b>", "<c>", "<d>"];
+        let reachable = nodes[..3]
+            .iter()
+            .cartesian_product(nodes)
+            .map(|(&s, o)| (s, o))
+            .collect_vec();
+        let plus = Path::OneOrMore(relation("<p>"));
+        assert_eq!(evaluate_on(CYCLE, &plus, None, false), sorted(&reachable));
+        let star = Path::ZeroOrMore(relation("<p>"));
+        let with_d = [reachable, vec![("<d>", "<d>")]].concat();
+        assert_eq!(evaluate_on(CYCLE, &star, None, false), sorted(&with_d));
+    }
+
+    #[test]
+    fn star_relates_every_node_to_itself() {
+        let star = Path::ZeroOrMore(relation("<p>"));
+        let expected = [
+            reflexive(&["<a>", "<b>", "\"c d\""]),
+            vec![("<a>", "<b>"), ("<b>", "\"c d\""), ("<a>", "\"c d\"")],
+        ]
+        .concat();
+        assert_eq!(evaluate_on(TRIPLES, &star, None, false), sorted(&expected));
+        // Only the nodes of the relation itself, not those of other predicates.
+        let star = Path::ZeroOrMore(relation("<q>"));
+        let expected = [reflexive(&["<a>", "\"c d\""]), vec![("<a>", "\"c d\"")]].concat();
+        assert_eq!(evaluate_on(TRIPLES, &star, None, false), sorted(&expected));
+    }
+
+    #[test]
+    fn max_depth_limits_the_closure() {
+        let plus = Path::OneOrMore(relation("<p>"));
+        let star = Path::ZeroOrMore(relation("<p>"));
+        let nodes = reflexive(&["<a>", "<b>", "<c>", "<d>"]);
+        let base = vec![
+            ("<a>", "<b>"),
+            ("<b>", "<c>"),
+            ("<c>", "<a>"),
+            ("<c>", "<d>"),
+        ];
+        assert_eq!(evaluate_on(CYCLE, &plus, Some(0), false), []);
+        assert_eq!(evaluate_on(CYCLE, &star, Some(0), false), sorted(&nodes));
+        assert_eq!(evaluate_on(CYCLE, &plus, Some(1), false), sorted(&base));
+        assert_eq!(
+            evaluate_on(CYCLE, &star, Some(1), false),
+            sorted(&[&nodes[..], &base].concat())
+        );
+        let two = [
+            ("<a>", "<c>"),
+            ("<b>", "<a>"),
+            ("<b>", "<d>"),
+            ("<c>", "<b>"),
+        ];
+        assert_eq!(
+            evaluate_on(CYCLE, &plus, Some(2), false),
+            sorted(&[&base[..], &two].concat())
+        );
+    }
+
+    #[test]
+    fn repeat_keeps_duplicate_paths() {
+        let repeat = |min, max| Path::Repeat(relation("<p>"), min, max);
+        let base = [
+            ("<a>", "<b>"),
+            ("<a>", "<c>"),
+            ("<b>", "<d>"),
+            ("<c>", "<d>"),
+        ];
+        let twice = [("<a>", "<d>"), ("<a>", "<d>")];
+        assert_eq!(
+            evaluate_on(DIAMOND, &repeat(2, 2), None, false),
+            sorted(&twice)
+        );
+        assert_eq!(
+            evaluate_on(DIAMOND, &repeat(1, 3), None, false),
+            sorted(&[&base[..], &twice].concat())
+        );
+        let nodes = reflexive(&["<a>", "<b>", "<c>", "<d>"]);
+        assert_eq!(
+            evaluate_on(DIAMOND, &repeat(0, 1), None, false),
+            sorted(&[&nodes[..], &base].concat())
+        );
+        assert_eq!(evaluate_on(DIAMOND, &repeat(3, 5), None, false), []);
+    }
+
+    #[test]
+    fn inverse_swaps_subjects_and_objects() {
+        let inverse = Path::Inverse(relation("<p>"));
+        let expected = [
+            ("<b>", "<a>"),
+            ("<c>", "<a>"),
+            ("<d>", "<b>"),
+            ("<d>", "<c>"),
+        ];
+        assert_eq!(
+            evaluate_on(DIAMOND, &inverse, None, false),
+            sorted(&expected)
+        );
+        let double = Path::Inverse(Box::new(Path::Inverse(relation("<p>"))));
+        let plain = Path::Relation("<p>".to_owned());
+        assert_eq!(
+            evaluate_on(DIAMOND, &double, None, false),
+            evaluate_on(DIAMOND, &plain, None, false)
+        );
+        let inverse = Path::Inverse(Box::new(Path::OneOrMore(relation("<p>"))));
+        let expected = [&expected[..], &[("<d>", "<a>")]].concat();
+        assert_eq!(
+            evaluate_on(DIAMOND, &inverse, None, false),
+            sorted(&expected)
+        );
+    }
+
+    #[test]
+    fn alternatives_keep_duplicates_unless_asked() {
+        let alternative = Path::Alternative(vec![
+            Path::Relation("<q>".to_owned()),
+            Path::Relation("<p>".to_owned()),
+        ]);
+        // Both <p> and <q> relate <a> to <b>.
+        let a_to_b = |pairs: Vec<(String, String)>| {
+            pairs
+                .into_iter()
+                .filter(|(s, o)| s == "<a>" && o == "<b>")
+                .count()
+        };
+        assert_eq!(a_to_b(evaluate_on(DIAMOND, &alternative, None, false)), 2);
+        assert_eq!(a_to_b(evaluate_on(DIAMOND, &alternative, None, true)), 1);
+        assert_eq!(evaluate_on(DIAMOND, &alternative, None, true).len(), 4);
+    }
+}
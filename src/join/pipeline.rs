@@ -1,158 +1,332 @@
-use std::{cell::Cell, collections::HashMap, fmt, iter, mem};
+use std::{
+    borrow::Cow,
+    cell::Cell,
+    collections::{HashMap, HashSet},
+    fmt,
+    iter::{self, zip},
+};
 
 use anyhow::bail;
 use itertools::Itertools;
-use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
+use rayon::iter::{
+    IndexedParallelIterator, IntoParallelIterator, IntoParallelRefIterator, ParallelIterator,
+};
 
 use crate::{
-    input::{self, Field, Input},
-    relation::{Relation, StrRelation, Universe},
+    input::{self, Field, Input, Terms},
+    query::{Chain, Path, Step},
+    relation::{Relation, StrRelation},
 };
 
+use super::path::Evaluator;
+
 pub struct Pipeline {
     pub relations: Vec<Relation>,
-    pub ranges: Vec<(Field, Field)>,
+    /// The rows of the seeds of the chain, if it has any.
+    pub seeds: Option<Vec<Vec<Field>>>,
 }
 
 impl Pipeline {
+    /// Evaluates the relations of the steps of `chain`. The values of the seeds are added to
+    /// `terms`.
     pub fn build<'a>(
         input: &'a Input,
-        universe: &Universe<'a>,
-        relation_names: &[String],
+        paths: &Evaluator<'_, 'a>,
+        chain: &'a Chain,
+        terms: &mut Terms,
     ) -> anyhow::Result<Self> {
-        // Resolve relation names or collect all unknown names before aborting.
-        let rels_or_errs: Validation<Vec<&StrRelation>, Vec<&String>> = relation_names
+        let steps = &chain.steps[..];
+        let seeds = chain.seeds.as_ref();
+        // Without seeds the first step provides the initial rows, otherwise every step is joined.
+        let joined = if seeds.is_some() { 0 } else { 1 };
+        // Make sure all relations are known or collect all unknown names before aborting.
+        let known_or_errs: Validation<(), Vec<&String>> = steps
             .iter()
-            .map(|name| match universe.get(&input::Str::new(name)) {
-                Some(r) => Validation::Valid(r),
-                None => Validation::Invalid(name),
+            .flat_map(|step| step.path.relations())
+            .map(|name| {
+                if paths.universe.contains_key(&input::Str::new(name)) {
+                    Validation::Valid(())
+                } else {
+                    Validation::Invalid(name)
+                }
             })
             .collect();
-        let rels = match rels_or_errs {
-            Validation::Valid(rels) => rels,
-            Validation::Invalid(unknown) => {
-                let n = unknown.len();
-                bail!(
-                    "unknown {}: {}",
-                    if n == 1 { "relation" } else { "relations" },
-                    unknown
-                        .into_iter()
-                        .enumerate()
-                        .flat_map(|(i, s)| if i == 0 {
-                            ["", s]
-                        } else if i + 1 == n {
-                            [", and ", s]
-                        } else {
-                            [", ", s]
-                        })
-                        .collect_display()
-                )
+        if let Validation::Invalid(unknown) = known_or_errs {
+            let n = unknown.len();
+            bail!(
+                "unknown {}: {}",
+                if n == 1 { "relation" } else { "relations" },
+                unknown
+                    .into_iter()
+                    .enumerate()
+                    .flat_map(|(i, s)| if i == 0 {
+                        ["", s]
+                    } else if i + 1 == n {
+                        [", and ", s]
+                    } else {
+                        [", ", s]
+                    })
+                    .collect_display()
+            )
+        }
+
+        // Evaluate the paths to plain relations.
+        let mut rels = Vec::new();
+        steps
+            .par_iter()
+            .map(|step| match step.path {
+                Path::TableRows(table, column) => {
+                    let table = &chain.tables[table];
+                    let rel = zip(&table.bindings.rows, &table.ids).filter_map(|(row, id)| {
+                        let value = input::Str::new(row[column].as_deref()?);
+                        Some((value, input::Str::new(id)))
+                    });
+                    Cow::Owned(rel.collect())
+                }
+                Path::TableColumn(table, column) => {
+                    let table = &chain.tables[table];
+                    let rel = zip(&table.bindings.rows, &table.ids).filter_map(|(row, id)| {
+                        let value = input::Str::new(row[column].as_deref()?);
+                        Some((input::Str::new(id), value))
+                    });
+                    Cow::Owned(rel.collect())
+                }
+                _ => paths.evaluate(&step.path),
+            })
+            .collect_into_vec(&mut rels);
+
+        // Seeds can make up the whole result.
+        if rels.is_empty() && seeds.is_none() {
+            bail!("no relations to join");
+        }
+
+        // Find the step producing each column. Column 0 holds the subjects of the first relation
+        // unless there are seeds.
+        let width = chain.width();
+        let producers = chain.producers();
+
+        // A zero-length path matches every value of the key column, not only the nodes of the
+        // path itself. The first step has no key column, its path matches the constant subject
+        // or object with itself instead. This happens before the constants are pushed down.
+        let seed_width = seeds.map_or(0, |seeds| seeds.variables.len());
+        let mut constants = Vec::new();
+        for (i, step) in steps.iter().enumerate() {
+            if step.path.matches_empty() {
+                let key = step.key;
+                let (prev, this) = rels.split_at_mut(i);
+                let nodes: HashSet<_> = this[0].iter().map(|&(subj, _)| subj).collect();
+                let missing = if i < joined {
+                    let ends = step.subject.iter().chain(&step.object).collect_vec();
+                    constants.extend(ends.iter().copied());
+                    ends.into_iter().map(|c| input::Str::new(c)).collect_vec()
+                } else if let Some(seeds) = seeds.filter(|_| key < seed_width) {
+                    seeds
+                        .rows
+                        .iter()
+                        .filter_map(|row| row[key].as_deref())
+                        .map(input::Str::new)
+                        .collect_vec()
+                } else if key == 0 {
+                    prev[0].iter().map(|&(subj, _)| subj).collect_vec()
+                } else {
+                    prev[producers[key]]
+                        .iter()
+                        .map(|&(_, obj)| obj)
+                        .collect_vec()
+                };
+                let missing = missing
+                    .into_iter()
+                    .filter(|node| !nodes.contains(node))
+                    .unique()
+                    .map(|node| (node, node))
+                    .collect_vec();
+                this[0].to_mut().extend(missing);
             }
-        };
+        }
 
-        if rels.len() < 2 {
-            bail!("no join to be performed");
+        // Push constant subjects and objects down so that only the matching entries take part in
+        // the join.
+        let rels = rels
+            .into_iter()
+            .zip(steps)
+            .map(|(rel, step)| Self::restrict(rel, step))
+            .collect_vec();
+
+        // Columns matched against by a later step need a dictionary which maps every value to a
+        // single field.
+        let mut is_key = vec![false; width];
+        for step in &steps[joined..] {
+            is_key[step.key] = true;
+            if let Some(c) = step.object_key {
+                is_key[c] = true;
+            }
         }
 
-        // For each relation (except the last) build a map from properties to offset fields.
+        // The seeds are stored as computed terms, each distinct value once. Equal values in the
+        // relations, e.g. the seeds matched by a zero-length path, get the same fields.
+        let mut seed_fields = HashMap::new();
+        let seeds = seeds.map(|seeds| {
+            seeds
+                .rows
+                .iter()
+                .map(|row| {
+                    let mut fields = vec![Field::INVALID; width];
+                    for (field, value) in fields.iter_mut().zip(row) {
+                        if let Some(value) = value {
+                            *field = *seed_fields
+                                .entry(input::Str::new(value))
+                                .or_insert_with(|| terms.add(value.clone()));
+                        }
+                    }
+                    fields
+                })
+                .collect_vec()
+        });
+        // The same goes for the subquery tables and the identifiers of their rows, and for the
+        // constants matched by a zero-length path.
+        for table in &chain.tables {
+            let values = table.bindings.rows.iter().flatten().flatten();
+            for value in values.chain(&table.ids) {
+                seed_fields
+                    .entry(input::Str::new(value))
+                    .or_insert_with(|| terms.add(value.clone()));
+            }
+        }
+        for value in constants {
+            seed_fields
+                .entry(input::Str::new(value))
+                .or_insert_with(|| terms.add(value.clone()));
+        }
+        let extract = |s| match seed_fields.get(&s) {
+            Some(&field) => field,
+            None => input.extract_field(s),
+        };
+
+        // Translate the objects of each relation into fields, building the dictionaries for the
+        // key columns on the way.
         let mut mapped_objs = Vec::new();
-        rels[..rels.len() - 1]
-            .par_iter()
-            .map(|rel_ref| {
-                let mut map = HashMap::new();
+        rels.par_iter()
+            .zip(steps)
+            .enumerate()
+            .map(|(i, (rel_ref, step))| {
+                if step.object_key.is_some() {
+                    // Both ends are resolved with the dictionaries later on.
+                    return (Vec::new(), None, None);
+                }
+                let mut subj_dict = (i == 0 && joined == 1 && is_key[0]).then(HashMap::new);
+                let mut obj_dict = step.column.filter(|&c| is_key[c]).map(|_| HashMap::new());
                 let field_rel = rel_ref
                     .iter()
-                    .map(|(subj, obj)| {
-                        let obj_field = map
-                            .entry(*obj)
-                            .or_insert_with(|| input.extract_field(*subj));
-                        (*subj, *obj_field)
+                    .map(|&(subj, obj)| {
+                        if let Some(dict) = &mut subj_dict {
+                            Self::field(extract, dict, subj);
+                        }
+                        let obj_field = match &mut obj_dict {
+                            Some(dict) => Self::field(extract, dict, obj),
+                            None => extract(obj),
+                        };
+                        (subj, obj_field)
                     })
                     .collect_vec();
-                (field_rel, map)
+                (field_rel, subj_dict, obj_dict)
             })
             .collect_into_vec(&mut mapped_objs);
 
-        let mut mapped_rels = vec![Relation::default(); rels.len()];
-        let mut field_ranges = vec![(Field::INVALID, Field::INVALID); rels.len()];
-        let mut out_rels = mapped_rels.iter_mut();
-        let mut out_ranges = field_ranges.iter_mut();
-
-        rayon::in_place_scope(|scope| {
-            let mut iter = mapped_objs.into_iter();
-            let (initial_table, initial_dict) = iter.next().expect("mapped_objs too short");
-
-            // Translate the first column into fields. Skip over the first out_range.
-            _ = out_ranges.next().expect("field_ranges too short");
-            let out_fst = out_rels.next().expect("mapped_rels too short");
-            scope.spawn(|_| {
-                *out_fst = initial_table
-                    .into_iter()
-                    .map(|(subj, obj_f)| (input.extract_field(subj), obj_f))
-                    .collect_vec();
-            });
-
-            // Grab a reference to the last slots so that it will not get consumed by the `zip`
-            // below.
-            let out_last_rel = out_rels.next_back().expect("mapped_rels too short");
-            let out_last_range = out_ranges.next_back().expect("field_ranges too short");
-
-            // Align the middle tables with the preceeding dictionary to resolve the subject
-            // columns.
-            let mut current_dict = initial_dict;
-            let zipped = iter.zip_longest(out_rels).zip_longest(out_ranges);
-            for z in zipped {
-                let (z, range_out) = z.both().expect("vectors too short");
-                let ((table, next_dict), rel_out) = z.both().expect("vectors too short");
-
-                let this_dict = mem::replace(&mut current_dict, next_dict);
-                scope.spawn(move |_| {
-                    Self::resolve(rel_out, range_out, this_dict, table.into_iter());
-                });
+        let mut tables = Vec::with_capacity(rels.len());
+        let mut dictionaries = Vec::new();
+        dictionaries.resize_with(width, || None);
+        for (i, (table, subj_dict, obj_dict)) in mapped_objs.into_iter().enumerate() {
+            if i == 0 && joined == 1 {
+                dictionaries[0] = subj_dict;
             }
+            if let Some(column) = steps[i].column {
+                dictionaries[column] = obj_dict;
+            }
+            tables.push(table);
+        }
 
-            // Resolve the last table which is not included in mapped_objs.
-            scope.spawn(move |_| {
-                let iter = rels
-                    .last()
-                    .expect("`rels.len() > 2` ensured above")
-                    .iter()
-                    .map(|&(subj, obj)| (subj, input.extract_field(obj)));
-                Self::resolve(out_last_rel, out_last_range, current_dict, iter);
-            });
-        });
+        for column in (0..seed_width).filter(|&c| is_key[c]) {
+            dictionaries[column] = Some(seed_fields.clone());
+        }
 
-        Ok(Pipeline {
-            relations: mapped_rels,
-            ranges: field_ranges,
-        })
+        // Resolve the subjects of each table with the dictionary of its key column. The subjects
+        // of the first table form the first column. The objects matched against a column are
+        // resolved with its dictionary as well.
+        let mut relations = Vec::new();
+        tables
+            .into_par_iter()
+            .zip(steps)
+            .enumerate()
+            .map(|(i, (table, step))| {
+                if let Some(c) = step.object_key {
+                    let subjects = dictionaries[step.key]
+                        .as_ref()
+                        .expect("dictionary for key column");
+                    let objects = dictionaries[c]
+                        .as_ref()
+                        .expect("dictionary for object key column");
+                    let table = rels[i]
+                        .iter()
+                        .filter_map(|(subj, obj)| Some((*subj, *objects.get(obj)?)))
+                        .collect_vec();
+                    Self::resolve(subjects, table)
+                } else if i == 0 && joined == 1 {
+                    table
+                        .into_iter()
+                        .map(|(subj, obj_f)| match &dictionaries[0] {
+                            Some(dict) => (dict[&subj], obj_f),
+                            None => (extract(subj), obj_f),
+                        })
+                        .collect_vec()
+                } else {
+                    let dict = dictionaries[step.key]
+                        .as_ref()
+                        .expect("dictionary for key column");
+                    Self::resolve(dict, table)
+                }
+            })
+            .collect_into_vec(&mut relations);
+
+        Ok(Pipeline { relations, seeds })
     }
 
-    fn resolve<'a>(
-        rel_out: &mut Relation,
-        range_out: &mut (Field, Field),
-        dictionary: HashMap<input::Str<'a>, Field>,
-        iter: impl IntoIterator<Item = (input::Str<'a>, Field)> + ExactSizeIterator,
-    ) {
-        rel_out.reserve(iter.len());
-
-        let mut first = true;
-        for (subj, obj_f) in iter {
-            let subj_f = if let Some(&subj_f) = dictionary.get(&subj) {
-                rel_out.push((subj_f, obj_f));
-                subj_f
-            } else {
-                continue;
-            };
+    /// Looks up the field for `s` in `dictionary`, adding it if necessary.
+    fn field<'a>(
+        extract: impl Fn(input::Str<'a>) -> Field,
+        dictionary: &mut HashMap<input::Str<'a>, Field>,
+        s: input::Str<'a>,
+    ) -> Field {
+        *dictionary.entry(s).or_insert_with(|| extract(s))
+    }
 
-            if first {
-                *range_out = (subj_f, subj_f);
-                first = false;
-            } else {
-                range_out.0 = range_out.0.min(subj_f);
-                range_out.1 = range_out.1.max(subj_f);
-            }
+    /// Filters `rel` down to the entries matching the constants of `step`. The relation is only
+    /// copied if there is anything to filter.
+    fn restrict<'u, 'a>(rel: Cow<'u, StrRelation<'a>>, step: &Step) -> Cow<'u, StrRelation<'a>> {
+        if step.subject.is_none() && step.object.is_none() {
+            return rel;
         }
+
+        let subject = step.subject.as_deref().map(input::Str::new);
+        let object = step.object.as_deref().map(input::Str::new);
+        let matches = |c: Option<input::Str>, s: &input::Str| c.is_none_or(|c| c == *s);
+        Cow::Owned(
+            rel.par_iter()
+                .filter(|(subj, obj)| matches(subject, subj) && matches(object, obj))
+                .copied()
+                .collect(),
+        )
+    }
+
+    /// Translates the subjects of `table` into fields. Entries with a subject missing from
+    /// `dictionary` cannot match and are dropped.
+    fn resolve<'a>(
+        dictionary: &HashMap<input::Str<'a>, Field>,
+        table: Vec<(input::Str<'a>, Field)>,
+    ) -> Relation {
+        table
+            .into_iter()
+            .filter_map(|(subj, obj_f)| Some((*dictionary.get(&subj)?, obj_f)))
+            .collect()
     }
 }
 
@@ -297,3 +471,168 @@ where
     I::Item: fmt::Display,
 {
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::query::{Chain, JoinMode, Path};
+
+    use super::*;
+
+    fn step(subject: Option<&str>, object: Option<&str>) -> Step {
+        Step {
+            subject: subject.map(str::to_owned),
+            object: object.map(str::to_owned),
+            ..Step::new(
+                Path::Relation("<p>".to_owned()),
+                JoinMode::Inner,
+                0,
+                Some(1),
+            )
+        }
+    }
+
+    fn relation<'a>(entries: &[(&'a str, &'a str)]) -> StrRelation<'a> {
+        entries
+            .iter()
+            .map(|&(s, o)| (input::Str::new(s), input::Str::new(o)))
+            .collect()
+    }
+
+    fn strings(rel: &StrRelation) -> Vec<(String, String)> {
+        rel.iter()
+            .map(|(s, o)| (s.to_string(), o.to_string()))
+            .collect()
+    }
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|&(s, o)| (s.to_owned(), o.to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn constants_restrict_relations() {
+        let rel = relation(&[("<a>", "<b>"), ("<a>", "<c>"), ("<b>", "<c>")]);
+        let restrict = |subject, object| {
+            strings(&Pipeline::restrict(
+                Cow::Borrowed(&rel),
+                &step(subject, object),
+            ))
+        };
+        assert_eq!(restrict(None, None).len(), 3);
+        assert_eq!(
+            restrict(Some("<a>"), None),
+            pairs(&[("<a>", "<b>"), ("<a>", "<c>")])
+        );
+        assert_eq!(
+            restrict(None, Some("<c>")),
+            pairs(&[("<a>", "<c>"), ("<b>", "<c>")])
+        );
+        assert_eq!(restrict(Some("<b>"), Some("<c>")), pairs(&[("<b>", "<c>")]));
+        // Constants matching no entry leave nothing to join.
+        assert_eq!(restrict(Some("<c>"), None), []);
+        assert_eq!(restrict(None, Some("<a>")), []);
+        assert_eq!(restrict(Some("<a>"), Some("<a>")), []);
+    }
+
+    /// Builds the pipeline for the chain `args` on `triples` and returns the entries of its
+    /// relations.
+    fn build(
+        triples: &str,
+        args: &[&str],
+        subject: Option<&str>,
+        object: Option<&str>,
+    ) -> Vec<Vec<(String, String)>> {
+        let input = Input::from_text(triples);
+        let universe = input
+            .iter_lines()
+            .map(|line| line.parse())
+            .map(|(subj, pred, obj)| (pred, (subj, obj)))
+            .into_group_map();
+        let evaluator = Evaluator {
+            input: &input,
+            universe: &universe,
+            new_algo: &|| Box::new(crate::join::sort_merge::Impl::new(false)),
+            max_depth: None,
+            dedup_alternatives: false,
+        };
+        let chain = Chain::from_relations(
+            &args.iter().map(|&arg| arg.to_owned()).collect_vec(),
+            subject.map(str::to_owned).as_ref(),
+            object.map(str::to_owned).as_ref(),
+            None,
+        )
+        .unwrap();
+        let mut terms = Terms::new(&input);
+        let pipeline = Pipeline::build(&input, &evaluator, &chain, &mut terms).unwrap();
+        pipeline
+            .relations
+            .iter()
+            .map(|rel| {
+                rel.iter()
+                    .map(|&(s, o)| {
+                        (
+                            terms.extract_str(s).to_string(),
+                            terms.extract_str(o).to_string(),
+                        )
+                    })
+                    .sorted()
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn middle_columns_hold_objects() {
+        let triples = "<a> <p> <b> .\n<b> <q> <c> .\n";
+        assert_eq!(
+            build(triples, &["<p>", "<q>"], None, None),
+            [pairs(&[("<a>", "<b>")]), pairs(&[("<b>", "<c>")])]
+        );
+    }
+
+    #[test]
+    fn inverse_relations_in_chains() {
+        let triples = "<a> <p> <b> .\n<a> <p> <c> .\n<d> <q> <c> .\n";
+        // Constants apply to the inverse relation, i.e. the subject to the original objects.
+        assert_eq!(
+            build(triples, &["<p>", "^<q>"], None, Some("<d>")),
+            [
+                pairs(&[("<a>", "<b>"), ("<a>", "<c>")]),
+                pairs(&[("<c>", "<d>")])
+            ]
+        );
+        assert_eq!(
+            build(triples, &["<q>", "^<p>"], None, None),
+            [pairs(&[("<d>", "<c>")]), pairs(&[("<c>", "<a>")])]
+        );
+    }
+
+    #[test]
+    fn zero_length_paths_of_constants() {
+        let triples = "<a> <p> <b> .\n<e> <q> <f> .\n";
+        // `<e>` is not part of `<p>` but still related to itself by `<p>*`.
+        assert_eq!(
+            build(triples, &["<p>*", "<q>"], Some("<e>"), None),
+            [pairs(&[("<e>", "<e>")]), pairs(&[("<e>", "<f>")])]
+        );
+        assert_eq!(
+            build(triples, &["<q>", "<p>*"], None, Some("<f>")),
+            [pairs(&[("<e>", "<f>")]), pairs(&[("<f>", "<f>")])]
+        );
+        assert_eq!(
+            build(triples, &["<p>{0,1}", "<q>"], Some("<e>"), None),
+            [pairs(&[("<e>", "<e>")]), pairs(&[("<e>", "<f>")])]
+        );
+        // Even constants which do not occur in the input at all.
+        assert_eq!(
+            build(triples, &["<p>*", "<q>"], Some("<x>"), None),
+            [pairs(&[("<x>", "<x>")]), vec![]]
+        );
+        assert_eq!(
+            build(triples, &["<p>*"], Some("<x>"), Some("<x>")),
+            [pairs(&[("<x>", "<x>")])]
+        );
+    }
+}
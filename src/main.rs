@@ -8,12 +8,13 @@ mod indented;
 mod input;
 mod join;
 mod partial_eq;
+mod query;
 mod relation;
 
 use crate::indented::{indented, indented_by};
 use crate::input::Input;
 use anyhow::{Context, Result};
-use clap::Parser;
+use clap::{ArgEnum, Parser};
 use itertools::{repeat_n, Itertools};
 use lazy_static::lazy_static;
 use std::ffi::OsStr;
@@ -22,16 +23,106 @@ use std::process::exit;
 use std::thread::available_parallelism;
 use std::{env, io};
 
+/// The output formats of the join results.
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Table,
+    Tsv,
+    Csv,
+}
+
+/// The input of a hash join step the hash table is built from.
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BuildSide {
+    /// The smaller one.
+    Auto,
+    /// The rows joined so far.
+    Left,
+    /// The entries of the relation joined in the step.
+    Right,
+}
+
 #[derive(Parser, Debug)]
 pub struct Args {
     /// File path to read input from. This must be an actual file as it will be memory mapped.
     #[clap(name = "FILE")]
     input: std::path::PathBuf,
 
-    /// Relations to join, in order.
+    /// Relations to join, in order. ‘?’ matches any predicate and adds a column for the
+    /// predicates.
     #[clap(name = "RELATION")]
     relations: Vec<String>,
 
+    /// Only consider entries of the first relation with subject <SUBJECT>.
+    #[clap(long, name = "SUBJECT")]
+    subject: Option<String>,
+
+    /// Only consider entries of the last relation with object <OBJECT>.
+    #[clap(long, name = "OBJECT")]
+    object: Option<String>,
+
+    /// Maximum length of the paths matched by ‘p+’ and ‘p*’. Unlimited by default.
+    #[clap(long, name = "DEPTH")]
+    max_depth: Option<usize>,
+
+    /// Remove duplicate entries when combining the relations of an alternative ‘p|q’.
+    #[clap(long)]
+    dedup_alternatives: bool,
+
+    /// Start from the rows of a TSV file instead of the entries of the first relation. The header
+    /// names the variables like ‘?x’, empty cells are unbound. The relations are joined with the
+    /// first column, queries with the variables of the same name.
+    #[clap(long, name = "BINDINGS")]
+    bindings: Option<std::path::PathBuf>,
+
+    /// Run <QUERY> instead of joining the given relations. Every triple pattern has to be
+    /// connected to the preceding ones, e.g. ‘SELECT * WHERE { <alice> <knows> ?x . ?x <knows> ?y }’.
+    /// OPTIONAL, MINUS and FILTER [NOT] EXISTS take a single triple pattern each, UNION is only
+    /// supported for the whole WHERE pattern as in ‘{ ... } UNION { ... }’.
+    #[clap(short, long, name = "QUERY")]
+    query: Option<String>,
+
+    /// Only check whether there are any results. Prints ‘true’ or ‘false’ and exits with status
+    /// 1 if there are none.
+    #[clap(long)]
+    ask: bool,
+
+    /// Remove duplicate results.
+    #[clap(long)]
+    distinct: bool,
+
+    /// Only output the given columns: a comma separated list of positions starting at ‘0’ or
+    /// variables like ‘?x’.
+    #[clap(long, name = "COLUMNS")]
+    select: Option<String>,
+
+    /// Sort the results by the given columns: a comma separated list of positions or variables,
+    /// each optionally wrapped in ‘asc(...)’ or ‘desc(...)’.
+    #[clap(long, name = "KEYS")]
+    order_by: Option<String>,
+
+    /// Only return the first N results. The last join step stops once it has produced enough
+    /// rows unless the results are sorted, deduplicated or filtered afterwards.
+    #[clap(long, name = "LIMIT")]
+    limit: Option<usize>,
+
+    /// Skip the first N results.
+    #[clap(long, name = "OFFSET")]
+    offset: Option<usize>,
+
+    /// How to output the results: ‘table’ prints the first results in a table, ‘tsv’ and ‘csv’
+    /// write all of them in the SPARQL result formats, headed by the variable names.
+    #[clap(long, arg_enum, name = "FORMAT", default_value = "table")]
+    format: OutputFormat,
+
+    /// Write the results to <OUTPUT> instead of stdout.
+    #[clap(short, long, name = "OUTPUT")]
+    output: Option<std::path::PathBuf>,
+
+    /// Remove duplicate triples from the output of a CONSTRUCT query.
+    #[clap(long)]
+    dedup_triples: bool,
+
     /// Number of bytes per chunk. `0` means use the page size which is probably `4096`. You can
     /// check `getpagesize` for the actual value.
     #[clap(short = 'c', long = "chunk-size", name = "BYTES", default_value = "0")]
@@ -77,6 +168,18 @@ pub struct Args {
     /// Run the improved versions of the hash-join/sort-merge-join algorithms.
     #[clap(short, long)]
     improved: bool,
+
+    /// Split both sides of the improved hash join into <PARTITIONS> partitions. By default
+    /// there are a few partitions per thread, more if needed for the partitions of the side
+    /// the hash table is built from to fit into the L2 cache.
+    #[clap(long, name = "PARTITIONS")]
+    partitions: Option<usize>,
+
+    /// Which side the hash join builds its hash table from: ‘left’ for the rows joined so far,
+    /// ‘right’ for the relation of each step and ‘auto’ for the smaller of the two. This applies
+    /// without --improved as well, use ‘left’ to always hash the rows joined so far like before.
+    #[clap(long, arg_enum, name = "SIDE", default_value = "auto")]
+    build: BuildSide,
 }
 
 impl Args {
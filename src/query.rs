@@ -0,0 +1,1650 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Context, Result};
+use itertools::Itertools;
+
+mod expr;
+mod parser;
+pub use expr::{Aggregate, Aggregation, BinOp, Expr, Function};
+use parser::Parser;
+
+/// A sequence of relations as it is executed by `join::join`. The first step provides the
+/// initial rows, every following step matches the subjects of its relation against one of the
+/// columns bound so far and, unless it only filters the rows, appends a column for the objects.
+/// With seeds the initial rows are given instead and the first step is joined like the others.
+#[derive(Debug, Clone, Default)]
+pub struct Chain {
+    pub steps: Vec<Step>,
+    /// The rows to start from, filling the first columns.
+    pub seeds: Option<Bindings>,
+    /// The variable bound by each column, if any.
+    pub variables: Vec<Option<String>>,
+    /// Columns which are only needed while joining, e.g. the triples matched by a pattern with
+    /// a variable predicate. They are never part of the output.
+    pub internal: Vec<usize>,
+    /// Nested SELECTs providing the seeds or one of the `tables`. They are evaluated before the
+    /// chain itself.
+    pub subqueries: Vec<Subquery>,
+    /// The results of the subqueries joined by later steps.
+    pub tables: Vec<Table>,
+}
+
+/// A nested SELECT whose results are joined with the patterns of the enclosing group.
+#[derive(Debug, Clone)]
+pub struct Subquery {
+    pub query: Query,
+    /// The table receiving the results, `None` if they are the seeds of the chain.
+    pub table: Option<usize>,
+}
+
+/// The results of a subquery together with a term identifying each row. The identifiers start
+/// with a tab, so they never occur in the input.
+#[derive(Debug, Clone)]
+pub struct Table {
+    pub bindings: Bindings,
+    pub ids: Vec<String>,
+}
+
+impl Table {
+    pub fn new(index: usize, bindings: Bindings) -> Self {
+        let ids = (0..bindings.rows.len())
+            .map(|row| format!("\t{}\t{}", index, row))
+            .collect();
+        Table { bindings, ids }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Step {
+    /// The relation joined in this step.
+    pub path: Path,
+    /// Only keep the entries with this subject.
+    pub subject: Option<String>,
+    /// Only keep the entries with this object.
+    pub object: Option<String>,
+    /// How rows are combined with the entries of the relation.
+    pub mode: JoinMode,
+    /// The column the subjects are matched against. Column `0` holds the subjects of the first
+    /// step, or the first column of the seeds.
+    pub key: usize,
+    /// The column the objects are written to. Steps which only filter the rows have none.
+    pub column: Option<usize>,
+    /// For a step which only filters the rows: the column the objects have to match as well, if
+    /// the object is a variable bound before.
+    pub object_key: Option<usize>,
+    /// Columns computed once this step is done, before the filters are checked.
+    pub binds: Vec<Bind>,
+    /// Conditions on the rows which can be checked once this step is done.
+    pub filters: Vec<Expr>,
+}
+
+/// A column computed from the other columns of a row, as by `BIND(... AS ?x)`.
+#[derive(Debug, Clone)]
+pub struct Bind {
+    pub expr: Expr,
+    pub column: usize,
+}
+
+impl Step {
+    pub fn new(path: Path, mode: JoinMode, key: usize, column: Option<usize>) -> Self {
+        Step {
+            path,
+            subject: None,
+            object: None,
+            mode,
+            key,
+            column,
+            object_key: None,
+            binds: Vec::new(),
+            filters: Vec::new(),
+        }
+    }
+}
+
+/// How the rows joined so far are combined with the entries of a relation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinMode {
+    /// Keep the rows with matching entries, once for every match.
+    Inner,
+    /// Like `Inner` but rows without any match are kept with the new column left unbound.
+    Optional,
+    /// Keep only the rows without any match. No column is added.
+    Anti,
+    /// Keep each row with at least one match exactly once. No column is added.
+    Semi,
+}
+
+impl JoinMode {
+    /// Whether the objects of the relation end up in the join table.
+    pub fn binds(self) -> bool {
+        match self {
+            JoinMode::Inner | JoinMode::Optional => true,
+            JoinMode::Anti | JoinMode::Semi => false,
+        }
+    }
+}
+
+/// The relation in predicate position.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Path {
+    /// A relation from the input, referenced by name.
+    Relation(String),
+    /// The inner path traversed from object to subject, written `^p`.
+    Inverse(Box<Path>),
+    /// The inner path repeated at least once, written `p+`.
+    OneOrMore(Box<Path>),
+    /// The inner path repeated any number of times, written `p*`.
+    ZeroOrMore(Box<Path>),
+    /// The inner path repeated between `min` and `max` times, written `p{min,max}`.
+    Repeat(Box<Path>, usize, usize),
+    /// Any of the given paths, written `p|q`.
+    Alternative(Vec<Path>),
+    /// A variable in predicate position, binding the predicate of the matching triples.
+    Variable(String),
+    /// Any predicate, written `?` on the command line.
+    Any,
+    /// Relates every subject of the input to the triples it appears in, only triples with the
+    /// given object are included. Used to bind variable predicates.
+    Triples(Option<String>),
+    /// The predicate or object of the triples found by `Triples`.
+    TriplePart(TriplePart),
+    /// Relates the values in a column of a subquery table to the identifiers of their rows.
+    TableRows(usize, usize),
+    /// Relates the identifiers of the rows of a subquery table to the values in a column.
+    TableColumn(usize, usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriplePart {
+    Predicate,
+    Object,
+}
+
+impl Path {
+    /// Parses a relation given on the command line. Leading `^`s invert the relation, a
+    /// trailing `+`, `*` or `{n,m}` repeats it. Alternatives are separated by `|` and can be
+    /// grouped using parentheses. `?` stands for any predicate.
+    pub fn from_arg(arg: &str) -> Result<Self> {
+        // Split at the top-level `|`s first since they bind the weakest.
+        let mut depth = 0usize;
+        let mut alternatives = vec![];
+        let mut start = 0;
+        for (i, c) in arg.char_indices() {
+            match c {
+                '(' => depth += 1,
+                ')' => depth = depth.checked_sub(1).context("unbalanced ‘)’")?,
+                '|' if depth == 0 => {
+                    alternatives.push(Self::from_arg(&arg[start..i])?);
+                    start = i + 1;
+                }
+                _ => {}
+            }
+        }
+        if !alternatives.is_empty() {
+            alternatives.push(Self::from_arg(&arg[start..])?);
+            return Ok(Path::Alternative(alternatives));
+        }
+
+        Ok(if arg == "?" {
+            Path::Any
+        } else if let Some(rest) = arg.strip_prefix('^') {
+            Path::Inverse(Box::new(Self::from_arg(rest)?))
+        } else if let Some(rest) = arg.strip_suffix('+') {
+            Path::OneOrMore(Box::new(Self::from_arg(rest)?))
+        } else if let Some(rest) = arg.strip_suffix('*') {
+            Path::ZeroOrMore(Box::new(Self::from_arg(rest)?))
+        } else if let Some((rest, bounds)) =
+            arg.strip_suffix('}').and_then(|arg| arg.rsplit_once('{'))
+        {
+            let (min, max) =
+                parse_bounds(bounds).with_context(|| format!("Invalid repetition in ‘{}’", arg))?;
+            Path::Repeat(Box::new(Self::from_arg(rest)?), min, max)
+        } else if arg.contains(['{', '}']) {
+            bail!("Invalid repetition in ‘{}’: unbalanced braces", arg)
+        } else if let Some(inner) = arg.strip_prefix('(').and_then(|a| a.strip_suffix(')')) {
+            Self::from_arg(inner)?
+        } else {
+            Path::Relation(arg.to_owned())
+        })
+    }
+
+    /// Whether the path matches paths of length zero, i.e. relates each node to itself.
+    pub fn matches_empty(&self) -> bool {
+        match self {
+            Path::Relation(_)
+            | Path::Variable(_)
+            | Path::Any
+            | Path::Triples(_)
+            | Path::TriplePart(_)
+            | Path::TableRows(..)
+            | Path::TableColumn(..) => false,
+            Path::ZeroOrMore(_) => true,
+            Path::Repeat(inner, min, _) => *min == 0 || inner.matches_empty(),
+            Path::Inverse(inner) | Path::OneOrMore(inner) => inner.matches_empty(),
+            Path::Alternative(paths) => paths.iter().any(Path::matches_empty),
+        }
+    }
+
+    /// Iterates over the names of all relations referenced by this path.
+    pub fn relations(&self) -> Box<dyn Iterator<Item = &String> + '_> {
+        match self {
+            Path::Relation(name) => Box::new(std::iter::once(name)),
+            Path::Inverse(inner)
+            | Path::OneOrMore(inner)
+            | Path::ZeroOrMore(inner)
+            | Path::Repeat(inner, _, _) => inner.relations(),
+            Path::Alternative(paths) => Box::new(paths.iter().flat_map(Path::relations)),
+            Path::Variable(_)
+            | Path::Any
+            | Path::Triples(_)
+            | Path::TriplePart(_)
+            | Path::TableRows(..)
+            | Path::TableColumn(..) => Box::new(std::iter::empty()),
+        }
+    }
+}
+
+impl std::fmt::Display for Path {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Path::Relation(name) => name.fmt(f),
+            Path::Inverse(inner) => write!(f, "^{}", inner),
+            Path::OneOrMore(inner) => write!(f, "{}+", inner),
+            Path::ZeroOrMore(inner) => write!(f, "{}*", inner),
+            Path::Repeat(inner, min, max) => write!(f, "{}{{{},{}}}", inner, min, max),
+            Path::Alternative(paths) => write!(f, "({})", paths.iter().format("|")),
+            Path::Variable(v) => write!(f, "?{}", v),
+            Path::Any => f.write_str("?"),
+            Path::Triples(None) => f.write_str("(triples)"),
+            Path::Triples(Some(object)) => write!(f, "(triples with object {})", object),
+            Path::TriplePart(TriplePart::Predicate) => f.write_str("(predicate)"),
+            Path::TriplePart(TriplePart::Object) => f.write_str("(object)"),
+            Path::TableRows(table, column) => {
+                write!(
+                    f,
+                    "(rows of subquery {} by column {})",
+                    table + 1,
+                    column + 1
+                )
+            }
+            Path::TableColumn(table, column) => {
+                write!(f, "(column {} of subquery {})", column + 1, table + 1)
+            }
+        }
+    }
+}
+
+impl Chain {
+    /// Builds the chain given by the relation names from the command line. Each relation is
+    /// joined with the objects of the last relation adding a column. Relations written as `[p]`
+    /// are optional, rows matching a relation written as `!p` are removed and rows matching a
+    /// relation written as `&p` are kept without adding a column. A relation written as `?`
+    /// matches any predicate and adds a column for the predicates before the one for the
+    /// objects. The constants are attached to the first and last relation respectively. The
+    /// first relation is joined with the first column of the `seeds` if given.
+    pub fn from_relations(
+        relations: &[String],
+        subject: Option<&String>,
+        object: Option<&String>,
+        seeds: Option<Bindings>,
+    ) -> Result<Self> {
+        let mut steps = Vec::with_capacity(relations.len());
+        let mut internal = Vec::new();
+        // `last` is the column holding the objects of the last relation.
+        let mut last = 0;
+        let mut width = seeds.as_ref().map_or(1, |seeds| seeds.variables.len());
+        for (i, arg) in relations.iter().enumerate() {
+            let (arg, mode) =
+                if let Some(inner) = arg.strip_prefix('[').and_then(|a| a.strip_suffix(']')) {
+                    (inner, JoinMode::Optional)
+                } else if let Some(inner) = arg.strip_prefix('!') {
+                    (inner, JoinMode::Anti)
+                } else if let Some(inner) = arg.strip_prefix('&') {
+                    (inner, JoinMode::Semi)
+                } else {
+                    (arg.as_str(), JoinMode::Inner)
+                };
+            if i == 0 && mode != JoinMode::Inner {
+                bail!("the first relation ‘{}’ has to be a plain relation", arg);
+            }
+
+            let path = Path::from_arg(arg)?;
+            if path == Path::Any && mode.binds() {
+                let edges = width;
+                internal.push(edges);
+                steps.extend(Self::triple_steps(mode, last, edges, None));
+                last = edges + 2;
+                width = last + 1;
+                continue;
+            }
+            let column = mode.binds().then_some(width);
+            steps.push(Step::new(path, mode, last, column));
+            if let Some(column) = column {
+                last = column;
+                width += 1;
+            }
+        }
+        if let Some(first) = steps.first_mut() {
+            first.subject = subject.cloned();
+        }
+        let n = steps.len();
+        match steps.last_mut() {
+            // The triples have to be restricted to the object, see `Chain::triple_steps`.
+            Some(step) if step.path == Path::TriplePart(TriplePart::Object) => {
+                steps[n - 3].path = Path::Triples(object.cloned())
+            }
+            Some(step) => step.object = object.cloned(),
+            None => {}
+        }
+        // The visible columns are named ?x0 to ?xn, in the order they can be selected in. The
+        // seeds keep their names.
+        let mut names = (0..).map(|i| Some(format!("x{}", i)));
+        let mut variables = (0..width)
+            .map(|c| match internal.contains(&c) {
+                true => None,
+                false => names.next().flatten(),
+            })
+            .collect_vec();
+        if let Some(seeds) = &seeds {
+            let n = seeds.variables.len();
+            for (c, var) in seeds.variables.iter().enumerate() {
+                if variables[n..].contains(&Some(var.clone())) {
+                    bail!(
+                        "the bound variable ?{} is also the name of a result column",
+                        var
+                    );
+                }
+                variables[c] = Some(var.clone());
+            }
+        }
+        Ok(Chain {
+            steps,
+            seeds,
+            variables,
+            internal,
+            ..Chain::default()
+        })
+    }
+
+    /// The steps binding the predicate and object of a pattern with a variable predicate: the
+    /// triples of each subject are written to column `edges`, followed by their predicates and
+    /// objects in the next two columns. Restricting the objects already while finding the
+    /// triples keeps the rows of an optional pattern intact.
+    fn triple_steps(mode: JoinMode, key: usize, edges: usize, object: Option<String>) -> [Step; 3] {
+        [
+            Step::new(Path::Triples(object), mode, key, Some(edges)),
+            Step::new(
+                Path::TriplePart(TriplePart::Predicate),
+                mode,
+                edges,
+                Some(edges + 1),
+            ),
+            Step::new(
+                Path::TriplePart(TriplePart::Object),
+                mode,
+                edges,
+                Some(edges + 2),
+            ),
+        ]
+    }
+
+    /// Resolves the selected columns, all columns but the internal ones if there is no
+    /// selection. Positions count the columns which are not internal. Variables which are not
+    /// bound by this chain are skipped.
+    pub fn output_columns(&self, selection: Option<&[Selector]>) -> Result<Vec<usize>> {
+        let visible = (0..self.width())
+            .filter(|c| !self.internal.contains(c))
+            .collect_vec();
+        let selection = match selection {
+            Some(selection) => selection,
+            None => return Ok(visible),
+        };
+        let mut columns = Vec::with_capacity(selection.len());
+        for selector in selection {
+            match selector {
+                Selector::Position(p) if *p < visible.len() => columns.push(visible[*p]),
+                Selector::Position(p) => {
+                    bail!(
+                        "cannot select column {}, the result has {}",
+                        p,
+                        visible.len()
+                    )
+                }
+                Selector::Var(v) => columns.extend(
+                    self.variables
+                        .iter()
+                        .position(|var| var.as_ref() == Some(v)),
+                ),
+            }
+        }
+        Ok(columns)
+    }
+
+    /// Number of columns in the join table.
+    pub fn width(&self) -> usize {
+        let start = self.seeds.as_ref().map_or(1, |seeds| seeds.variables.len());
+        start
+            + self
+                .steps
+                .iter()
+                .map(|step| usize::from(step.column.is_some()) + step.binds.len())
+                .sum::<usize>()
+    }
+
+    /// The step binding each column, either by its relation or by computing it. The seeds and
+    /// the subjects of the first step count as bound by the first step.
+    pub fn producers(&self) -> Vec<usize> {
+        let mut producers = vec![0; self.width()];
+        for (i, step) in self.steps.iter().enumerate() {
+            let binds = step.binds.iter().map(|bind| bind.column);
+            for column in step.column.into_iter().chain(binds) {
+                producers[column] = i;
+            }
+        }
+        producers
+    }
+
+    /// The names of all relations used by the chain and its subqueries.
+    pub fn relations(&self) -> Vec<&String> {
+        let subqueries = self
+            .subqueries
+            .iter()
+            .flat_map(|s| s.query.pattern.relations());
+        self.steps
+            .iter()
+            .flat_map(|step| step.path.relations())
+            .chain(subqueries)
+            .collect()
+    }
+
+    /// Appends the triple pattern to the chain. `columns` records which column binds which
+    /// variable or constant.
+    fn push(
+        &mut self,
+        columns: &mut HashMap<Term, usize>,
+        pattern: TriplePattern,
+        mode: JoinMode,
+    ) -> Result<()> {
+        let TriplePattern {
+            subject,
+            predicate,
+            object,
+        } = pattern;
+
+        let key = if self.variables.is_empty() {
+            if mode != JoinMode::Inner {
+                bail!("the first pattern of a query cannot be optional");
+            }
+            columns.insert(subject.clone(), 0);
+            self.push_variable(&subject);
+            0
+        } else {
+            match columns.get(&subject) {
+                Some(&col) if self.is_computed(col) => bail!(
+                    "{} is computed by BIND and cannot be the subject of a pattern",
+                    subject
+                ),
+                Some(&col) => col,
+                None => bail!(
+                    "pattern ‘{} {} {}’ is not connected to the preceding patterns",
+                    subject,
+                    predicate,
+                    object
+                ),
+            }
+        };
+
+        // A filtering step can match both ends of its pattern against the row.
+        let object_key = match (&object, columns.get(&object)) {
+            (Term::Var(_), Some(&col)) if !mode.binds() => {
+                if self.is_computed(col) {
+                    bail!(
+                        "{} is computed by BIND and cannot be the object of a pattern",
+                        object
+                    );
+                }
+                Some(col)
+            }
+            (Term::Var(v), Some(_)) => bail!("variable ?{} is bound more than once", v),
+            _ => None,
+        };
+
+        let predicate = match predicate {
+            Path::Variable(p) if mode.binds() => {
+                let var = Term::Var(p);
+                if columns.contains_key(&var) || var == object {
+                    bail!("variable {} is bound more than once", var);
+                }
+                let edges = self.width();
+                self.internal.push(edges);
+                let mut steps =
+                    Self::triple_steps(mode, key, edges, object.as_const().map(str::to_owned));
+                steps[0].subject = subject.as_const().map(str::to_owned);
+                self.steps.extend(steps);
+                self.variables.push(None);
+                columns.insert(var.clone(), edges + 1);
+                self.push_variable(&var);
+                columns.insert(object.clone(), edges + 2);
+                self.push_variable(&object);
+                return Ok(());
+            }
+            // Filtering steps do not bind the predicate.
+            Path::Variable(_) => Path::Any,
+            path => path,
+        };
+        // The objects of filtering steps are not visible outside of their pattern.
+        let column = mode.binds().then(|| self.width());
+        if let Some(column) = column {
+            columns.insert(object.clone(), column);
+            self.push_variable(&object);
+        }
+
+        self.steps.push(Step {
+            path: predicate,
+            subject: subject.as_const().map(str::to_owned),
+            object: object.as_const().map(str::to_owned),
+            mode,
+            key,
+            column,
+            object_key,
+            binds: Vec::new(),
+            filters: Vec::new(),
+        });
+        Ok(())
+    }
+
+    /// Adds a column computed by `expr` once the last step so far is done.
+    fn bind(&mut self, columns: &mut HashMap<Term, usize>, expr: Expr, var: String) -> Result<()> {
+        let var = Term::Var(var);
+        if columns.contains_key(&var) {
+            bail!("variable {} is bound more than once", var);
+        }
+        let column = self.width();
+        match self.steps.last_mut() {
+            Some(step) => step.binds.push(Bind { expr, column }),
+            None => bail!("BIND needs a preceding triple pattern"),
+        }
+        columns.insert(var.clone(), column);
+        self.push_variable(&var);
+        Ok(())
+    }
+
+    fn is_computed(&self, column: usize) -> bool {
+        self.steps
+            .iter()
+            .flat_map(|step| &step.binds)
+            .any(|bind| bind.column == column)
+    }
+
+    /// Starts the chain from `seeds`, which bind the first columns.
+    fn seed(&mut self, columns: &mut HashMap<Term, usize>, seeds: Bindings) -> Result<()> {
+        if !self.variables.is_empty() {
+            bail!("VALUES has to come before the triple patterns");
+        }
+        for var in &seeds.variables {
+            columns.insert(Term::Var(var.clone()), self.variables.len());
+            self.variables.push(Some(var.clone()));
+        }
+        self.seeds = Some(seeds);
+        Ok(())
+    }
+
+    /// Joins the results of `query`. A subquery coming first provides the seeds, a later one is
+    /// joined on the single variable it shares with the preceding patterns.
+    fn subquery(&mut self, columns: &mut HashMap<Term, usize>, query: Query) -> Result<()> {
+        let variables = query.variables()?;
+        if self.variables.is_empty() {
+            let seeds = Bindings {
+                variables,
+                rows: Vec::new(),
+            };
+            self.seed(columns, seeds)?;
+            self.subqueries.push(Subquery { query, table: None });
+            return Ok(());
+        }
+
+        let shared = variables
+            .iter()
+            .enumerate()
+            .filter_map(|(i, v)| Some((i, *columns.get(&Term::Var(v.clone()))?)))
+            .collect_vec();
+        let (key_index, key) = match shared[..] {
+            [shared] => shared,
+            [] => bail!("subquery is not connected to the preceding patterns"),
+            _ => bail!(
+                "a subquery can only be joined on a single variable, it shares {}",
+                shared
+                    .iter()
+                    .map(|&(i, _)| format!("?{}", variables[i]))
+                    .format(", ")
+            ),
+        };
+        if self.is_computed(key) {
+            bail!(
+                "?{} is computed by BIND and cannot be joined with a subquery",
+                variables[key_index]
+            );
+        }
+
+        // The rows of the table matching the key become an internal column, their values are
+        // looked up from there.
+        let table = self.tables.len();
+        let bindings = Bindings {
+            variables,
+            rows: Vec::new(),
+        };
+        self.tables.push(Table::new(table, bindings));
+        self.subqueries.push(Subquery {
+            query,
+            table: Some(table),
+        });
+        let rows = self.width();
+        self.internal.push(rows);
+        self.variables.push(None);
+        let path = Path::TableRows(table, key_index);
+        self.steps
+            .push(Step::new(path, JoinMode::Inner, key, Some(rows)));
+        let variables = self.tables[table].bindings.variables.clone();
+        for (i, var) in variables.into_iter().enumerate() {
+            if i == key_index {
+                continue;
+            }
+            let column = self.width();
+            let path = Path::TableColumn(table, i);
+            self.steps
+                .push(Step::new(path, JoinMode::Optional, rows, Some(column)));
+            columns.insert(Term::Var(var.clone()), column);
+            self.variables.push(Some(var));
+        }
+        Ok(())
+    }
+
+    /// Names the next column after `term`. Columns holding a constant are internal, they only
+    /// connect patterns.
+    fn push_variable(&mut self, term: &Term) {
+        if term.as_var().is_none() {
+            self.internal.push(self.variables.len());
+        }
+        self.variables.push(term.as_var().map(str::to_owned));
+    }
+}
+
+/// Parses the bounds of a repetition without the braces: `n`, `n,m` or `,m`.
+fn parse_bounds(bounds: &str) -> Result<(usize, usize)> {
+    let parse = |n: &str| {
+        n.trim()
+            .parse::<usize>()
+            .with_context(|| format!("invalid bound ‘{}’", n))
+    };
+    let (min, max) = match bounds.split_once(',') {
+        None => {
+            let n = parse(bounds)?;
+            (n, n)
+        }
+        Some((_, max)) if max.trim().is_empty() => {
+            bail!("repetitions need an upper bound, use ‘+’ or ‘*’ instead")
+        }
+        Some((min, max)) if min.trim().is_empty() => (0, parse(max)?),
+        Some((min, max)) => (parse(min)?, parse(max)?),
+    };
+    if min > max {
+        bail!("lower bound {} exceeds upper bound {}", min, max);
+    }
+    Ok((min, max))
+}
+
+/// A column of the result, selected by position or by the variable it binds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Selector {
+    Position(usize),
+    Var(String),
+}
+
+impl Selector {
+    /// Parses a comma separated list of positions and variables like `0,?x`.
+    pub fn parse_list(arg: &str) -> Result<Vec<Self>> {
+        arg.split(',')
+            .map(|item| {
+                let item = item.trim();
+                match item.strip_prefix(['?', '$']) {
+                    Some(var) => Ok(Selector::Var(var.to_owned())),
+                    None => item
+                        .parse()
+                        .map(Selector::Position)
+                        .with_context(|| format!("invalid column ‘{}’", item)),
+                }
+            })
+            .collect()
+    }
+}
+
+/// A column to sort the results by.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OrderKey {
+    pub column: Selector,
+    pub descending: bool,
+}
+
+impl OrderKey {
+    /// Parses a comma separated list of columns like `?x,desc(1)`.
+    pub fn parse_list(arg: &str) -> Result<Vec<Self>> {
+        arg.split(',')
+            .map(|item| {
+                let item = item.trim();
+                let lower = item.to_ascii_lowercase();
+                let (column, descending) = if lower.starts_with("desc(") && item.ends_with(')') {
+                    (&item[5..item.len() - 1], true)
+                } else if lower.starts_with("asc(") && item.ends_with(')') {
+                    (&item[4..item.len() - 1], false)
+                } else {
+                    (item, false)
+                };
+                let column = Selector::parse_list(column)?.remove(0);
+                Ok(OrderKey { column, descending })
+            })
+            .collect()
+    }
+}
+
+impl std::fmt::Display for OrderKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.descending {
+            write!(f, "DESC({})", self.column)
+        } else {
+            self.column.fmt(f)
+        }
+    }
+}
+
+impl std::fmt::Display for Selector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Selector::Position(p) => p.fmt(f),
+            Selector::Var(v) => write!(f, "?{}", v),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Term {
+    Var(String),
+    Const(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct TriplePattern {
+    pub subject: Term,
+    pub predicate: Path,
+    pub object: Term,
+}
+
+/// A group of patterns enclosed in braces.
+#[derive(Debug, Clone, Default)]
+pub struct Group {
+    pub elements: Vec<Element>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Element {
+    Triple(TriplePattern),
+    /// `OPTIONAL { ... }`
+    Optional(Group),
+    /// `MINUS { ... }`
+    Minus(Group),
+    /// `FILTER NOT EXISTS { ... }`
+    NotExists(Group),
+    /// `FILTER EXISTS { ... }`
+    Exists(Group),
+    /// `{ ... } UNION { ... }`, or a single nested group without `UNION`.
+    Union(Vec<Group>),
+    /// `FILTER ( ... )`
+    Filter(Expr),
+    /// `BIND ( ... AS ?x )`
+    Bind(Expr, String),
+    /// `VALUES ?x { ... }`
+    Values(Bindings),
+    /// `{ SELECT ... }`
+    SubQuery(Box<Query>),
+}
+
+/// A table of values given for some variables, e.g. by `VALUES`. `None` stands for an unbound
+/// value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bindings {
+    pub variables: Vec<String>,
+    pub rows: Vec<Vec<Option<String>>>,
+}
+
+impl Bindings {
+    /// Parses a table in the SPARQL TSV format: a header naming the variables like `?x`
+    /// followed by a line of terms for each row. Empty cells are unbound.
+    pub fn parse_tsv(text: &str) -> Result<Self> {
+        let mut lines = text.lines().map(|line| line.trim_end_matches('\r'));
+        let header = lines.next().context("missing header")?;
+        let variables = header
+            .split('\t')
+            .map(|var| match var.trim().strip_prefix(['?', '$']) {
+                Some(name) if !name.is_empty() => Ok(name.to_owned()),
+                _ => bail!("expected a variable in the header, found ‘{}’", var),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if let Some(var) = variables.iter().duplicates().next() {
+            bail!("variable ?{} appears more than once in the header", var);
+        }
+
+        let mut rows = Vec::new();
+        for (i, line) in lines.enumerate().filter(|(_, line)| !line.is_empty()) {
+            let row = line
+                .split('\t')
+                .map(|cell| {
+                    Some(cell.trim())
+                        .filter(|c| !c.is_empty())
+                        .map(str::to_owned)
+                })
+                .collect_vec();
+            if row.len() != variables.len() {
+                bail!(
+                    "line {} has {} values instead of {}",
+                    i + 2,
+                    row.len(),
+                    variables.len()
+                );
+            }
+            rows.push(row);
+        }
+        Ok(Bindings { variables, rows })
+    }
+}
+
+/// A parsed query of the form
+///
+/// ```text
+/// PREFIX ex: <http://example.org/>
+/// SELECT DISTINCT ?x ?y ?e WHERE {
+///     <alice> ex:knows+ ?x . ?x ^ex:knows ?y .
+///     OPTIONAL { ?y ex:email ?e } MINUS { ?y ex:blocked ?z }
+///     FILTER (?x != ?y && regex(?e, "^info@"))
+/// }
+/// ORDER BY ?x DESC(?e) LIMIT 10
+/// ```
+///
+/// Results can be grouped and aggregated as in
+/// `SELECT ?x (COUNT(?y) AS ?n) WHERE { ... } GROUP BY ?x HAVING (COUNT(?y) > 1)`.
+///
+/// Instead of selecting, `CONSTRUCT { ?x ex:friendOfFriend ?y } WHERE { ... }` builds triples
+/// from the results. `CONSTRUCT WHERE { ... }` uses the pattern as the template. `ASK { ... }`
+/// only checks whether there are any results.
+///
+/// The pattern may also be a union of such groups: `{ ... } UNION { ... }`. A group can start
+/// from given rows as in `VALUES ?x { <alice> <bob> }` or `VALUES (?x ?y) { (<a> UNDEF) }`.
+/// A nested `{ SELECT ... }` is evaluated on its own and its results are joined like a
+/// relation, e.g. `?x ex:knows ?y . { SELECT ?y (COUNT(?z) AS ?n) WHERE { ... } GROUP BY ?y }`.
+#[derive(Debug, Clone)]
+pub struct Query {
+    /// `SELECT DISTINCT`
+    pub distinct: bool,
+    /// The selected variables, `None` for `SELECT *`.
+    pub projection: Option<Vec<Selector>>,
+    /// The triples built for each result by `CONSTRUCT`. Their predicates are relations or
+    /// variables.
+    pub construct: Option<Vec<TriplePattern>>,
+    /// `ASK`
+    pub ask: bool,
+    pub pattern: Group,
+    /// `ORDER BY`, empty if the results are not sorted.
+    pub order: Vec<OrderKey>,
+    /// `LIMIT`
+    pub limit: Option<usize>,
+    /// `OFFSET`
+    pub offset: Option<usize>,
+    /// `GROUP BY`, also present if the query uses aggregates without grouping.
+    pub grouping: Option<Grouping>,
+}
+
+/// How the rows are grouped and what is computed for each group. Without keys all rows form a
+/// single group.
+#[derive(Debug, Clone, Default)]
+pub struct Grouping {
+    /// The variables of `GROUP BY`.
+    pub keys: Vec<String>,
+    /// Each aggregate with the variable it is bound to. Aggregates which only appear in
+    /// `HAVING` are named after themselves, e.g. `COUNT(?x)`.
+    pub aggregates: Vec<(String, Aggregate)>,
+    /// The conditions of `HAVING`, referring to the aggregates by their variables.
+    pub having: Vec<Expr>,
+}
+
+impl Grouping {
+    /// The variables whose values are needed to compute the groups and aggregates.
+    pub fn inputs(&self) -> Vec<&String> {
+        self.keys
+            .iter()
+            .chain(self.aggregates.iter().filter_map(|(_, a)| a.var.as_ref()))
+            .unique()
+            .collect()
+    }
+}
+
+impl Query {
+    pub fn parse(text: &str) -> Result<Self> {
+        Parser::new(text).query().context("Cannot parse query")
+    }
+
+    /// Arranges the patterns into one chain for each alternative of a top-level union. Every
+    /// pattern has to start at a variable or constant bound by one of the preceding patterns.
+    /// Constants are pushed down to the respective steps, filters to the first step after
+    /// which all their variables are bound. Every chain starts from `seeds` if given.
+    pub fn into_chains(self, seeds: Option<&Bindings>) -> Result<Vec<Chain>> {
+        let mut chains = Vec::new();
+        Self::collect_chains(self.pattern, seeds, &mut chains)?;
+        Ok(chains)
+    }
+
+    fn collect_chains(
+        group: Group,
+        seeds: Option<&Bindings>,
+        chains: &mut Vec<Chain>,
+    ) -> Result<()> {
+        match <[Element; 1]>::try_from(group.elements) {
+            Ok([Element::Union(groups)]) => groups
+                .into_iter()
+                .try_for_each(|group| Self::collect_chains(group, seeds, chains)),
+            Ok(elements) => Self::chain(Vec::from(elements), seeds).map(|c| chains.push(c)),
+            Err(elements) => Self::chain(elements, seeds).map(|c| chains.push(c)),
+        }
+    }
+
+    fn chain(elements: Vec<Element>, seeds: Option<&Bindings>) -> Result<Chain> {
+        let mut chain = Chain::default();
+        let mut columns = HashMap::new();
+        let mut filters = Vec::new();
+
+        if let Some(seeds) = seeds {
+            if elements.iter().any(|e| matches!(e, Element::Values(_))) {
+                bail!("VALUES cannot be combined with --bindings");
+            }
+            chain.seed(&mut columns, seeds.clone())?;
+        }
+        for element in elements {
+            match element {
+                Element::Values(seeds) => chain.seed(&mut columns, seeds)?,
+                Element::Triple(pattern) => chain.push(&mut columns, pattern, JoinMode::Inner)?,
+                Element::Optional(group) => {
+                    let pattern = Self::single_triple(group, "OPTIONAL")?;
+                    chain.push(&mut columns, pattern, JoinMode::Optional)?
+                }
+                Element::Minus(group) => {
+                    let pattern = Self::single_triple(group, "MINUS")?;
+                    chain.push(&mut columns, pattern, JoinMode::Anti)?
+                }
+                Element::NotExists(group) => {
+                    let pattern = Self::single_triple(group, "FILTER NOT EXISTS")?;
+                    chain.push(&mut columns, pattern, JoinMode::Anti)?
+                }
+                Element::Exists(group) => {
+                    let pattern = Self::single_triple(group, "FILTER EXISTS")?;
+                    chain.push(&mut columns, pattern, JoinMode::Semi)?
+                }
+                Element::Union(_) => {
+                    bail!("UNION is only supported for the whole pattern of the query")
+                }
+                Element::Filter(expr) => filters.push(expr),
+                Element::Bind(expr, var) => chain.bind(&mut columns, expr, var)?,
+                Element::SubQuery(query) => chain.subquery(&mut columns, *query)?,
+            }
+        }
+
+        // Variables which are never bound leave the filter at the last step.
+        let producers = chain.producers();
+        let last = chain.steps.len().saturating_sub(1);
+        for expr in filters {
+            let step = expr
+                .variables()
+                .into_iter()
+                .map(|v| match columns.get(&Term::Var(v.to_owned())) {
+                    Some(&column) => producers[column],
+                    None => last,
+                })
+                .max()
+                .unwrap_or(0);
+            match chain.steps.get_mut(step) {
+                Some(step) => step.filters.push(expr),
+                None => bail!("FILTER needs at least one triple pattern"),
+            }
+        }
+
+        Ok(chain)
+    }
+
+    /// The variables of the results in the order of their columns: the selected ones, or all
+    /// visible variables of the pattern for `SELECT *`.
+    pub fn variables(&self) -> Result<Vec<String>> {
+        if let Some(projection) = &self.projection {
+            return Ok(projection
+                .iter()
+                .map(|selector| match selector {
+                    Selector::Var(v) => v.clone(),
+                    Selector::Position(_) => unreachable!("queries select by variable"),
+                })
+                .collect());
+        }
+        let chains = self.clone().into_chains(None)?;
+        let variables = chains
+            .iter()
+            .flat_map(|chain| {
+                let columns = chain.output_columns(None).expect("all columns");
+                columns
+                    .into_iter()
+                    .filter_map(|c| chain.variables[c].clone())
+                    .collect_vec()
+            })
+            .unique()
+            .collect();
+        Ok(variables)
+    }
+
+    /// Nested groups are only supported if they consist of a single triple pattern.
+    fn single_triple(group: Group, keyword: &str) -> Result<TriplePattern> {
+        match <[Element; 1]>::try_from(group.elements) {
+            Ok([Element::Triple(pattern)]) => Ok(pattern),
+            _ => bail!("{} is only supported for a single triple pattern", keyword),
+        }
+    }
+}
+
+impl Group {
+    /// Iterates over the names of all relations referenced by the patterns of the group.
+    pub fn relations(&self) -> Box<dyn Iterator<Item = &String> + '_> {
+        Box::new(self.elements.iter().flat_map(|element| match element {
+            Element::Triple(pattern) => pattern.predicate.relations(),
+            Element::Optional(group)
+            | Element::Minus(group)
+            | Element::NotExists(group)
+            | Element::Exists(group) => group.relations(),
+            Element::Union(groups) => Box::new(groups.iter().flat_map(Group::relations)),
+            Element::SubQuery(query) => query.pattern.relations(),
+            Element::Filter(_) | Element::Bind(..) | Element::Values(_) => {
+                Box::new(std::iter::empty())
+            }
+        }))
+    }
+}
+
+impl Term {
+    pub fn as_const(&self) -> Option<&str> {
+        match self {
+            Term::Const(c) => Some(c),
+            Term::Var(_) => None,
+        }
+    }
+
+    pub fn as_var(&self) -> Option<&str> {
+        match self {
+            Term::Var(v) => Some(v),
+            Term::Const(_) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Term {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Term::Var(v) => write!(f, "?{}", v),
+            Term::Const(c) => c.fmt(f),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chains(text: &str) -> Result<Vec<Chain>> {
+        Query::parse(text)?.into_chains(None)
+    }
+
+    fn chain(text: &str) -> Chain {
+        let mut chains = chains(text).unwrap();
+        assert_eq!(chains.len(), 1);
+        chains.remove(0)
+    }
+
+    fn error(text: &str) -> String {
+        chains(text).unwrap_err().to_string()
+    }
+
+    fn vars(names: &[&str]) -> Vec<Option<String>> {
+        names
+            .iter()
+            .map(|n| Some(n.to_string()).filter(|n| !n.is_empty()))
+            .collect()
+    }
+
+    /// The constant subject and object of each step.
+    fn constants(chain: &Chain) -> Vec<(Option<&str>, Option<&str>)> {
+        chain
+            .steps
+            .iter()
+            .map(|step| (step.subject.as_deref(), step.object.as_deref()))
+            .collect()
+    }
+
+    #[test]
+    fn constants_of_relations() {
+        let relations = ["<p>".to_owned(), "<q>".to_owned(), "<r>".to_owned()];
+        let (a, b) = ("<a>".to_owned(), "<b>".to_owned());
+        let chain = Chain::from_relations(&relations, Some(&a), Some(&b), None).unwrap();
+        assert_eq!(
+            constants(&chain),
+            [(Some("<a>"), None), (None, None), (None, Some("<b>"))]
+        );
+        let chain = Chain::from_relations(&relations[..1], Some(&a), Some(&b), None).unwrap();
+        assert_eq!(constants(&chain), [(Some("<a>"), Some("<b>"))]);
+        let chain = Chain::from_relations(&relations, None, None, None).unwrap();
+        assert_eq!(constants(&chain), [(None, None); 3]);
+    }
+
+    #[test]
+    fn constants_of_patterns() {
+        let chain = chain("SELECT * WHERE { <a> <p> ?x . ?x <q> <b> . <b> <r> ?y }");
+        assert_eq!(
+            constants(&chain),
+            [
+                (Some("<a>"), None),
+                (None, Some("<b>")),
+                (Some("<b>"), None)
+            ]
+        );
+    }
+
+    fn relation(name: &str) -> Box<Path> {
+        Box::new(Path::Relation(name.to_owned()))
+    }
+
+    #[test]
+    fn inverse_relations() {
+        assert_eq!(
+            Path::from_arg("^<p>").unwrap(),
+            Path::Inverse(relation("<p>"))
+        );
+        assert_eq!(
+            Path::from_arg("^^<p>").unwrap(),
+            Path::Inverse(Box::new(Path::Inverse(relation("<p>"))))
+        );
+        let chain = chain("SELECT * WHERE { ?x ^<p> ?y . ?y <q> ?z }");
+        let paths = chain
+            .steps
+            .iter()
+            .map(|step| &step.path)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            [
+                &Path::Inverse(relation("<p>")),
+                &Path::Relation("<q>".to_owned())
+            ]
+        );
+    }
+
+    #[test]
+    fn repetition_bounds() {
+        assert_eq!(parse_bounds("2").unwrap(), (2, 2));
+        assert_eq!(parse_bounds("1,3").unwrap(), (1, 3));
+        assert_eq!(parse_bounds(" 0 , 2 ").unwrap(), (0, 2));
+        assert_eq!(parse_bounds(",4").unwrap(), (0, 4));
+        let error = |bounds| format!("{:#}", parse_bounds(bounds).unwrap_err());
+        assert_eq!(error("3,1"), "lower bound 3 exceeds upper bound 1");
+        assert_eq!(
+            error("x"),
+            "invalid bound ‘x’: invalid digit found in string"
+        );
+        assert_eq!(
+            error("1,y"),
+            "invalid bound ‘y’: invalid digit found in string"
+        );
+        assert_eq!(
+            error("2,"),
+            "repetitions need an upper bound, use ‘+’ or ‘*’ instead"
+        );
+    }
+
+    #[test]
+    fn repetitions() {
+        assert_eq!(
+            Path::from_arg("<p>{2}").unwrap(),
+            Path::Repeat(relation("<p>"), 2, 2)
+        );
+        assert_eq!(
+            Path::from_arg("^<p>{1,3}").unwrap(),
+            Path::Inverse(Box::new(Path::Repeat(relation("<p>"), 1, 3)))
+        );
+        let arg_error = |arg| format!("{:#}", Path::from_arg(arg).unwrap_err());
+        assert_eq!(
+            arg_error("<p>{3,1}"),
+            "Invalid repetition in ‘<p>{3,1}’: lower bound 3 exceeds upper bound 1"
+        );
+        assert_eq!(
+            arg_error("<p>{a}"),
+            "Invalid repetition in ‘<p>{a}’: invalid bound ‘a’: invalid digit found in string"
+        );
+        assert_eq!(
+            arg_error("<p>{1,2"),
+            "Invalid repetition in ‘<p>{1,2’: unbalanced braces"
+        );
+
+        let chain = chain("SELECT * WHERE { ?x <p>{0,2} ?y }");
+        assert_eq!(chain.steps[0].path, Path::Repeat(relation("<p>"), 0, 2));
+        assert!(chain.steps[0].path.matches_empty());
+        let query_error = |text| format!("{:#}", chains(text).unwrap_err());
+        assert_eq!(
+            query_error("SELECT * WHERE { ?x <p>{2,1} ?y }"),
+            "Cannot parse query: lower bound 2 exceeds upper bound 1"
+        );
+        assert_eq!(
+            query_error("SELECT * WHERE { ?x <p>{1,2 ?y }"),
+            "Cannot parse query: expected ‘}’ after the bounds of a repetition, found ‘?y’"
+        );
+    }
+
+    #[test]
+    fn alternatives() {
+        let alternative =
+            |names: &[&str]| Path::Alternative(names.iter().map(|&name| *relation(name)).collect());
+        assert_eq!(
+            Path::from_arg("<p>|<q>").unwrap(),
+            alternative(&["<p>", "<q>"])
+        );
+        assert_eq!(
+            Path::from_arg("<p>|(<q>|<r>)+").unwrap(),
+            Path::Alternative(vec![
+                Path::Relation("<p>".to_owned()),
+                Path::OneOrMore(Box::new(alternative(&["<q>", "<r>"]))),
+            ])
+        );
+        assert_eq!(
+            format!("{:#}", Path::from_arg("<p>)|(<q>").unwrap_err()),
+            "unbalanced ‘)’"
+        );
+        assert!(Path::from_arg("(<p>|<q>)*").unwrap().matches_empty());
+    }
+
+    #[test]
+    fn optional_step() {
+        let chain = chain("SELECT * WHERE { ?x <p> ?y OPTIONAL { ?y <q> ?z } ?x <r> ?w }");
+        let steps = chain
+            .steps
+            .iter()
+            .map(|s| (s.mode, s.key, s.column))
+            .collect_vec();
+        assert_eq!(
+            steps,
+            [
+                (JoinMode::Inner, 0, Some(1)),
+                (JoinMode::Optional, 1, Some(2)),
+                (JoinMode::Inner, 0, Some(3)),
+            ]
+        );
+        assert_eq!(chain.variables, vars(&["x", "y", "z", "w"]));
+    }
+
+    #[test]
+    fn optional_takes_a_single_pattern() {
+        assert_eq!(
+            error("SELECT * WHERE { ?x <p> ?y OPTIONAL { ?y <q> ?z . ?z <r> ?w } }"),
+            "OPTIONAL is only supported for a single triple pattern"
+        );
+    }
+
+    #[test]
+    fn first_pattern_cannot_be_optional() {
+        assert_eq!(
+            error("SELECT * WHERE { OPTIONAL { ?x <p> ?y } }"),
+            "the first pattern of a query cannot be optional"
+        );
+    }
+
+    #[test]
+    fn minus_and_not_exists_filter_rows() {
+        for keyword in ["MINUS", "FILTER NOT EXISTS"] {
+            let chain = chain(&format!(
+                "SELECT * WHERE {{ ?x <p> ?y {} {{ ?y <q> ?z }} }}",
+                keyword
+            ));
+            let step = &chain.steps[1];
+            assert_eq!(step.mode, JoinMode::Anti, "{}", keyword);
+            assert_eq!((step.key, step.column, step.object_key), (1, None, None));
+            // The variables of the pattern are not visible outside of it.
+            assert_eq!(chain.variables, vars(&["x", "y"]));
+            assert_eq!(
+                error(&format!(
+                    "SELECT * WHERE {{ ?x <p> ?y {} {{ ?y <q> ?z }} ?z <r> ?w }}",
+                    keyword
+                )),
+                "pattern ‘?z <r> ?w’ is not connected to the preceding patterns"
+            );
+        }
+    }
+
+    #[test]
+    fn minus_matches_both_bound_variables() {
+        let chain = chain("SELECT * WHERE { ?x <p> ?y MINUS { ?x <q> ?y } }");
+        let step = &chain.steps[1];
+        assert_eq!((step.key, step.column, step.object_key), (0, None, Some(1)));
+    }
+
+    #[test]
+    fn exists_filters_rows() {
+        let chain = chain("SELECT * WHERE { ?x <p> ?y FILTER EXISTS { ?y <q> ?z } ?y <r> ?w }");
+        let steps = chain
+            .steps
+            .iter()
+            .map(|s| (s.mode, s.key, s.column))
+            .collect_vec();
+        assert_eq!(
+            steps,
+            [
+                (JoinMode::Inner, 0, Some(1)),
+                (JoinMode::Semi, 1, None),
+                (JoinMode::Inner, 1, Some(2)),
+            ]
+        );
+        assert_eq!(chain.variables, vars(&["x", "y", "w"]));
+    }
+
+    #[test]
+    fn exists_matches_both_bound_variables() {
+        let chain = chain("SELECT * WHERE { ?x <p> ?y FILTER EXISTS { ?y <q> ?x } }");
+        let step = &chain.steps[1];
+        assert_eq!(step.mode, JoinMode::Semi);
+        assert_eq!((step.key, step.column, step.object_key), (1, None, Some(0)));
+    }
+
+    #[test]
+    fn union_gives_a_chain_per_group() {
+        let chains =
+            chains("SELECT * WHERE { { ?x <p> ?y } UNION { ?x <q> ?z } UNION { ?x <r> ?y } }")
+                .unwrap();
+        let variables = chains.iter().map(|c| c.variables.clone()).collect_vec();
+        assert_eq!(
+            variables,
+            [vars(&["x", "y"]), vars(&["x", "z"]), vars(&["x", "y"])]
+        );
+    }
+
+    #[test]
+    fn union_is_only_supported_for_the_whole_pattern() {
+        assert_eq!(
+            error("SELECT * WHERE { ?x <p> ?y { ?y <q> ?z } UNION { ?y <r> ?z } }"),
+            "UNION is only supported for the whole pattern of the query"
+        );
+    }
+
+    #[test]
+    fn selects_columns_by_position_and_variable() {
+        assert_eq!(
+            Selector::parse_list("2, ?y,$z").unwrap(),
+            [
+                Selector::Position(2),
+                Selector::Var("y".to_owned()),
+                Selector::Var("z".to_owned())
+            ]
+        );
+        assert!(Selector::parse_list("x").is_err());
+
+        let chain = chain("SELECT * WHERE { ?x <p> <c> . ?x <q> ?y . ?y <r> ?z }");
+        // The column of the constant is internal and cannot be selected.
+        assert_eq!(chain.output_columns(None).unwrap(), [0, 2, 3]);
+        let selection = Selector::parse_list("?z,1,?unknown").unwrap();
+        assert_eq!(chain.output_columns(Some(&selection)).unwrap(), [3, 2]);
+        let selection = Selector::parse_list("3").unwrap();
+        assert_eq!(
+            chain
+                .output_columns(Some(&selection))
+                .unwrap_err()
+                .to_string(),
+            "cannot select column 3, the result has 3"
+        );
+    }
+
+    #[test]
+    fn variable_predicates_bind_the_parts_of_triples() {
+        let chain = chain("SELECT * WHERE { ?x <p> ?y . ?y ?p <c> }");
+        let steps = chain
+            .steps
+            .iter()
+            .map(|s| (s.path.clone(), s.key, s.column))
+            .collect_vec();
+        assert_eq!(
+            steps,
+            [
+                (Path::Relation("<p>".to_owned()), 0, Some(1)),
+                (Path::Triples(Some("<c>".to_owned())), 1, Some(2)),
+                (Path::TriplePart(TriplePart::Predicate), 2, Some(3)),
+                (Path::TriplePart(TriplePart::Object), 2, Some(4)),
+            ]
+        );
+        assert_eq!(chain.variables, vars(&["x", "y", "", "p", ""]));
+        assert_eq!(chain.internal, [2, 4]);
+    }
+
+    #[test]
+    fn variable_predicates_of_filters_match_any_predicate() {
+        let chain = chain("SELECT * WHERE { ?x <p> ?y MINUS { ?y ?q ?z } }");
+        assert_eq!(chain.steps[1].path, Path::Any);
+        assert_eq!(chain.width(), 2);
+    }
+
+    #[test]
+    fn variable_predicates_are_bound_once() {
+        assert_eq!(
+            error("SELECT * WHERE { ?x <p> ?y . ?y ?x ?z }"),
+            "variable ?x is bound more than once"
+        );
+        assert_eq!(
+            error("SELECT * WHERE { ?x <p> ?y . ?y ?p ?p }"),
+            "variable ?p is bound more than once"
+        );
+    }
+
+    #[test]
+    fn any_relation_on_the_command_line() {
+        let relations = ["<p>".to_owned(), "?".to_owned()];
+        let object = "<o>".to_owned();
+        let chain = Chain::from_relations(&relations, None, Some(&object), None).unwrap();
+        let paths = chain.steps.iter().map(|s| s.path.clone()).collect_vec();
+        assert_eq!(
+            paths,
+            [
+                Path::Relation("<p>".to_owned()),
+                Path::Triples(Some(object)),
+                Path::TriplePart(TriplePart::Predicate),
+                Path::TriplePart(TriplePart::Object),
+            ]
+        );
+        assert_eq!(chain.variables, vars(&["x0", "x1", "", "x2", "x3"]));
+    }
+
+    #[test]
+    fn variables_of_the_results() {
+        let variables = |text: &str| Query::parse(text).unwrap().variables().unwrap();
+        assert_eq!(
+            variables("SELECT ?z ?x WHERE { ?x <p> ?y . ?y <q> ?z }"),
+            ["z", "x"]
+        );
+        assert_eq!(
+            variables("SELECT * WHERE { ?x <p> <c> . ?x <q> ?y MINUS { ?y <r> ?z } }"),
+            ["x", "y"]
+        );
+    }
+
+    fn bindings(variables: &[&str], rows: &[&[Option<&str>]]) -> Bindings {
+        Bindings {
+            variables: variables.iter().map(|v| v.to_string()).collect(),
+            rows: rows
+                .iter()
+                .map(|row| row.iter().map(|t| t.map(str::to_owned)).collect())
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn parses_tsv_bindings() {
+        let parsed = Bindings::parse_tsv("?x\t$y\r\n<a>\t\"b\"\n\n\t<c>\n").unwrap();
+        assert_eq!(
+            parsed,
+            bindings(
+                &["x", "y"],
+                &[&[Some("<a>"), Some("\"b\"")], &[None, Some("<c>")]]
+            )
+        );
+        let error = |text: &str| Bindings::parse_tsv(text).unwrap_err().to_string();
+        assert_eq!(error(""), "missing header");
+        assert_eq!(
+            error("x\n<a>"),
+            "expected a variable in the header, found ‘x’"
+        );
+        assert_eq!(
+            error("?x\t?x\n"),
+            "variable ?x appears more than once in the header"
+        );
+        assert_eq!(error("?x\n<a>\t<b>"), "line 2 has 2 values instead of 1");
+    }
+
+    #[test]
+    fn values_seed_the_chain() {
+        let chain = chain("SELECT * WHERE { VALUES (?x ?z) { (<a> UNDEF) } ?x <p> ?y }");
+        assert_eq!(
+            chain.seeds,
+            Some(bindings(&["x", "z"], &[&[Some("<a>"), None]]))
+        );
+        assert_eq!(chain.variables, vars(&["x", "z", "y"]));
+        let step = &chain.steps[0];
+        assert_eq!((step.key, step.column), (0, Some(2)));
+    }
+
+    #[test]
+    fn bindings_seed_every_chain() {
+        let seeds = bindings(&["y"], &[&[Some("<b>")]]);
+        let query = Query::parse("SELECT * WHERE { { ?y <p> ?x } UNION { ?y <q> ?z } }").unwrap();
+        let chains = query.into_chains(Some(&seeds)).unwrap();
+        assert!(chains.iter().all(|c| c.seeds.as_ref() == Some(&seeds)));
+        assert_eq!(chains[0].steps[0].key, 0);
+
+        let query = Query::parse("SELECT * WHERE { VALUES ?y { <c> } ?y <p> ?x }").unwrap();
+        assert_eq!(
+            query.into_chains(Some(&seeds)).unwrap_err().to_string(),
+            "VALUES cannot be combined with --bindings"
+        );
+        assert_eq!(
+            error("SELECT * WHERE { ?y <p> ?x VALUES ?y { <c> } }"),
+            "VALUES has to come before the triple patterns"
+        );
+    }
+
+    #[test]
+    fn seeds_of_command_line_relations() {
+        let seeds = bindings(&["s", "t"], &[&[Some("<a>"), None]]);
+        let relations = ["<p>".to_owned(), "<q>".to_owned()];
+        let chain = Chain::from_relations(&relations, None, None, Some(seeds)).unwrap();
+        assert_eq!(chain.variables, vars(&["s", "t", "x2", "x3"]));
+        let steps = chain.steps.iter().map(|s| (s.key, s.column)).collect_vec();
+        assert_eq!(steps, [(0, Some(2)), (2, Some(3))]);
+
+        let seeds = bindings(&["x2"], &[]);
+        assert!(Chain::from_relations(&relations, None, None, Some(seeds)).is_err());
+    }
+
+    #[test]
+    fn bind_computes_a_column() {
+        let chain = chain("SELECT * WHERE { ?x <p> ?y BIND(STR(?y) AS ?s) ?x <q> ?z }");
+        assert_eq!(chain.variables, vars(&["x", "y", "s", "z"]));
+        let binds = chain
+            .steps
+            .iter()
+            .map(|s| s.binds.iter().map(|b| b.column).collect_vec())
+            .collect_vec();
+        assert_eq!(binds, [vec![2], vec![]]);
+        assert_eq!(chain.steps[1].column, Some(3));
+        assert_eq!(chain.producers(), [0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn bind_errors() {
+        assert_eq!(
+            error("SELECT * WHERE { ?x <p> ?y BIND(1 AS ?y) }"),
+            "variable ?y is bound more than once"
+        );
+        assert_eq!(
+            error("SELECT * WHERE { BIND(1 AS ?y) ?x <p> ?y }"),
+            "BIND needs a preceding triple pattern"
+        );
+        assert_eq!(
+            error("SELECT * WHERE { ?x <p> ?y BIND(?y AS ?z) ?z <q> ?w }"),
+            "?z is computed by BIND and cannot be the subject of a pattern"
+        );
+        for keyword in ["MINUS", "FILTER EXISTS", "FILTER NOT EXISTS"] {
+            assert_eq!(
+                error(&format!(
+                    "SELECT * WHERE {{ ?x <p> ?y BIND(?y AS ?z) {} {{ ?x <q> ?z }} }}",
+                    keyword
+                )),
+                "?z is computed by BIND and cannot be the object of a pattern"
+            );
+        }
+    }
+
+    #[test]
+    fn subquery_joined_on_a_shared_variable() {
+        let chain = chain(
+            "SELECT * WHERE { ?x <p> ?y { SELECT ?y (COUNT(?z) AS ?n) WHERE { ?y <q> ?z } \
+             GROUP BY ?y } }",
+        );
+        let steps = chain
+            .steps
+            .iter()
+            .map(|s| (s.path.clone(), s.mode, s.key, s.column))
+            .collect_vec();
+        assert_eq!(
+            steps,
+            [
+                (
+                    Path::Relation("<p>".to_owned()),
+                    JoinMode::Inner,
+                    0,
+                    Some(1)
+                ),
+                (Path::TableRows(0, 0), JoinMode::Inner, 1, Some(2)),
+                (Path::TableColumn(0, 1), JoinMode::Optional, 2, Some(3)),
+            ]
+        );
+        assert_eq!(chain.variables, vars(&["x", "y", "", "n"]));
+        assert_eq!(chain.internal, [2]);
+        assert_eq!(chain.tables[0].bindings.variables, ["y", "n"]);
+        assert_eq!(chain.subqueries[0].table, Some(0));
+    }
+
+    #[test]
+    fn leading_subquery_seeds_the_chain() {
+        let chain = chain("SELECT * WHERE { { SELECT ?y WHERE { ?y <q> ?z } } ?y <p> ?x }");
+        assert_eq!(chain.variables, vars(&["y", "x"]));
+        assert_eq!(chain.seeds.as_ref().unwrap().variables, ["y"]);
+        assert_eq!(chain.subqueries[0].table, None);
+        assert!(chain.tables.is_empty());
+    }
+
+    #[test]
+    fn subquery_errors() {
+        assert_eq!(
+            error("SELECT * WHERE { ?x <p> ?y { SELECT ?z WHERE { ?z <q> ?w } } }"),
+            "subquery is not connected to the preceding patterns"
+        );
+        assert_eq!(
+            error("SELECT * WHERE { ?x <p> ?y { SELECT ?x ?y WHERE { ?x <q> ?y } } }"),
+            "a subquery can only be joined on a single variable, it shares ?x, ?y"
+        );
+        assert_eq!(
+            error("SELECT * WHERE { ?x <p> ?y BIND(?y AS ?z) { SELECT ?z WHERE { ?z <q> ?w } } }"),
+            "?z is computed by BIND and cannot be joined with a subquery"
+        );
+    }
+}
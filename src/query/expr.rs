@@ -0,0 +1,278 @@
+use std::fmt;
+use std::ops::RangeInclusive;
+
+use itertools::Itertools;
+
+/// An expression as used by `FILTER`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Var(String),
+    /// An IRI or literal as written in the query, including the angle brackets or quotes.
+    Const(String),
+    Number(f64),
+    Bool(bool),
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+    Call(Function, Vec<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+/// The built-in functions which can be called from expressions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Function {
+    Bound,
+    Regex,
+    Str,
+    StrLen,
+    Contains,
+    StrStarts,
+    StrEnds,
+    LCase,
+    UCase,
+    IsIri,
+    IsLiteral,
+    IsBlank,
+    IsNumeric,
+    Concat,
+    SubStr,
+    StrBefore,
+    StrAfter,
+    Replace,
+    EncodeForUri,
+    Abs,
+    Round,
+    Ceil,
+    Floor,
+    Iri,
+    Uri,
+    If,
+    Coalesce,
+}
+
+impl Function {
+    const ALL: [Function; 27] = [
+        Function::Bound,
+        Function::Regex,
+        Function::Str,
+        Function::StrLen,
+        Function::Contains,
+        Function::StrStarts,
+        Function::StrEnds,
+        Function::LCase,
+        Function::UCase,
+        Function::IsIri,
+        Function::IsLiteral,
+        Function::IsBlank,
+        Function::IsNumeric,
+        Function::Concat,
+        Function::SubStr,
+        Function::StrBefore,
+        Function::StrAfter,
+        Function::Replace,
+        Function::EncodeForUri,
+        Function::Abs,
+        Function::Round,
+        Function::Ceil,
+        Function::Floor,
+        Function::Iri,
+        Function::Uri,
+        Function::If,
+        Function::Coalesce,
+    ];
+
+    /// Looks up a function by its case-insensitive name.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|f| f.name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Function::Bound => "BOUND",
+            Function::Regex => "REGEX",
+            Function::Str => "STR",
+            Function::StrLen => "STRLEN",
+            Function::Contains => "CONTAINS",
+            Function::StrStarts => "STRSTARTS",
+            Function::StrEnds => "STRENDS",
+            Function::LCase => "LCASE",
+            Function::UCase => "UCASE",
+            Function::IsIri => "isIRI",
+            Function::IsLiteral => "isLiteral",
+            Function::IsBlank => "isBlank",
+            Function::IsNumeric => "isNumeric",
+            Function::Concat => "CONCAT",
+            Function::SubStr => "SUBSTR",
+            Function::StrBefore => "STRBEFORE",
+            Function::StrAfter => "STRAFTER",
+            Function::Replace => "REPLACE",
+            Function::EncodeForUri => "ENCODE_FOR_URI",
+            Function::Abs => "ABS",
+            Function::Round => "ROUND",
+            Function::Ceil => "CEIL",
+            Function::Floor => "FLOOR",
+            Function::Iri => "IRI",
+            Function::Uri => "URI",
+            Function::If => "IF",
+            Function::Coalesce => "COALESCE",
+        }
+    }
+
+    /// The number of arguments the function accepts.
+    pub fn arity(self) -> RangeInclusive<usize> {
+        match self {
+            Function::Concat | Function::Coalesce => 0..=usize::MAX,
+            Function::Regex | Function::SubStr => 2..=3,
+            Function::Replace => 3..=4,
+            Function::If => 3..=3,
+            Function::Contains
+            | Function::StrStarts
+            | Function::StrEnds
+            | Function::StrBefore
+            | Function::StrAfter => 2..=2,
+            _ => 1..=1,
+        }
+    }
+}
+
+/// The functions computing a single value from the rows of a group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aggregation {
+    Count,
+    Sum,
+    Min,
+    Max,
+    Avg,
+    GroupConcat,
+}
+
+impl Aggregation {
+    const ALL: [Aggregation; 6] = [
+        Aggregation::Count,
+        Aggregation::Sum,
+        Aggregation::Min,
+        Aggregation::Max,
+        Aggregation::Avg,
+        Aggregation::GroupConcat,
+    ];
+
+    /// Looks up an aggregate function by its case-insensitive name.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|a| a.name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Aggregation::Count => "COUNT",
+            Aggregation::Sum => "SUM",
+            Aggregation::Min => "MIN",
+            Aggregation::Max => "MAX",
+            Aggregation::Avg => "AVG",
+            Aggregation::GroupConcat => "GROUP_CONCAT",
+        }
+    }
+}
+
+/// An aggregate like `COUNT(DISTINCT ?x)` or `GROUP_CONCAT(?x; SEPARATOR=", ")`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Aggregate {
+    pub function: Aggregation,
+    /// Only take every distinct value into account once.
+    pub distinct: bool,
+    /// The aggregated variable, `None` for `COUNT(*)`.
+    pub var: Option<String>,
+    /// The separator of `GROUP_CONCAT` as written in the query, without the quotes.
+    pub separator: String,
+}
+
+impl Expr {
+    /// Collects the names of all variables used in the expression.
+    pub fn variables(&self) -> Vec<&str> {
+        let mut vars = Vec::new();
+        self.collect_variables(&mut vars);
+        vars
+    }
+
+    fn collect_variables<'e>(&'e self, vars: &mut Vec<&'e str>) {
+        match self {
+            Expr::Var(v) => vars.push(v),
+            Expr::Const(_) | Expr::Number(_) | Expr::Bool(_) => {}
+            Expr::Not(inner) | Expr::Neg(inner) => inner.collect_variables(vars),
+            Expr::Binary(_, lhs, rhs) => {
+                lhs.collect_variables(vars);
+                rhs.collect_variables(vars);
+            }
+            Expr::Call(_, args) => args.iter().for_each(|arg| arg.collect_variables(vars)),
+        }
+    }
+}
+
+impl fmt::Display for BinOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BinOp::Or => "||",
+            BinOp::And => "&&",
+            BinOp::Eq => "=",
+            BinOp::Ne => "!=",
+            BinOp::Lt => "<",
+            BinOp::Le => "<=",
+            BinOp::Gt => ">",
+            BinOp::Ge => ">=",
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+        })
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Var(v) => write!(f, "?{}", v),
+            Expr::Const(c) => c.fmt(f),
+            Expr::Number(n) => n.fmt(f),
+            Expr::Bool(b) => b.fmt(f),
+            Expr::Not(inner) => write!(f, "!{}", inner),
+            Expr::Neg(inner) => write!(f, "-{}", inner),
+            Expr::Binary(op, lhs, rhs) => write!(f, "({} {} {})", lhs, op, rhs),
+            Expr::Call(func, args) => write!(f, "{}({})", func.name(), args.iter().format(", ")),
+        }
+    }
+}
+
+impl fmt::Display for Aggregate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}(", self.function.name())?;
+        if self.distinct {
+            f.write_str("DISTINCT ")?;
+        }
+        match &self.var {
+            Some(v) => write!(f, "?{}", v)?,
+            None => f.write_str("*")?,
+        }
+        if self.function == Aggregation::GroupConcat && self.separator != " " {
+            write!(f, "; SEPARATOR=\"{}\"", self.separator)?;
+        }
+        f.write_str(")")
+    }
+}
@@ -0,0 +1,1249 @@
+use std::collections::HashMap;
+use std::iter::Peekable;
+use std::str::CharIndices;
+
+use anyhow::{bail, Context, Result};
+use itertools::Itertools;
+
+use super::{
+    parse_bounds, Aggregate, Aggregation, BinOp, Bindings, Element, Expr, Function, Group,
+    Grouping, OrderKey, Path, Query, Selector, Term, TriplePattern,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Token<'s> {
+    /// An IRI including the angle brackets.
+    Iri(&'s str),
+    /// A literal including the double quotes.
+    Literal(&'s str),
+    /// A variable name without the leading `?` or `$`.
+    Var(&'s str),
+    /// Keywords, prefixed names, plain names and numbers.
+    Word(&'s str),
+    Punct(char),
+    /// Operators consisting of two characters.
+    Op(&'s str),
+}
+
+impl std::fmt::Display for Token<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Iri(s) | Token::Literal(s) | Token::Word(s) | Token::Op(s) => s.fmt(f),
+            Token::Var(v) => write!(f, "?{}", v),
+            Token::Punct(c) => c.fmt(f),
+        }
+    }
+}
+
+pub struct Lexer<'s> {
+    text: &'s str,
+    chars: Peekable<CharIndices<'s>>,
+}
+
+impl<'s> Lexer<'s> {
+    pub fn new(text: &'s str) -> Self {
+        Lexer {
+            text,
+            chars: text.char_indices().peekable(),
+        }
+    }
+
+    fn is_word_char(c: char) -> bool {
+        c.is_alphanumeric() || matches!(c, '_' | '-' | ':')
+    }
+
+    fn is_var_char(c: char) -> bool {
+        c.is_alphanumeric() || c == '_'
+    }
+
+    /// Consumes characters up to and including `end`. Returns the slice from `start` on.
+    fn until(&mut self, start: usize, end: char) -> Result<&'s str> {
+        for (i, c) in self.chars.by_ref() {
+            if c == end {
+                return Ok(&self.text[start..i + c.len_utf8()]);
+            }
+        }
+        bail!("missing closing ‘{}’", end)
+    }
+
+    /// Consumes the characters of a literal up to and including the closing quote, skipping
+    /// over escaped characters. Returns the slice from `start` on.
+    fn literal(&mut self, start: usize) -> Result<&'s str> {
+        while let Some((i, c)) = self.chars.next() {
+            match c {
+                '\\' => {
+                    self.chars.next();
+                }
+                '"' => return Ok(&self.text[start..=i]),
+                _ => {}
+            }
+        }
+        bail!("missing closing ‘\"’")
+    }
+
+    /// Consumes characters matching `is_char` following `end`. Returns the slice from `start`
+    /// on.
+    fn word(&mut self, start: usize, mut end: usize, is_char: fn(char) -> bool) -> &'s str {
+        while let Some(&(i, c)) = self.chars.peek() {
+            if !is_char(c) {
+                break;
+            }
+            end = i + c.len_utf8();
+            self.chars.next();
+        }
+        &self.text[start..end]
+    }
+
+    /// Consumes the digits following `start` and an optional fractional part.
+    fn number(&mut self, start: usize) -> &'s str {
+        let digits = |c: char| c.is_ascii_digit();
+        let int = self.word(start, start + 1, digits);
+        let end = start + int.len();
+        let rest = &self.text[end..];
+        if rest.starts_with('.') && rest[1..].starts_with(digits) {
+            self.chars.next();
+            let frac = self.word(end + 1, end + 1, digits);
+            return &self.text[start..end + 1 + frac.len()];
+        }
+        int
+    }
+
+    /// Whether the text following the `<` at `start` forms an IRI rather than an operator.
+    /// Text starting with a variable or containing `&&` is read as comparisons, as in
+    /// `?x<?y&&?y>1`, even though IRIs may contain `?` and `&`.
+    fn is_iri(&self, start: usize) -> bool {
+        let rest = &self.text[start + 1..];
+        let end = match rest.find(|c: char| c.is_whitespace() || "<>\"{}|^`\\".contains(c)) {
+            Some(i) if rest[i..].starts_with('>') => i,
+            _ => return false,
+        };
+        let iri = &rest[..end];
+        !iri.starts_with(['?', '$']) && !iri.contains("&&")
+    }
+
+    /// Consumes the next character if it is `c`.
+    fn accept(&mut self, c: char) -> bool {
+        self.chars.next_if(|&(_, next)| next == c).is_some()
+    }
+}
+
+impl<'s> Iterator for Lexer<'s> {
+    type Item = Result<Token<'s>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (start, c) = self.chars.next()?;
+            let token = match c {
+                c if c.is_whitespace() => continue,
+                '#' => {
+                    // Skip comments until the end of the line.
+                    self.chars.by_ref().find(|&(_, c)| c == '\n');
+                    continue;
+                }
+                '<' if self.is_iri(start) => self.until(start, '>').map(Token::Iri),
+                '"' => self.literal(start).map(Token::Literal),
+                '?' | '$' => match self.word(start + 1, start + 1, Self::is_var_char) {
+                    "" => Err(anyhow::anyhow!("missing variable name after ‘{}’", c)),
+                    name => Ok(Token::Var(name)),
+                },
+                '<' | '>' | '!' if self.accept('=') => Ok(Token::Op(&self.text[start..start + 2])),
+                '&' if self.accept('&') => Ok(Token::Op("&&")),
+                '|' if self.accept('|') => Ok(Token::Op("||")),
+                c if c.is_ascii_digit() => Ok(Token::Word(self.number(start))),
+                c if c != '-' && Self::is_word_char(c) => Ok(Token::Word(self.word(
+                    start,
+                    start + c.len_utf8(),
+                    Self::is_word_char,
+                ))),
+                c => Ok(Token::Punct(c)),
+            };
+            return Some(token);
+        }
+    }
+}
+
+pub struct Parser<'s> {
+    tokens: Peekable<Lexer<'s>>,
+    prefixes: HashMap<&'s str, &'s str>,
+    /// The aggregates of the SELECT list and `HAVING` with their variables.
+    aggregates: Vec<(String, Aggregate)>,
+    /// Whether aggregates may appear in expressions, i.e. while parsing `HAVING`.
+    allow_aggregates: bool,
+}
+
+impl<'s> Parser<'s> {
+    pub fn new(text: &'s str) -> Self {
+        Parser {
+            tokens: Lexer::new(text).peekable(),
+            prefixes: HashMap::new(),
+            aggregates: Vec::new(),
+            allow_aggregates: false,
+        }
+    }
+
+    fn peek(&mut self) -> Result<Option<Token<'s>>> {
+        match self.tokens.peek() {
+            Some(Ok(t)) => Ok(Some(*t)),
+            Some(Err(_)) => Err(self.tokens.next().unwrap().unwrap_err()),
+            None => Ok(None),
+        }
+    }
+
+    fn next(&mut self) -> Result<Token<'s>> {
+        self.tokens.next().context("unexpected end of query")?
+    }
+
+    fn is_keyword(token: Option<Token>, keyword: &str) -> bool {
+        matches!(token, Some(Token::Word(w)) if w.eq_ignore_ascii_case(keyword))
+    }
+
+    /// Consumes the next token if it is `keyword`.
+    fn accept_keyword(&mut self, keyword: &str) -> Result<bool> {
+        let found = Self::is_keyword(self.peek()?, keyword);
+        if found {
+            self.next()?;
+        }
+        Ok(found)
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<()> {
+        match self.next()? {
+            Token::Word(w) if w.eq_ignore_ascii_case(keyword) => Ok(()),
+            t => bail!("expected ‘{}’, found ‘{}’", keyword, t),
+        }
+    }
+
+    /// Consumes the next token if it is the punctuation `p`.
+    fn accept_punct(&mut self, p: char) -> Result<bool> {
+        let found = self.peek()? == Some(Token::Punct(p));
+        if found {
+            self.next()?;
+        }
+        Ok(found)
+    }
+
+    /// Consumes the next token if it is the operator `op`.
+    fn accept_op(&mut self, op: &str) -> Result<bool> {
+        let found = self.peek()? == Some(Token::Op(op));
+        if found {
+            self.next()?;
+        }
+        Ok(found)
+    }
+
+    fn expect_punct(&mut self, p: char) -> Result<()> {
+        match self.next()? {
+            Token::Punct(c) if c == p => Ok(()),
+            t => bail!("expected ‘{}’, found ‘{}’", p, t),
+        }
+    }
+
+    pub fn query(mut self) -> Result<Query> {
+        while self.accept_keyword("PREFIX")? {
+            let name = match self.next()? {
+                Token::Word(w) if w.ends_with(':') => w,
+                t => bail!("expected prefix name, found ‘{}’", t),
+            };
+            let iri = match self.next()? {
+                Token::Iri(iri) => iri,
+                t => bail!("expected IRI, found ‘{}’", t),
+            };
+            self.prefixes.insert(name, iri);
+        }
+
+        let query = if self.accept_keyword("CONSTRUCT")? {
+            self.construct()?
+        } else if self.accept_keyword("ASK")? {
+            self.accept_keyword("WHERE")?;
+            Query {
+                distinct: false,
+                projection: None,
+                construct: None,
+                ask: true,
+                pattern: self.group()?,
+                order: Vec::new(),
+                limit: None,
+                offset: None,
+                grouping: None,
+            }
+        } else {
+            self.expect_keyword("SELECT")?;
+            self.select()?
+        };
+        if let Some(t) = self.peek()? {
+            bail!("unexpected ‘{}’ after query", t);
+        }
+        Ok(query)
+    }
+
+    /// Parses a SELECT query following the keyword, up to its modifiers. Subqueries have their
+    /// own aggregates, so those of the enclosing query are put aside meanwhile.
+    fn select(&mut self) -> Result<Query> {
+        let outer = std::mem::take(&mut self.aggregates);
+        let distinct = self.accept_keyword("DISTINCT")?;
+        let projection = if self.accept_punct('*')? {
+            None
+        } else {
+            let mut vars = Vec::new();
+            loop {
+                match self.peek()? {
+                    Some(Token::Var(v)) => {
+                        self.next()?;
+                        vars.push(Selector::Var(v.to_owned()));
+                    }
+                    Some(Token::Punct('(')) => {
+                        self.next()?;
+                        let aggregate = match self.next()? {
+                            Token::Word(w) => match Aggregation::from_name(w) {
+                                Some(function) => self.aggregate(function)?,
+                                None => bail!("expected an aggregate, found ‘{}’", w),
+                            },
+                            t => bail!("expected an aggregate, found ‘{}’", t),
+                        };
+                        self.expect_keyword("AS")?;
+                        let var = match self.next()? {
+                            Token::Var(v) => v.to_owned(),
+                            t => bail!("expected a variable after AS, found ‘{}’", t),
+                        };
+                        self.expect_punct(')')?;
+                        self.aggregates.push((var.clone(), aggregate));
+                        vars.push(Selector::Var(var));
+                    }
+                    _ => break,
+                }
+            }
+            if vars.is_empty() {
+                bail!(
+                    "expected ‘*’ or variables after SELECT, found ‘{}’",
+                    self.next()?
+                );
+            }
+            Some(vars)
+        };
+        self.accept_keyword("WHERE")?;
+        let pattern = self.group()?;
+        let grouping = self.grouping(projection.as_deref())?;
+        self.aggregates = outer;
+        self.modifiers(Query {
+            distinct,
+            projection,
+            construct: None,
+            ask: false,
+            pattern,
+            order: Vec::new(),
+            limit: None,
+            offset: None,
+            grouping,
+        })
+    }
+
+    /// Parses the rest of a `CONSTRUCT` query: the template followed by the pattern, or only
+    /// the pattern for `CONSTRUCT WHERE`.
+    fn construct(&mut self) -> Result<Query> {
+        let (template, pattern) = if self.accept_keyword("WHERE")? {
+            let pattern = self.group()?;
+            let template = pattern
+                .elements
+                .iter()
+                .map(|element| match element {
+                    Element::Triple(
+                        pattern @ TriplePattern {
+                            predicate: Path::Relation(_) | Path::Variable(_),
+                            ..
+                        },
+                    ) => Ok(pattern.clone()),
+                    _ => bail!("CONSTRUCT WHERE only supports triple patterns without paths"),
+                })
+                .collect::<Result<Vec<_>>>()?;
+            (template, pattern)
+        } else {
+            let template = self.template()?;
+            self.accept_keyword("WHERE")?;
+            (template, self.group()?)
+        };
+        Self::check_template(&template)?;
+        let next = self.peek()?;
+        if Self::is_keyword(next, "GROUP") || Self::is_keyword(next, "HAVING") {
+            bail!("CONSTRUCT does not support GROUP BY or HAVING");
+        }
+        self.modifiers(Query {
+            distinct: false,
+            projection: None,
+            construct: Some(template),
+            ask: false,
+            pattern,
+            order: Vec::new(),
+            limit: None,
+            offset: None,
+            grouping: None,
+        })
+    }
+
+    /// Parses the triples of a `CONSTRUCT` template, their predicates are relations or variables.
+    fn template(&mut self) -> Result<Vec<TriplePattern>> {
+        self.expect_punct('{')?;
+        let mut triples = Vec::new();
+        while !self.accept_punct('}')? {
+            let subject = self.term()?;
+            let predicate = match self.term()? {
+                Term::Var(v) => Path::Variable(v),
+                Term::Const(c) => Path::Relation(c),
+            };
+            triples.push(TriplePattern {
+                subject,
+                predicate,
+                object: self.term()?,
+            });
+            if !self.accept_punct('.')? {
+                self.expect_punct('}')?;
+                break;
+            }
+        }
+        Ok(triples)
+    }
+
+    /// Rejects template triples that can never be well-formed: a constant predicate has to be
+    /// an IRI and a constant subject cannot be a literal. Values bound by variables are only
+    /// known per row.
+    fn check_template(template: &[TriplePattern]) -> Result<()> {
+        for triple in template {
+            if let Term::Const(c) = &triple.subject {
+                if c.starts_with('"') {
+                    bail!(
+                        "the subject ‘{}’ of a CONSTRUCT template cannot be a literal",
+                        c
+                    );
+                }
+            }
+            if let Path::Relation(c) = &triple.predicate {
+                if !c.starts_with('<') {
+                    bail!(
+                        "the predicate ‘{}’ of a CONSTRUCT template has to be an IRI",
+                        c
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Parses `ORDER BY`, `LIMIT` and `OFFSET` following the pattern.
+    fn modifiers(&mut self, mut query: Query) -> Result<Query> {
+        if self.accept_keyword("ORDER")? {
+            self.expect_keyword("BY")?;
+            query.order = self.order_keys()?;
+        }
+        loop {
+            if query.limit.is_none() && self.accept_keyword("LIMIT")? {
+                query.limit = Some(self.count("LIMIT")?);
+            } else if query.offset.is_none() && self.accept_keyword("OFFSET")? {
+                query.offset = Some(self.count("OFFSET")?);
+            } else {
+                break;
+            }
+        }
+        Ok(query)
+    }
+
+    /// Parses `GROUP BY` and `HAVING` if present. There is a grouping as well if the SELECT list
+    /// contains aggregates, all selected variables then have to be grouped by or aggregated.
+    fn grouping(&mut self, projection: Option<&[Selector]>) -> Result<Option<Grouping>> {
+        let mut keys = Vec::new();
+        let grouped = self.accept_keyword("GROUP")?;
+        if grouped {
+            self.expect_keyword("BY")?;
+            while let Some(Token::Var(v)) = self.peek()? {
+                self.next()?;
+                keys.push(v.to_owned());
+            }
+            if keys.is_empty() {
+                bail!(
+                    "expected a variable after GROUP BY, found ‘{}’",
+                    self.next()?
+                );
+            }
+        }
+
+        let mut having = Vec::new();
+        if self.accept_keyword("HAVING")? {
+            self.allow_aggregates = true;
+            having.push(self.constraint()?);
+            while self.peek()? == Some(Token::Punct('(')) {
+                having.push(self.constraint()?);
+            }
+            self.allow_aggregates = false;
+        }
+
+        if !grouped && self.aggregates.is_empty() && having.is_empty() {
+            return Ok(None);
+        }
+        let grouping = Grouping {
+            keys,
+            aggregates: std::mem::take(&mut self.aggregates),
+            having,
+        };
+        let projection = match projection {
+            Some(projection) => projection,
+            None => bail!("SELECT * cannot be used together with GROUP BY or aggregates"),
+        };
+        for selector in projection {
+            if let Selector::Var(v) = selector {
+                if !grouping.keys.contains(v) && !grouping.aggregates.iter().any(|(n, _)| n == v) {
+                    bail!("?{} has to be grouped by or aggregated", v);
+                }
+            }
+        }
+        Ok(Some(grouping))
+    }
+
+    /// Parses the arguments of an aggregate, starting at the opening parenthesis.
+    fn aggregate(&mut self, function: Aggregation) -> Result<Aggregate> {
+        self.expect_punct('(')?;
+        let distinct = self.accept_keyword("DISTINCT")?;
+        let var = match self.next()? {
+            Token::Var(v) => Some(v.to_owned()),
+            Token::Punct('*') if function == Aggregation::Count && !distinct => None,
+            t => bail!("expected a variable in {}, found ‘{}’", function.name(), t),
+        };
+        let mut separator = String::from(" ");
+        if function == Aggregation::GroupConcat && self.accept_punct(';')? {
+            self.expect_keyword("SEPARATOR")?;
+            self.expect_punct('=')?;
+            separator = match self.next()? {
+                Token::Literal(s) => s[1..s.len() - 1].to_owned(),
+                t => bail!("expected a literal after SEPARATOR, found ‘{}’", t),
+            };
+        }
+        self.expect_punct(')')?;
+        Ok(Aggregate {
+            function,
+            distinct,
+            var,
+            separator,
+        })
+    }
+
+    /// The non-negative integer following `keyword`.
+    fn count(&mut self, keyword: &str) -> Result<usize> {
+        match self.next()? {
+            Token::Word(w) => w
+                .parse()
+                .with_context(|| format!("invalid {} ‘{}’", keyword, w)),
+            t => bail!("expected a number after {}, found ‘{}’", keyword, t),
+        }
+    }
+
+    /// The conditions of `ORDER BY`: variables, optionally wrapped in `ASC(...)` or `DESC(...)`.
+    fn order_keys(&mut self) -> Result<Vec<OrderKey>> {
+        let mut keys = Vec::new();
+        loop {
+            let descending = if self.accept_keyword("DESC")? {
+                Some(true)
+            } else if self.accept_keyword("ASC")? {
+                Some(false)
+            } else {
+                None
+            };
+            if descending.is_some() {
+                self.expect_punct('(')?;
+            }
+            let var = match (self.peek()?, descending) {
+                (Some(Token::Var(v)), _) => v.to_owned(),
+                (_, None) if !keys.is_empty() => break,
+                _ => bail!("expected a variable to order by, found ‘{}’", self.next()?),
+            };
+            self.next()?;
+            if descending.is_some() {
+                self.expect_punct(')')?;
+            }
+            keys.push(OrderKey {
+                column: Selector::Var(var),
+                descending: descending.unwrap_or(false),
+            });
+        }
+        Ok(keys)
+    }
+
+    /// Parses a group of patterns, or a subquery if the group starts with SELECT.
+    fn group(&mut self) -> Result<Group> {
+        self.expect_punct('{')?;
+        if self.accept_keyword("SELECT")? {
+            let query = self.select()?;
+            self.expect_punct('}')?;
+            return Ok(Group {
+                elements: vec![Element::SubQuery(Box::new(query))],
+            });
+        }
+        let mut elements = Vec::new();
+        loop {
+            if self.accept_punct('}')? {
+                break;
+            }
+            let nested = if self.accept_keyword("OPTIONAL")? {
+                Some(Element::Optional(self.group()?))
+            } else if self.accept_keyword("MINUS")? {
+                Some(Element::Minus(self.group()?))
+            } else if self.accept_keyword("FILTER")? {
+                if self.accept_keyword("NOT")? {
+                    self.expect_keyword("EXISTS")?;
+                    Some(Element::NotExists(self.group()?))
+                } else if self.accept_keyword("EXISTS")? {
+                    Some(Element::Exists(self.group()?))
+                } else {
+                    Some(Element::Filter(self.constraint()?))
+                }
+            } else if self.accept_keyword("BIND")? {
+                self.expect_punct('(')?;
+                let expr = self.expr()?;
+                self.expect_keyword("AS")?;
+                let var = match self.next()? {
+                    Token::Var(v) => v.to_owned(),
+                    t => bail!("expected a variable after AS, found ‘{}’", t),
+                };
+                self.expect_punct(')')?;
+                Some(Element::Bind(expr, var))
+            } else if self.accept_keyword("VALUES")? {
+                Some(Element::Values(self.values()?))
+            } else if self.peek()? == Some(Token::Punct('{')) {
+                let mut groups = vec![self.group()?];
+                while self.accept_keyword("UNION")? {
+                    groups.push(self.group()?);
+                }
+                match <[Group; 1]>::try_from(groups) {
+                    Ok([Group { elements }]) if matches!(elements[..], [Element::SubQuery(_)]) => {
+                        elements.into_iter().next()
+                    }
+                    Ok(groups) => Some(Element::Union(Vec::from(groups))),
+                    Err(groups) => Some(Element::Union(groups)),
+                }
+            } else {
+                None
+            };
+            if let Some(element) = nested {
+                elements.push(element);
+                // The dot after a group is optional.
+                self.accept_punct('.')?;
+                continue;
+            }
+
+            elements.push(Element::Triple(TriplePattern {
+                subject: self.term()?,
+                predicate: self.path()?,
+                object: self.term()?,
+            }));
+            // The dot may be left out before the end of the group or a nested element.
+            if !self.accept_punct('.')? && !self.at_nested()? {
+                self.expect_punct('}')?;
+                break;
+            }
+        }
+        Ok(Group { elements })
+    }
+
+    /// Parses the data of `VALUES`, either for a single variable as in `?x { <a> <b> }` or for
+    /// several as in `(?x ?y) { (<a> <b>) (<c> UNDEF) }`.
+    fn values(&mut self) -> Result<Bindings> {
+        let var = |token| match token {
+            Token::Var(v) => Ok(v.to_owned()),
+            t => bail!("expected a variable after VALUES, found ‘{}’", t),
+        };
+        let single = !self.accept_punct('(')?;
+        let mut variables = Vec::new();
+        if single {
+            variables.push(var(self.next()?)?);
+        } else {
+            while !self.accept_punct(')')? {
+                variables.push(var(self.next()?)?);
+            }
+        }
+        if let Some(var) = variables.iter().duplicates().next() {
+            bail!("variable ?{} appears more than once in VALUES", var);
+        }
+
+        self.expect_punct('{')?;
+        let mut rows = Vec::new();
+        while !self.accept_punct('}')? {
+            if single {
+                rows.push(vec![self.value()?]);
+                continue;
+            }
+            self.expect_punct('(')?;
+            let mut row = Vec::with_capacity(variables.len());
+            while !self.accept_punct(')')? {
+                row.push(self.value()?);
+            }
+            if row.len() != variables.len() {
+                bail!(
+                    "VALUES row has {} values instead of {}",
+                    row.len(),
+                    variables.len()
+                );
+            }
+            rows.push(row);
+        }
+        Ok(Bindings { variables, rows })
+    }
+
+    /// Parses a value of `VALUES`, `None` for `UNDEF`.
+    fn value(&mut self) -> Result<Option<String>> {
+        if self.accept_keyword("UNDEF")? {
+            return Ok(None);
+        }
+        self.constant().map(Some)
+    }
+
+    /// Whether the next token starts a nested element of a group.
+    fn at_nested(&mut self) -> Result<bool> {
+        let next = self.peek()?;
+        Ok(next == Some(Token::Punct('{'))
+            || ["OPTIONAL", "MINUS", "FILTER", "VALUES", "BIND"]
+                .iter()
+                .any(|keyword| Self::is_keyword(next, keyword)))
+    }
+
+    /// Parses the condition of a `FILTER`: either a bracketted expression or a function call.
+    fn constraint(&mut self) -> Result<Expr> {
+        if self.accept_punct('(')? {
+            let expr = self.expr()?;
+            self.expect_punct(')')?;
+            return Ok(expr);
+        }
+        match self.primary()? {
+            call @ Expr::Call(..) => Ok(call),
+            expr => bail!(
+                "expected ‘(’ or a function call after FILTER, found ‘{}’",
+                expr
+            ),
+        }
+    }
+
+    fn expr(&mut self) -> Result<Expr> {
+        let mut lhs = self.and_expr()?;
+        while self.accept_op("||")? {
+            lhs = Expr::Binary(BinOp::Or, Box::new(lhs), Box::new(self.and_expr()?));
+        }
+        Ok(lhs)
+    }
+
+    fn and_expr(&mut self) -> Result<Expr> {
+        let mut lhs = self.relational()?;
+        while self.accept_op("&&")? {
+            lhs = Expr::Binary(BinOp::And, Box::new(lhs), Box::new(self.relational()?));
+        }
+        Ok(lhs)
+    }
+
+    fn relational(&mut self) -> Result<Expr> {
+        let lhs = self.additive()?;
+        let op = match self.peek()? {
+            Some(Token::Punct('=')) => BinOp::Eq,
+            Some(Token::Op("!=")) => BinOp::Ne,
+            Some(Token::Punct('<')) => BinOp::Lt,
+            Some(Token::Op("<=")) => BinOp::Le,
+            Some(Token::Punct('>')) => BinOp::Gt,
+            Some(Token::Op(">=")) => BinOp::Ge,
+            _ => return Ok(lhs),
+        };
+        self.next()?;
+        Ok(Expr::Binary(op, Box::new(lhs), Box::new(self.additive()?)))
+    }
+
+    fn additive(&mut self) -> Result<Expr> {
+        let mut lhs = self.multiplicative()?;
+        loop {
+            let op = match self.peek()? {
+                Some(Token::Punct('+')) => BinOp::Add,
+                Some(Token::Punct('-')) => BinOp::Sub,
+                _ => return Ok(lhs),
+            };
+            self.next()?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(self.multiplicative()?));
+        }
+    }
+
+    fn multiplicative(&mut self) -> Result<Expr> {
+        let mut lhs = self.unary()?;
+        loop {
+            let op = match self.peek()? {
+                Some(Token::Punct('*')) => BinOp::Mul,
+                Some(Token::Punct('/')) => BinOp::Div,
+                _ => return Ok(lhs),
+            };
+            self.next()?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        if self.accept_punct('!')? {
+            Ok(Expr::Not(Box::new(self.unary()?)))
+        } else if self.accept_punct('-')? {
+            Ok(Expr::Neg(Box::new(self.unary()?)))
+        } else if self.accept_punct('+')? {
+            self.unary()
+        } else {
+            self.primary()
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr> {
+        match self.next()? {
+            Token::Punct('(') => {
+                let expr = self.expr()?;
+                self.expect_punct(')')?;
+                Ok(expr)
+            }
+            Token::Var(v) => Ok(Expr::Var(v.to_owned())),
+            Token::Iri(s) | Token::Literal(s) => Ok(Expr::Const(s.to_owned())),
+            Token::Word(w) if w.starts_with(|c: char| c.is_ascii_digit()) => w
+                .parse()
+                .map(Expr::Number)
+                .with_context(|| format!("invalid number ‘{}’", w)),
+            Token::Word(w) if w.eq_ignore_ascii_case("true") => Ok(Expr::Bool(true)),
+            Token::Word(w) if w.eq_ignore_ascii_case("false") => Ok(Expr::Bool(false)),
+            Token::Word(w) if self.peek()? == Some(Token::Punct('(')) => {
+                if let Some(function) = Aggregation::from_name(w) {
+                    if !self.allow_aggregates {
+                        bail!("{} is only allowed in SELECT and HAVING", function.name());
+                    }
+                    // Aggregates are computed before `HAVING` and referred to by their variables.
+                    let aggregate = self.aggregate(function)?;
+                    let known = self.aggregates.iter().find(|(_, a)| *a == aggregate);
+                    let name = match known {
+                        Some((name, _)) => name.clone(),
+                        None => {
+                            let name = aggregate.to_string();
+                            self.aggregates.push((name.clone(), aggregate));
+                            name
+                        }
+                    };
+                    return Ok(Expr::Var(name));
+                }
+                let func =
+                    Function::from_name(w).with_context(|| format!("unknown function ‘{}’", w))?;
+                self.next()?;
+                let mut args = Vec::new();
+                if !self.accept_punct(')')? {
+                    loop {
+                        args.push(self.expr()?);
+                        if !self.accept_punct(',')? {
+                            break;
+                        }
+                    }
+                    self.expect_punct(')')?;
+                }
+                if !func.arity().contains(&args.len()) {
+                    bail!(
+                        "{} expects {} arguments, found {}",
+                        func.name(),
+                        func.arity().map(|n| n.to_string()).join(" or "),
+                        args.len()
+                    );
+                }
+                if func == Function::Bound && !matches!(args[..], [Expr::Var(_)]) {
+                    bail!("the argument of BOUND has to be a variable");
+                }
+                Ok(Expr::Call(func, args))
+            }
+            Token::Word(w) => Ok(Expr::Const(self.expand(w))),
+            t => bail!("expected an expression, found ‘{}’", t),
+        }
+    }
+
+    fn term(&mut self) -> Result<Term> {
+        if let Some(Token::Var(v)) = self.peek()? {
+            self.next()?;
+            return Ok(Term::Var(v.to_owned()));
+        }
+        self.constant().map(Term::Const)
+    }
+
+    fn path(&mut self) -> Result<Path> {
+        if let Some(Token::Var(v)) = self.peek()? {
+            self.next()?;
+            return Ok(Path::Variable(v.to_owned()));
+        }
+        let first = self.path_element()?;
+        if self.peek()? != Some(Token::Punct('|')) {
+            return Ok(first);
+        }
+
+        let mut alternatives = vec![first];
+        while self.accept_punct('|')? {
+            alternatives.push(self.path_element()?);
+        }
+        Ok(Path::Alternative(alternatives))
+    }
+
+    fn path_element(&mut self) -> Result<Path> {
+        if self.accept_punct('^')? {
+            return Ok(Path::Inverse(Box::new(self.path_element()?)));
+        }
+
+        let primary = if self.accept_punct('(')? {
+            let inner = self.path()?;
+            self.expect_punct(')')?;
+            inner
+        } else {
+            self.constant().map(Path::Relation)?
+        };
+
+        if self.accept_punct('+')? {
+            Ok(Path::OneOrMore(Box::new(primary)))
+        } else if self.accept_punct('*')? {
+            Ok(Path::ZeroOrMore(Box::new(primary)))
+        } else if self.accept_punct('{')? {
+            let mut bounds = String::new();
+            loop {
+                match self.next()? {
+                    Token::Punct('}') => break,
+                    t @ (Token::Word(_) | Token::Punct(',')) => bounds.push_str(&t.to_string()),
+                    t => bail!("expected ‘}}’ after the bounds of a repetition, found ‘{}’", t),
+                }
+            }
+            let (min, max) = parse_bounds(&bounds)?;
+            Ok(Path::Repeat(Box::new(primary), min, max))
+        } else {
+            Ok(primary)
+        }
+    }
+
+    /// Parses an IRI, literal or (prefixed) name. Prefixed names are expanded if the prefix has
+    /// been declared, otherwise they are taken verbatim.
+    fn constant(&mut self) -> Result<String> {
+        match self.next()? {
+            Token::Iri(s) | Token::Literal(s) => Ok(s.to_owned()),
+            Token::Word(w) => Ok(self.expand(w)),
+            t => bail!("expected a term, found ‘{}’", t),
+        }
+    }
+
+    fn expand(&self, word: &str) -> String {
+        if let Some(colon) = word.find(':') {
+            let (prefix, local) = word.split_at(colon + 1);
+            if let Some(iri) = self.prefixes.get(prefix) {
+                return format!("{}{}>", &iri[..iri.len() - 1], local);
+            }
+        }
+        word.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(text: &str) -> Vec<Token<'_>> {
+        Lexer::new(text).collect::<Result<_>>().unwrap()
+    }
+
+    fn pattern(text: &str) -> Group {
+        Query::parse(text).unwrap().pattern
+    }
+
+    #[test]
+    fn lexer_splits_terms() {
+        assert_eq!(
+            tokens("?x ex:p <http://a/b> \"say \\\"hi\\\"\" . # comment\n$y"),
+            [
+                Token::Var("x"),
+                Token::Word("ex:p"),
+                Token::Iri("<http://a/b>"),
+                Token::Literal("\"say \\\"hi\\\"\""),
+                Token::Punct('.'),
+                Token::Var("y"),
+            ]
+        );
+    }
+
+    #[test]
+    fn lexer_reports_unclosed_literal() {
+        let error = Lexer::new("\"open").find_map(Result::err).unwrap();
+        assert_eq!(error.to_string(), "missing closing ‘\"’");
+    }
+
+    #[test]
+    fn prefixes_are_expanded() {
+        let group = pattern("PREFIX ex: <http://e/> SELECT * WHERE { ?x ex:p ex:o }");
+        match &group.elements[..] {
+            [Element::Triple(t)] => {
+                assert_eq!(t.predicate, Path::Relation("<http://e/p>".to_owned()));
+                assert_eq!(t.object, Term::Const("<http://e/o>".to_owned()));
+            }
+            elements => panic!("unexpected pattern {:?}", elements),
+        }
+    }
+
+    #[test]
+    fn optional_group() {
+        let group = pattern("SELECT * WHERE { ?x <p> ?y OPTIONAL { ?y <q> ?z } . ?x <r> ?w }");
+        match &group.elements[..] {
+            [Element::Triple(_), Element::Optional(optional), Element::Triple(_)] => {
+                match &optional.elements[..] {
+                    [Element::Triple(t)] => {
+                        assert_eq!(t.subject, Term::Var("y".to_owned()));
+                        assert_eq!(t.predicate, Path::Relation("<q>".to_owned()));
+                        assert_eq!(t.object, Term::Var("z".to_owned()));
+                    }
+                    elements => panic!("unexpected optional pattern {:?}", elements),
+                }
+            }
+            elements => panic!("unexpected pattern {:?}", elements),
+        }
+    }
+
+    #[test]
+    fn optional_needs_a_group() {
+        assert!(Query::parse("SELECT * WHERE { ?x <p> ?y OPTIONAL ?y <q> ?z }").is_err());
+    }
+
+    fn filter(expr: &str) -> Expr {
+        let text = format!("SELECT * WHERE {{ ?x <p> ?y FILTER {} }}", expr);
+        match pattern(&text).elements.pop() {
+            Some(Element::Filter(expr)) => expr,
+            element => panic!("unexpected element {:?}", element),
+        }
+    }
+
+    #[test]
+    fn lexer_splits_operators() {
+        assert_eq!(
+            tokens("(?x<=1.5&&!(?y!=<a>||?z>-2))"),
+            [
+                Token::Punct('('),
+                Token::Var("x"),
+                Token::Op("<="),
+                Token::Word("1.5"),
+                Token::Op("&&"),
+                Token::Punct('!'),
+                Token::Punct('('),
+                Token::Var("y"),
+                Token::Op("!="),
+                Token::Iri("<a>"),
+                Token::Op("||"),
+                Token::Var("z"),
+                Token::Punct('>'),
+                Token::Punct('-'),
+                Token::Word("2"),
+                Token::Punct(')'),
+                Token::Punct(')'),
+            ]
+        );
+    }
+
+    #[test]
+    fn lexer_tells_comparisons_from_iris() {
+        assert_eq!(
+            tokens("?x<?y&&?y>1"),
+            [
+                Token::Var("x"),
+                Token::Punct('<'),
+                Token::Var("y"),
+                Token::Op("&&"),
+                Token::Var("y"),
+                Token::Punct('>'),
+                Token::Word("1"),
+            ]
+        );
+        assert_eq!(
+            tokens("?x<1&&2>?y"),
+            [
+                Token::Var("x"),
+                Token::Punct('<'),
+                Token::Word("1"),
+                Token::Op("&&"),
+                Token::Word("2"),
+                Token::Punct('>'),
+                Token::Var("y"),
+            ]
+        );
+        assert_eq!(
+            tokens("<http://a/b?x=1&y=2>"),
+            [Token::Iri("<http://a/b?x=1&y=2>")]
+        );
+        assert_eq!(
+            filter("(?x<?y&&?y>1)").to_string(),
+            "((?x < ?y) && (?y > 1))"
+        );
+    }
+
+    #[test]
+    fn operator_precedence() {
+        assert_eq!(
+            filter("(?x = 1 || ?y < 2 + 3 * ?z && !BOUND(?y))").to_string(),
+            "((?x = 1) || ((?y < (2 + (3 * ?z))) && !BOUND(?y)))"
+        );
+        assert_eq!(
+            filter("(-?x - 2 / 4 >= 1)").to_string(),
+            "((-?x - (2 / 4)) >= 1)"
+        );
+    }
+
+    #[test]
+    fn expressions_round_trip() {
+        for expr in [
+            "(?x = 1 || ?y < 2 + 3 * ?z && !BOUND(?y))",
+            "(-?x - 2.5 / 4 >= 1)",
+            "(?x != <http://a/b> && ?y = \"lit\")",
+            "REGEX(STR(?x), \"^a\", \"i\")",
+            "(isIRI(?x) || isLiteral(?y) && true)",
+            "(LCASE(?x) = UCASE(?y))",
+        ] {
+            let parsed = filter(expr);
+            assert_eq!(filter(&format!("({})", parsed)), parsed, "{}", expr);
+        }
+    }
+
+    #[test]
+    fn unknown_functions_are_rejected() {
+        let text = "SELECT * WHERE { ?x <p> ?y FILTER(FOO(?x)) }";
+        assert!(Query::parse(text).is_err());
+    }
+
+    #[test]
+    fn select_distinct() {
+        let query = Query::parse("SELECT DISTINCT * WHERE { ?x <p> ?y }").unwrap();
+        assert!(query.distinct);
+        let query = Query::parse("select * where { ?x <p> ?y }").unwrap();
+        assert!(!query.distinct);
+    }
+
+    #[test]
+    fn order_by() {
+        let query =
+            Query::parse("SELECT * WHERE { ?x <p> ?y } ORDER BY ?y DESC(?x) ASC(?y)").unwrap();
+        let order = query.order.iter().map(ToString::to_string).collect_vec();
+        assert_eq!(order, ["?y", "DESC(?x)", "?y"]);
+        assert!(Query::parse("SELECT * WHERE { ?x <p> ?y } ORDER BY").is_err());
+    }
+
+    #[test]
+    fn limit_and_offset() {
+        let query = Query::parse("SELECT * WHERE { ?x <p> ?y } OFFSET 5 LIMIT 10").unwrap();
+        assert_eq!((query.limit, query.offset), (Some(10), Some(5)));
+        let query = Query::parse("SELECT * WHERE { ?x <p> ?y } LIMIT 3").unwrap();
+        assert_eq!((query.limit, query.offset), (Some(3), None));
+        assert!(Query::parse("SELECT * WHERE { ?x <p> ?y } LIMIT -1").is_err());
+    }
+
+    #[test]
+    fn group_by_and_having() {
+        let query = Query::parse(
+            "SELECT ?x (COUNT(?y) AS ?n) WHERE { ?x <p> ?y } GROUP BY ?x \
+             HAVING (SUM(?y) > 2) (?n > 1)",
+        )
+        .unwrap();
+        let grouping = query.grouping.unwrap();
+        assert_eq!(grouping.keys, ["x"]);
+        let aggregates = grouping
+            .aggregates
+            .iter()
+            .map(|(var, aggregate)| format!("{} = {}", var, aggregate))
+            .collect_vec();
+        assert_eq!(aggregates, ["n = COUNT(?y)", "SUM(?y) = SUM(?y)"]);
+        let having = grouping
+            .having
+            .iter()
+            .map(ToString::to_string)
+            .collect_vec();
+        assert_eq!(having, ["(?SUM(?y) > 2)", "(?n > 1)"]);
+    }
+
+    #[test]
+    fn grouping_errors() {
+        let error = |text: &str| Query::parse(text).unwrap_err().root_cause().to_string();
+        assert_eq!(
+            error("SELECT ?x ?y WHERE { ?x <p> ?y } GROUP BY ?x"),
+            "?y has to be grouped by or aggregated"
+        );
+        assert_eq!(
+            error("SELECT * WHERE { ?x <p> ?y } GROUP BY ?x"),
+            "SELECT * cannot be used together with GROUP BY or aggregates"
+        );
+        assert_eq!(
+            error("SELECT (SUM(*) AS ?n) WHERE { ?x <p> ?y }"),
+            "expected a variable in SUM, found ‘*’"
+        );
+    }
+
+    #[test]
+    fn values() {
+        let group = pattern("SELECT * WHERE { VALUES ?x { <a> \"b\" UNDEF } ?x <p> ?y }");
+        match &group.elements[..] {
+            [Element::Values(values), Element::Triple(_)] => {
+                assert_eq!(values.variables, ["x"]);
+                assert_eq!(
+                    values.rows,
+                    [
+                        vec![Some("<a>".to_owned())],
+                        vec![Some("\"b\"".to_owned())],
+                        vec![None]
+                    ]
+                );
+            }
+            elements => panic!("unexpected pattern {:?}", elements),
+        }
+        let error = |text: &str| Query::parse(text).unwrap_err().root_cause().to_string();
+        assert_eq!(
+            error("SELECT * WHERE { VALUES (?x ?y) { (<a>) } ?x <p> ?y }"),
+            "VALUES row has 1 values instead of 2"
+        );
+        assert_eq!(
+            error("SELECT * WHERE { VALUES (?x ?x) { } ?x <p> ?y }"),
+            "variable ?x appears more than once in VALUES"
+        );
+    }
+
+    #[test]
+    fn construct_template() {
+        let query =
+            Query::parse("CONSTRUCT { ?x <q> _:b . _:b ?p \"v\" } WHERE { ?x ?p ?y }").unwrap();
+        let template = query.construct.unwrap();
+        assert_eq!(template.len(), 2);
+        assert_eq!(template[0].object, Term::Const("_:b".to_owned()));
+        assert_eq!(template[1].predicate, Path::Variable("p".to_owned()));
+        assert_eq!(query.projection, None);
+
+        let query = Query::parse("CONSTRUCT WHERE { ?x <q> ?y . ?y ?p ?z }").unwrap();
+        assert_eq!(query.construct.unwrap().len(), 2);
+    }
+
+    #[test]
+    fn construct_errors() {
+        let error = |text: &str| Query::parse(text).unwrap_err().root_cause().to_string();
+        assert_eq!(
+            error("CONSTRUCT { \"a\" <q> ?y } WHERE { ?x <p> ?y }"),
+            "the subject ‘\"a\"’ of a CONSTRUCT template cannot be a literal"
+        );
+        assert_eq!(
+            error("CONSTRUCT { ?x \"q\" ?y } WHERE { ?x <p> ?y }"),
+            "the predicate ‘\"q\"’ of a CONSTRUCT template has to be an IRI"
+        );
+        assert_eq!(
+            error("CONSTRUCT { ?x _:q ?y } WHERE { ?x <p> ?y }"),
+            "the predicate ‘_:q’ of a CONSTRUCT template has to be an IRI"
+        );
+        assert_eq!(
+            error("CONSTRUCT WHERE { ?x <p>+ ?y }"),
+            "CONSTRUCT WHERE only supports triple patterns without paths"
+        );
+        assert_eq!(
+            error("CONSTRUCT { ?x <q> ?y } WHERE { ?x <p> ?y } GROUP BY ?x"),
+            "CONSTRUCT does not support GROUP BY or HAVING"
+        );
+    }
+
+    #[test]
+    fn ask() {
+        for text in ["ASK { ?x <p> ?y }", "ask where { ?x <p> ?y }"] {
+            let query = Query::parse(text).unwrap();
+            assert!(query.ask);
+            assert_eq!(query.projection, None);
+        }
+        let error = Query::parse("ASK { ?x <p> ?y } LIMIT 1").unwrap_err();
+        assert_eq!(
+            error.root_cause().to_string(),
+            "unexpected ‘LIMIT’ after query"
+        );
+    }
+}